@@ -129,6 +129,30 @@ fn minify_all_html() {
     }
 }
 
+/// Zstd variants of the assets, the binary only makes `.br` and `.gz` ones by
+/// itself on startup. Without `zstd` the assets would silently never be served
+/// as zstd, so the build stops instead
+fn precompress_assets() {
+    const SKIP: [&str; 6] = ["webp", "png", "avif", "br", "gz", "zst"];
+    for entry in std::fs::read_dir("html_files/assets").unwrap() {
+        let path = entry.unwrap().path();
+        let ext = path.extension().and_then(|e| e.to_str()).unwrap_or_default();
+        if !path.is_file() || SKIP.contains(&ext) {
+            continue;
+        }
+
+        let out = format!("{}.zst", path.display());
+        let status = Command::new("zstd")
+            .args(["-19", "-q", "-f", path.to_str().unwrap(), "-o", &out])
+            .status();
+        match status {
+            Ok(status) if status.success() => (),
+            Ok(status) => panic!("'zstd' failed to compress {}: {}", path.display(), status),
+            Err(e) => panic!("Couldn't call 'zstd' to precompress the assets, is it installed? {}", e),
+        }
+    }
+}
+
 fn main() {
     // Download icons
     for icon in ICONS {
@@ -150,6 +174,8 @@ fn main() {
         "html_files/assets/instantpage.js",
     );
 
+    precompress_assets();
+
     // Tell cargo to keep an eye on files
     println!("cargo:rerun-if-changed=tailwind.config.js");
     println!("cargo:rerun-if-changed=html_src");
@@ -0,0 +1,337 @@
+use std::{
+    convert::Infallible,
+    future::Future,
+    io::Write,
+    pin::Pin,
+    sync::{Arc, Mutex, OnceLock},
+    task::{Context, Poll},
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
+
+use serde::Deserialize;
+use tower_service::Service;
+use tracing::{field, info_span, Instrument, Span};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use warp::{
+    http::{header, HeaderName, HeaderValue, Response, Uri},
+    hyper::{
+        body::{Body, Frame, Incoming, SizeHint},
+        Request,
+    },
+};
+
+use crate::{privacy, server::RemoteAddr, session, telemetry};
+
+pub static REQUEST_ID: HeaderName = HeaderName::from_static("x-request-id");
+
+/// The URI as it can be logged. Query values carry authorization codes on the
+/// login pages and searched emails on the audit page, so only the names of the
+/// parameters are kept
+fn loggable(uri: &Uri) -> String {
+    let full = uri.to_string();
+    let Some((target, query)) = full.split_once('?') else {
+        return full;
+    };
+    let names: Vec<&str> = query
+        .split('&')
+        .filter(|p| !p.is_empty())
+        .map(|p| p.split_once('=').map_or(p, |(name, _)| name))
+        .collect();
+    match names.is_empty() {
+        true => target.to_string(),
+        false => format!("{}?{}", target, names.join("&")),
+    }
+}
+
+/// Longer ids than this are replaced, they are most likely junk
+const MAX_REQUEST_ID_LEN: usize = 128;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LogFormat {
+    /// `host - user [time] "request" status bytes`, plus the latency
+    #[default]
+    Common,
+    /// Common, with the referer and user agent before the latency
+    Combined,
+    /// One JSON object per line
+    Json,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct AccessLogConfig {
+    #[serde(default)]
+    pub format: LogFormat,
+}
+
+/// Where the lines go, stdout unless a test wants them
+pub type Sink = Arc<Mutex<dyn Write + Send>>;
+
+#[derive(Clone)]
+pub struct AccessLog {
+    config: Arc<AccessLogConfig>,
+    sink: Sink,
+}
+
+impl AccessLog {
+    pub fn new(config: AccessLogConfig, sink: Sink) -> Self {
+        Self {
+            config: Arc::new(config),
+            sink,
+        }
+    }
+
+    pub fn stdout(config: AccessLogConfig) -> Self {
+        Self::new(config, Arc::new(Mutex::new(std::io::stdout())))
+    }
+
+    fn write(&self, entry: &Entry, status: u16, bytes: u64) {
+        let line = match self.config.format {
+            LogFormat::Common => format!("{} {}", entry.common(status, bytes), entry.latency_ms()),
+            LogFormat::Combined => format!(
+                "{} \"{}\" \"{}\" {}",
+                entry.common(status, bytes),
+                entry.referer.as_deref().unwrap_or("-"),
+                entry.user_agent.as_deref().unwrap_or("-"),
+                entry.latency_ms()
+            ),
+            LogFormat::Json => serde_json::json!({
+                "time": timestamp(entry.time, false),
+                "request_id": &*entry.context.id,
+                "remote": entry.remote,
+                "method": entry.method,
+                "path": entry.path,
+                "protocol": entry.protocol,
+                "status": status,
+                "bytes": bytes,
+                "latency_ms": entry.start.elapsed().as_secs_f64() * 1000.0,
+                "user": entry.context.user.get(),
+                "referer": entry.referer,
+                "user_agent": entry.user_agent,
+            })
+            .to_string(),
+        };
+        let _ = writeln!(self.sink.lock().unwrap(), "{}", line);
+    }
+}
+
+/// What a request carries around so that the user can be put in its span and
+/// in the access log once some filter finds out who they are
+#[derive(Clone)]
+pub struct RequestContext {
+    pub id: Arc<str>,
+    user: Arc<OnceLock<String>>,
+    span: Span,
+}
+
+impl RequestContext {
+    /// Redacted like every other log, since it ends up in the traces too
+    pub fn identified(&self, email: &str) {
+        let shown = privacy::email(email);
+        self.span.record("user", shown.as_str());
+        let _ = self.user.set(shown);
+    }
+}
+
+/// The id the client or a proxy sent, as long as it's sensible, or a new one
+fn request_id<B>(req: &Request<B>) -> Arc<str> {
+    req.headers()
+        .get(&REQUEST_ID)
+        .and_then(|id| id.to_str().ok())
+        .filter(|id| !id.is_empty() && id.len() <= MAX_REQUEST_ID_LEN && id.bytes().all(|b| b.is_ascii_graphic()))
+        .map(Arc::from)
+        .unwrap_or_else(|| Arc::from(session::random_token(16)))
+}
+
+/// Gives every request an id and a span, and logs it once its body was sent
+#[derive(Clone)]
+pub struct Traced<S> {
+    inner: S,
+    log: Option<AccessLog>,
+}
+
+impl<S> Traced<S> {
+    pub fn new(inner: S, log: Option<AccessLog>) -> Self {
+        Self { inner, log }
+    }
+}
+
+impl<S, B> Service<Request<Incoming>> for Traced<S>
+where
+    S: Service<Request<Incoming>, Response = Response<B>, Error = Infallible>,
+    S::Future: Send + 'static,
+    B: Body + Unpin,
+{
+    type Response = Response<Logged<B>>;
+    type Error = Infallible;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Infallible>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Infallible>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut req: Request<Incoming>) -> Self::Future {
+        let id = request_id(&req);
+        let span = info_span!("request", id = &*id, user = field::Empty);
+        let _ = span.set_parent(telemetry::parent_context(req.headers()));
+        let context = RequestContext {
+            id: id.clone(),
+            user: Default::default(),
+            span: span.clone(),
+        };
+        let header_value = HeaderValue::from_str(&id).expect("Request ids are visible ASCII");
+        req.headers_mut().insert(REQUEST_ID.clone(), header_value.clone());
+        req.extensions_mut().insert(context.clone());
+
+        let entry = self.log.as_ref().map(|log| {
+            let text = |name: header::HeaderName| {
+                req.headers().get(name).and_then(|v| v.to_str().ok()).map(str::to_string)
+            };
+            (
+                log.clone(),
+                Entry {
+                    time: SystemTime::now(),
+                    start: Instant::now(),
+                    remote: req.extensions().get::<RemoteAddr>().map(|r| r.0.ip().to_string()),
+                    method: req.method().to_string(),
+                    path: loggable(req.uri()),
+                    protocol: format!("{:?}", req.version()),
+                    referer: text(header::REFERER).map(|r| r.parse().map_or(r, |uri: Uri| loggable(&uri))),
+                    user_agent: text(header::USER_AGENT),
+                    context,
+                },
+            )
+        });
+
+        let response = span.in_scope(|| self.inner.call(req));
+        Box::pin(
+            async move {
+                let mut response = response.await?;
+                response.headers_mut().insert(REQUEST_ID.clone(), header_value);
+                let status = response.status().as_u16();
+                Ok(response.map(|body| Logged {
+                    body,
+                    bytes: 0,
+                    entry: entry.map(|(log, entry)| (log, entry, status)),
+                }))
+            }
+            .instrument(span),
+        )
+    }
+}
+
+struct Entry {
+    time: SystemTime,
+    start: Instant,
+    remote: Option<String>,
+    method: String,
+    path: String,
+    protocol: String,
+    referer: Option<String>,
+    user_agent: Option<String>,
+    context: RequestContext,
+}
+
+impl Entry {
+    fn common(&self, status: u16, bytes: u64) -> String {
+        format!(
+            "{} - {} [{}] \"{} {} {}\" {} {}",
+            self.remote.as_deref().unwrap_or("-"),
+            self.context.user.get().map_or("-", String::as_str),
+            timestamp(self.time, true),
+            self.method,
+            self.path,
+            self.protocol,
+            status,
+            match bytes {
+                0 => "-".to_string(),
+                bytes => bytes.to_string(),
+            }
+        )
+    }
+
+    fn latency_ms(&self) -> String {
+        format!("{:.3}ms", self.start.elapsed().as_secs_f64() * 1000.0)
+    }
+}
+
+/// A response body that counts what goes through it, and writes the access
+/// log line when it's done with, whether it was sent whole or not
+pub struct Logged<B> {
+    body: B,
+    bytes: u64,
+    entry: Option<(AccessLog, Entry, u16)>,
+}
+
+impl<B: Body + Unpin> Body for Logged<B> {
+    type Data = B::Data;
+    type Error = B::Error;
+
+    fn poll_frame(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Result<Frame<B::Data>, B::Error>>> {
+        let frame = Pin::new(&mut self.body).poll_frame(cx);
+        if let Poll::Ready(Some(Ok(frame))) = &frame {
+            if let Some(data) = frame.data_ref() {
+                self.bytes += warp::Buf::remaining(data) as u64;
+            }
+        }
+        frame
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.body.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.body.size_hint()
+    }
+}
+
+impl<B> Drop for Logged<B> {
+    fn drop(&mut self) {
+        if let Some((log, entry, status)) = self.entry.take() {
+            log.write(&entry, status, self.bytes);
+        }
+    }
+}
+
+/// `18/Oct/2026:13:55:36 +0000` for the common format, RFC 3339 otherwise
+pub fn timestamp(time: SystemTime, common: bool) -> String {
+    const MONTHS: [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since_epoch.as_secs();
+    let (hour, minute, second) = (secs / 3600 % 24, secs / 60 % 60, secs % 60);
+
+    // Days to a civil date, from Howard Hinnant's `civil_from_days`
+    let days = (secs / 86400) as i64 + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days.rem_euclid(146097);
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 { shifted_month + 3 } else { shifted_month - 9 };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+
+    match common {
+        true => format!(
+            "{:02}/{}/{}:{:02}:{:02}:{:02} +0000",
+            day,
+            MONTHS[month as usize - 1],
+            year,
+            hour,
+            minute,
+            second
+        ),
+        false => format!(
+            "{}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+            year,
+            month,
+            day,
+            hour,
+            minute,
+            second,
+            since_epoch.subsec_millis()
+        ),
+    }
+}
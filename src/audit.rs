@@ -0,0 +1,505 @@
+use std::{
+    collections::HashMap,
+    fs::{self, File, OpenOptions},
+    io::{self, Read, Seek, SeekFrom, Write},
+    net::{IpAddr, SocketAddr},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use handlebars::Handlebars;
+use ipnet::IpNet;
+use percent_encoding::percent_decode_str;
+use serde::{Deserialize, Serialize};
+use tokio::task;
+use tracing::{error, info, warn};
+use warp::{
+    filters::BoxedFilter,
+    http::{HeaderMap, StatusCode, Uri},
+    reject,
+    reply::Response,
+    Filter, Reply,
+};
+
+use crate::{
+    access_log::timestamp,
+    common::CurrentUserData,
+    consts::{self, defaults},
+    filters,
+    groups::Groups,
+    identity::IdentitySource,
+    rate_limit::{self, RateLimiter},
+    rendering::Renderer,
+    server::RemoteAddr,
+};
+
+const CURRENT: &str = "audit.jsonl";
+
+mod config_defaults {
+    pub fn dir() -> String {
+        crate::consts::defaults::AUDIT_DIR.to_string()
+    }
+
+    pub fn max_file_size() -> u64 {
+        crate::consts::defaults::AUDIT_MAX_FILE_SIZE
+    }
+
+    pub fn retention_days() -> u64 {
+        crate::consts::defaults::AUDIT_RETENTION_DAYS
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct AuditConfig {
+    /// Where the log files go, relative to the config dir
+    #[serde(default = "config_defaults::dir")]
+    pub dir: String,
+
+    /// A new file is started once the current one gets this big, and every day
+    #[serde(default = "config_defaults::max_file_size")]
+    pub max_file_size: u64,
+
+    /// Old files are deleted after this many days
+    #[serde(default = "config_defaults::retention_days")]
+    pub retention_days: u64,
+
+    /// Members of this group can browse the log at `/audit`
+    #[serde(default)]
+    pub admin_group: Option<String>,
+
+    /// Emails that can browse the log at `/audit`
+    #[serde(default)]
+    pub admins: Vec<String>,
+}
+
+impl AuditConfig {
+    fn is_admin(&self, user: &CurrentUserData) -> bool {
+        self.admins.iter().any(|a| a.eq_ignore_ascii_case(&user.email))
+            || self
+                .admin_group
+                .as_ref()
+                .is_some_and(|group| user.groups.contains(group))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Action {
+    /// Opened the launcher
+    View,
+    /// Went to a service through one of the tiles
+    Launch,
+}
+
+/// One line of the log. Unlike the other logs, the identity is never redacted:
+/// knowing who did what is the whole point
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Event {
+    pub time: String,
+    pub action: Action,
+    pub email: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub subject: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub route: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source_ip: Option<String>,
+}
+
+impl Event {
+    pub fn new(action: Action, user: &CurrentUserData, source_ip: Option<IpAddr>) -> Self {
+        Self {
+            time: timestamp(SystemTime::now(), false),
+            action,
+            email: user.email.clone(),
+            subject: user.subject.clone(),
+            route: None,
+            url: None,
+            source_ip: source_ip.map(|ip| ip.to_string()),
+        }
+    }
+}
+
+fn day(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs() / consts::time::days(1)
+}
+
+struct Writer {
+    dir: PathBuf,
+    file: Option<File>,
+    size: u64,
+    /// Day the current file was started
+    day: u64,
+}
+
+/// Append-only JSON lines, rotated by size and day, with the old files removed
+/// once they are past the retention
+#[derive(Clone)]
+pub struct AuditLog {
+    config: Arc<AuditConfig>,
+    writer: Arc<Mutex<Writer>>,
+}
+
+impl AuditLog {
+    pub fn new(config: AuditConfig) -> io::Result<Self> {
+        let dir = Path::new(consts::paths::get_conf_dir()).join(&config.dir);
+        fs::create_dir_all(&dir)?;
+        info!("Writing the audit log to {}", dir.display());
+        let log = Self {
+            config: Arc::new(config),
+            writer: Arc::new(Mutex::new(Writer {
+                dir,
+                file: None,
+                size: 0,
+                day: 0,
+            })),
+        };
+        log.prune(&log.writer.lock().unwrap().dir);
+        Ok(log)
+    }
+
+    pub fn config(&self) -> &AuditConfig {
+        &self.config
+    }
+
+    /// Writes `event` down, away from the request handlers as the disk might be
+    /// slow
+    pub async fn record(&self, event: &Event) {
+        let mut line = serde_json::to_string(event).expect("Audit events are always valid JSON");
+        line.push('\n');
+        let this = self.clone();
+        let written = task::spawn_blocking(move || this.append(line.as_bytes()))
+            .await
+            .expect("Writing to the audit log panicked");
+        if let Err(e) = written {
+            error!("Couldn't write to the audit log: {}", e);
+        }
+    }
+
+    fn append(&self, line: &[u8]) -> io::Result<()> {
+        let mut writer = self.writer.lock().unwrap();
+        let current = writer.dir.join(CURRENT);
+        if writer.file.is_none() {
+            let file = OpenOptions::new().create(true).append(true).open(&current)?;
+            let metadata = file.metadata()?;
+            writer.size = metadata.len();
+            writer.day = match metadata.len() {
+                0 => day(SystemTime::now()),
+                _ => day(metadata.modified()?),
+            };
+            writer.file = Some(file);
+        }
+
+        let today = day(SystemTime::now());
+        if writer.size > 0 && (writer.size + line.len() as u64 > self.config.max_file_size || writer.day != today) {
+            writer.file = None;
+            fs::rename(&current, rotated_name(&writer.dir))?;
+            writer.file = Some(OpenOptions::new().create(true).append(true).open(&current)?);
+            writer.size = 0;
+            writer.day = today;
+            self.prune(&writer.dir);
+        }
+
+        let file = writer.file.as_mut().expect("Opened above");
+        file.write_all(line)?;
+        writer.size += line.len() as u64;
+        Ok(())
+    }
+
+    /// Removes the rotated files that are past the retention
+    fn prune(&self, dir: &Path) {
+        let retention = Duration::from_secs(consts::time::days(self.config.retention_days));
+        for file in rotated_files(dir) {
+            let expired = fs::metadata(&file)
+                .and_then(|m| m.modified())
+                .is_ok_and(|modified| modified.elapsed().unwrap_or_default() > retention);
+            if expired {
+                match fs::remove_file(&file) {
+                    Ok(_) => info!("Removed {} from the audit log", file.display()),
+                    Err(e) => warn!("Couldn't remove {} from the audit log: {}", file.display(), e),
+                }
+            }
+        }
+    }
+
+    /// The newest events first, at most `limit` of those matching `search`
+    pub async fn search(&self, search: &Search, limit: usize) -> Vec<Event> {
+        let dir = self.writer.lock().unwrap().dir.clone();
+        let search = search.clone();
+        task::spawn_blocking(move || {
+            let mut files = vec![dir.join(CURRENT)];
+            files.extend(rotated_files(&dir).into_iter().rev());
+            files
+                .into_iter()
+                .filter_map(|file| File::open(file).and_then(ReverseLines::new).ok())
+                .flatten()
+                .filter_map(Result::ok)
+                .filter_map(|line| serde_json::from_str(&line).ok())
+                .filter(|event| search.matches(event))
+                .take(limit)
+                .collect()
+        })
+        .await
+        .expect("Searching the audit log panicked")
+    }
+}
+
+/// Lines of a file from the last one to the first, read a block at a time from
+/// the end so that searches for recent events don't go through whole files
+struct ReverseLines {
+    file: File,
+    pos: u64,
+    buf: Vec<u8>,
+}
+
+impl ReverseLines {
+    const BLOCK: u64 = 64 * 1024;
+
+    fn new(file: File) -> io::Result<Self> {
+        let pos = file.metadata()?.len();
+        Ok(Self {
+            file,
+            pos,
+            buf: Vec::new(),
+        })
+    }
+}
+
+impl Iterator for ReverseLines {
+    type Item = io::Result<String>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            // Everything after the last newline has been read, so it's a whole line
+            if let Some(end) = self.buf.iter().rposition(|&b| b == b'\n') {
+                let line = self.buf.split_off(end + 1);
+                self.buf.truncate(end);
+                if line.is_empty() {
+                    continue;
+                }
+                return Some(Ok(String::from_utf8_lossy(&line).into_owned()));
+            }
+            if self.pos == 0 {
+                return (!self.buf.is_empty())
+                    .then(|| Ok(String::from_utf8_lossy(&std::mem::take(&mut self.buf)).into_owned()));
+            }
+
+            let len = Self::BLOCK.min(self.pos);
+            self.pos -= len;
+            let mut block = vec![0; len as usize];
+            let read = self
+                .file
+                .seek(SeekFrom::Start(self.pos))
+                .and_then(|_| self.file.read_exact(&mut block));
+            if let Err(e) = read {
+                self.pos = 0;
+                self.buf.clear();
+                return Some(Err(e));
+            }
+            block.append(&mut self.buf);
+            self.buf = block;
+        }
+    }
+}
+
+/// Rotated files are named after when they were rotated, so they sort in order
+fn rotated_name(dir: &Path) -> PathBuf {
+    let now = timestamp(SystemTime::now(), false);
+    let stamp = now[..19].replace(':', "-");
+    let mut path = dir.join(format!("audit-{}.jsonl", stamp));
+    let mut n = 1;
+    while path.exists() {
+        path = dir.join(format!("audit-{}.{}.jsonl", stamp, n));
+        n += 1;
+    }
+    path
+}
+
+fn rotated_files(dir: &Path) -> Vec<PathBuf> {
+    let mut files: Vec<PathBuf> = fs::read_dir(dir)
+        .map(|entries| {
+            entries
+                .filter_map(|e| e.ok())
+                .map(|e| e.path())
+                .filter(|p| {
+                    p.file_name()
+                        .and_then(|n| n.to_str())
+                        .is_some_and(|n| n.starts_with("audit-") && n.ends_with(".jsonl"))
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+    files.sort();
+    files
+}
+
+/// What admins can narrow the log down with, all of them matching parts of
+/// the field regardless of case
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct Search {
+    #[serde(default)]
+    pub user: String,
+    #[serde(default)]
+    pub action: String,
+    #[serde(default)]
+    pub route: String,
+    #[serde(default)]
+    pub date: String,
+}
+
+impl Search {
+    fn matches(&self, event: &Event) -> bool {
+        let has = |field: &str, part: &str| part.is_empty() || field.to_lowercase().contains(&part.to_lowercase());
+        let action = match event.action {
+            Action::View => "view",
+            Action::Launch => "launch",
+        };
+        (has(&event.email, &self.user) || event.subject.as_deref().is_some_and(|s| has(s, &self.user)))
+            && has(action, &self.action)
+            && has(event.route.as_deref().unwrap_or_default(), &self.route)
+            && has(&event.time, &self.date)
+    }
+}
+
+/// The address of whoever is behind the trusted proxies, going back through
+/// `X-Forwarded-For` for as long as the hops are trusted
+pub fn source_ip(trusted: &[IpNet], remote: Option<SocketAddr>, headers: &HeaderMap) -> Option<IpAddr> {
+    let remote = remote?.ip().to_canonical();
+    let is_trusted = |ip: &IpAddr| trusted.iter().any(|net| net.contains(ip));
+    if !is_trusted(&remote) {
+        return Some(remote);
+    }
+
+    let forwarded: Vec<IpAddr> = headers
+        .get_all("x-forwarded-for")
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .filter_map(|ip| ip.trim().parse::<IpAddr>().ok())
+        .map(|ip| ip.to_canonical())
+        .collect();
+    Some(
+        forwarded
+            .iter()
+            .rev()
+            .find(|ip| !is_trusted(ip))
+            .or(forwarded.first())
+            .copied()
+            .unwrap_or(remote),
+    )
+}
+
+/// Finds out where the request really comes from, see `source_ip`
+pub fn source(trusted: Arc<[IpNet]>) -> impl warp::Filter<Extract = (Option<IpAddr>,), Error = std::convert::Infallible> + Clone {
+    warp::ext::optional::<RemoteAddr>()
+        .and(warp::header::headers_cloned())
+        .map(move |remote: Option<RemoteAddr>, headers: HeaderMap| source_ip(&trusted, remote.map(|r| r.0), &headers))
+}
+
+#[derive(Serialize)]
+struct Page<'a> {
+    events: &'a [Event],
+    search: &'a Search,
+    limit: usize,
+}
+
+/// `/launch/<tile>` sends users on to the tile's service, writing down that
+/// they went, and `/audit` lets admins look through the log
+pub fn routes(
+    audit: AuditLog,
+    identity: Arc<dyn IdentitySource>,
+    groups: Groups,
+    renderer: Renderer<'static>,
+    trusted: Arc<[IpNet]>,
+    template: PathBuf,
+    limiter: Option<RateLimiter>,
+) -> BoxedFilter<(Response,)> {
+    let launch_audit = audit.clone();
+    let launch = warp::path!("launch" / String)
+        .and(warp::get())
+        .and(rate_limit::per_identity(
+            limiter.clone(),
+            filters::claimed(identity.clone()),
+            filters::identity_with_groups(identity.clone(), groups.clone()),
+        ))
+        .and(source(trusted))
+        .and_then(move |tile: String, user: CurrentUserData, source_ip: Option<IpAddr>| {
+            let audit = launch_audit.clone();
+            let renderer = renderer.clone();
+            async move {
+                let tile = percent_decode_str(&tile).decode_utf8_lossy();
+                let (label, url) = renderer.launch_target(&user, &tile).ok_or_else(reject::not_found)?;
+                let location = Uri::try_from(url.as_str()).map_err(|_| reject::not_found())?;
+                audit.record(&Event {
+                    route: Some(label),
+                    url: Some(url),
+                    ..Event::new(Action::Launch, &user, source_ip)
+                })
+                .await;
+                Ok::<_, warp::Rejection>(warp::redirect::found(location).into_response())
+            }
+        });
+
+    let handlebars = Arc::new(Handlebars::new());
+    let page = warp::path!("audit")
+        .and(warp::get())
+        .and(rate_limit::per_identity(
+            limiter,
+            filters::claimed(identity.clone()),
+            filters::identity_with_groups(identity, groups),
+        ))
+        .and(warp::query::<HashMap<String, String>>())
+        .then(move |user: CurrentUserData, query: HashMap<String, String>| {
+            let audit = audit.clone();
+            let handlebars = handlebars.clone();
+            let template = template.clone();
+            async move {
+                if !audit.config().is_admin(&user) {
+                    return warp::reply::with_status("Only admins can see the audit log", StatusCode::FORBIDDEN)
+                        .into_response();
+                }
+
+                let search = Search {
+                    user: query.get("user").cloned().unwrap_or_default(),
+                    action: query.get("action").cloned().unwrap_or_default(),
+                    route: query.get("route").cloned().unwrap_or_default(),
+                    date: query.get("date").cloned().unwrap_or_default(),
+                };
+                let limit = query
+                    .get("limit")
+                    .and_then(|l| l.parse().ok())
+                    .unwrap_or(defaults::AUDIT_PAGE_SIZE)
+                    .min(defaults::AUDIT_MAX_PAGE_SIZE);
+                let events = audit.search(&search, limit).await;
+                if query.get("format").is_some_and(|f| f == "json") {
+                    return warp::reply::json(&events).into_response();
+                }
+
+                let page = Page {
+                    events: &events,
+                    search: &search,
+                    limit,
+                };
+                let rendered = fs::read_to_string(&template)
+                    .map_err(|e| e.to_string())
+                    .and_then(|html| handlebars.render_template(&html, &page).map_err(|e| e.to_string()));
+                match rendered {
+                    Ok(html) => warp::reply::html(html).into_response(),
+                    Err(e) => {
+                        error!("Can't render the audit page: {}", e);
+                        StatusCode::INTERNAL_SERVER_ERROR.into_response()
+                    }
+                }
+            }
+        });
+
+    launch
+        .or(page)
+        .unify()
+        .with(filters::disable_cache())
+        .map(Reply::into_response)
+        .boxed()
+}
@@ -0,0 +1,359 @@
+use std::{
+    collections::HashMap,
+    io::Cursor,
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, Mutex, RwLock},
+    time::{Duration, SystemTime},
+};
+
+use image::{ImageFormat, ImageReader, Limits};
+use openssl::sha::sha256;
+use reqwest::{
+    dns::{Name, Resolve, Resolving},
+    redirect, Url,
+};
+use tokio::{sync::watch, task};
+use tracing::{debug, warn};
+use warp::{http::Response, hyper::header};
+
+use crate::{common::CurrentUserData, consts};
+
+const SIZE: u32 = 128;
+const MAX_DOWNLOAD: usize = 5 * 1024 * 1024;
+/// Pictures are shrunk to `SIZE` anyway, bigger ones are most likely meant to
+/// make us allocate
+const MAX_DIMENSION: u32 = 4096;
+const MAX_DECODE_ALLOC: u64 = 64 * 1024 * 1024;
+
+/// Decodes an untrusted picture without letting it take more than its share
+fn decode(bytes: &[u8]) -> image::ImageResult<image::DynamicImage> {
+    let mut limits = Limits::default();
+    limits.max_image_width = Some(MAX_DIMENSION);
+    limits.max_image_height = Some(MAX_DIMENSION);
+    limits.max_alloc = Some(MAX_DECODE_ALLOC);
+
+    let mut reader = ImageReader::new(Cursor::new(bytes)).with_guessed_format()?;
+    reader.limits(limits);
+    reader.decode()
+}
+
+#[derive(Clone)]
+pub struct Avatar {
+    data: Arc<Vec<u8>>,
+    content_type: &'static str,
+}
+
+impl Avatar {
+    pub fn into_response(self) -> Response<Vec<u8>> {
+        Response::builder()
+            .header(header::CONTENT_TYPE, self.content_type)
+            .header(
+                header::CACHE_CONTROL,
+                format!("private, max-age={}", consts::time::hours(1)),
+            )
+            .body((*self.data).clone())
+            .expect("Avatar response is always valid")
+    }
+}
+
+/// Loopback, private, link-local and the like, where the services next to
+/// hallway live rather than anyone's picture
+fn is_internal(ip: IpAddr) -> bool {
+    match ip.to_canonical() {
+        IpAddr::V4(ip) => {
+            ip.is_loopback()
+                || ip.is_private()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+                // Carrier-grade NAT, 100.64.0.0/10
+                || (ip.octets()[0] == 100 && ip.octets()[1] & 0xc0 == 64)
+        }
+        IpAddr::V6(ip) => {
+            ip.is_loopback()
+                || ip.is_unspecified()
+                // Unique local, fc00::/7
+                || ip.segments()[0] & 0xfe00 == 0xfc00
+                // Link-local, fe80::/10
+                || ip.segments()[0] & 0xffc0 == 0xfe80
+        }
+    }
+}
+
+/// Pictures come from claims, so they are only fetched over HTTP(S) and from
+/// addresses that aren't internal. Host names are checked once resolved
+fn is_fetchable(url: &Url, allow_internal: bool) -> bool {
+    let scheme = matches!(url.scheme(), "http" | "https");
+    let host = url
+        .host_str()
+        .map(|h| h.trim_start_matches('[').trim_end_matches(']'))
+        .and_then(|h| h.parse::<IpAddr>().ok())
+        .is_none_or(|ip| allow_internal || !is_internal(ip));
+    scheme && host
+}
+
+/// Resolves host names to their public addresses only
+struct PublicResolver;
+
+impl Resolve for PublicResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        let host = name.as_str().to_string();
+        Box::pin(async move {
+            let addrs: Vec<SocketAddr> = tokio::net::lookup_host((host.as_str(), 0))
+                .await?
+                .filter(|addr| !is_internal(addr.ip()))
+                .collect();
+            if addrs.is_empty() {
+                return Err(format!("{} has no public address", host).into());
+            }
+            Ok(Box::new(addrs.into_iter()) as Box<dyn Iterator<Item = SocketAddr> + Send>)
+        })
+    }
+}
+
+struct CachedPicture {
+    // None when the picture couldn't be obtained, so that we don't keep retrying
+    avatar: Option<Avatar>,
+    time: SystemTime,
+}
+
+/// A download still going on, `None` until it's done
+type Pending = watch::Receiver<Option<Option<Avatar>>>;
+
+/// Pictures from the identity provider, downloaded by us so that the browser
+/// never talks to the provider, with a generated avatar as fallback
+#[derive(Clone)]
+pub struct Avatars {
+    client: reqwest::Client,
+    allow_internal: bool,
+    pictures: Arc<RwLock<HashMap<String, CachedPicture>>>,
+    pending: Arc<Mutex<HashMap<String, Pending>>>,
+}
+
+impl Avatars {
+    pub fn new() -> Self {
+        Self::with_internal(false)
+    }
+
+    /// For tests, which serve pictures on loopback
+    #[cfg(test)]
+    pub fn allowing_internal() -> Self {
+        Self::with_internal(true)
+    }
+
+    fn with_internal(allow_internal: bool) -> Self {
+        let builder = reqwest::Client::builder()
+            .timeout(Duration::from_secs(10))
+            .redirect(redirect::Policy::custom(move |attempt| {
+                match attempt.previous().len() < 5 && is_fetchable(attempt.url(), allow_internal) {
+                    true => attempt.follow(),
+                    false => attempt.stop(),
+                }
+            }));
+        let builder = match allow_internal {
+            true => builder,
+            false => builder.dns_resolver(PublicResolver),
+        };
+        Self {
+            client: builder.build().expect("Couldn't create HTTP client"),
+            allow_internal,
+            pictures: Arc::new(RwLock::new(HashMap::new())),
+            pending: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Url where the user's avatar can be found, changes whenever the avatar would
+    pub fn url_for(user: &CurrentUserData) -> String {
+        let mut id = user.email.clone();
+        id.push('\n');
+        id.push_str(user.picture.as_deref().unwrap_or(&user.name));
+        format!("/avatar?v={}", to_hex(&sha256(id.as_bytes())[..8]))
+    }
+
+    pub async fn get(&self, user: &CurrentUserData) -> Avatar {
+        let picture = match &user.picture {
+            Some(url) => self.get_picture(url).await,
+            None => None,
+        };
+        picture.unwrap_or_else(|| initials_avatar(&user.name, &user.email))
+    }
+
+    async fn get_picture(&self, url: &str) -> Option<Avatar> {
+        let ttl = Duration::from_secs(consts::defaults::AVATAR_TTL);
+        let cached = self.pictures.read().unwrap().get(url).and_then(|c| {
+            let fresh = c.time.elapsed().map(|e| e < ttl).unwrap_or(false);
+            fresh.then(|| c.avatar.clone())
+        });
+
+        match cached {
+            Some(avatar) => avatar,
+            None => {
+                let mut pending = self.fetch(url);
+                let done = pending.wait_for(Option::is_some).await.map(|avatar| avatar.clone());
+                done.ok().flatten().flatten()
+            }
+        }
+    }
+
+    /// Downloads the picture into the cache, unless that is already being done
+    fn fetch(&self, url: &str) -> Pending {
+        let mut pending = self.pending.lock().unwrap();
+        if let Some(download) = pending.get(url) {
+            return download.clone();
+        }
+        let (sender, receiver) = watch::channel(None);
+        pending.insert(url.to_string(), receiver.clone());
+
+        let this = self.clone();
+        let url = url.to_string();
+        tokio::spawn(async move {
+            let avatar = this.download(&url).await;
+            {
+                let ttl = Duration::from_secs(consts::defaults::AVATAR_TTL);
+                let mut pictures = this.pictures.write().unwrap();
+                pictures.retain(|_, c| c.time.elapsed().map(|e| e < ttl).unwrap_or(false));
+                pictures.insert(
+                    url.clone(),
+                    CachedPicture {
+                        avatar: avatar.clone(),
+                        time: SystemTime::now(),
+                    },
+                );
+            }
+            this.pending.lock().unwrap().remove(&url);
+            let _ = sender.send(Some(avatar));
+        });
+        receiver
+    }
+
+    async fn download(&self, url: &str) -> Option<Avatar> {
+        let url = match Url::parse(url) {
+            Ok(url) if is_fetchable(&url, self.allow_internal) => url,
+            _ => {
+                warn!("Not downloading an avatar from a non HTTP or internal address");
+                return None;
+            }
+        };
+        // The rest of the address might carry tokens
+        let host = url.host_str().unwrap_or_default().to_string();
+        debug!("Downloading avatar from {}", host);
+
+        // Read as it comes, so that a huge picture is given up on early
+        let fetched = async {
+            let mut resp = self.client.get(url).send().await?.error_for_status()?;
+            if resp.content_length().is_some_and(|len| len > MAX_DOWNLOAD as u64) {
+                return Ok(None);
+            }
+            let mut bytes = Vec::new();
+            while let Some(chunk) = resp.chunk().await? {
+                if bytes.len() + chunk.len() > MAX_DOWNLOAD {
+                    return Ok(None);
+                }
+                bytes.extend_from_slice(&chunk);
+            }
+            Ok::<_, reqwest::Error>(Some(bytes))
+        }
+        .await;
+
+        let bytes = match fetched {
+            Ok(Some(bytes)) => bytes,
+            Ok(None) => {
+                warn!("Avatar from {} is too big", host);
+                return None;
+            }
+            Err(e) => {
+                warn!("Couldn't download avatar from {}: {}", host, e.without_url());
+                return None;
+            }
+        };
+
+        // Decoding and resizing is heavy, keep it away from the request handlers
+        let resized = task::spawn_blocking(move || {
+            let mut out = Cursor::new(Vec::new());
+            decode(&bytes)?
+                .resize_to_fill(SIZE, SIZE, image::imageops::FilterType::Lanczos3)
+                .write_to(&mut out, ImageFormat::Png)?;
+            Ok::<_, image::ImageError>(out.into_inner())
+        })
+        .await;
+
+        match resized {
+            Ok(Ok(data)) => Some(Avatar {
+                data: Arc::new(data),
+                content_type: "image/png",
+            }),
+            Ok(Err(e)) => {
+                warn!("Avatar from {} is not a valid image: {}", host, e);
+                None
+            }
+            // Whatever went wrong decoding it, the initials will do
+            Err(e) => {
+                warn!("Resizing the avatar from {} failed: {}", host, e);
+                None
+            }
+        }
+    }
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn initials(name: &str, email: &str) -> String {
+    let from_words = |s: &str| -> String {
+        s.split(|c: char| !c.is_alphanumeric())
+            .filter_map(|w| w.chars().next())
+            .take(2)
+            .flat_map(char::to_uppercase)
+            .collect()
+    };
+
+    let res = from_words(name);
+    if res.is_empty() {
+        from_words(email.split('@').next().unwrap_or_default())
+    } else {
+        res
+    }
+}
+
+fn escape_xml(s: &str) -> String {
+    s.chars()
+        .map(|c| match c {
+            '&' => "&amp;".to_string(),
+            '<' => "&lt;".to_string(),
+            '>' => "&gt;".to_string(),
+            '"' => "&quot;".to_string(),
+            '\'' => "&apos;".to_string(),
+            c => c.to_string(),
+        })
+        .collect()
+}
+
+/// Deterministic avatar: the user's initials over a color picked from their email
+pub fn initials_avatar(name: &str, email: &str) -> Avatar {
+    let hash = sha256(email.to_lowercase().as_bytes());
+    let hue = u16::from_be_bytes([hash[0], hash[1]]) % 360;
+
+    let svg = format!(
+        concat!(
+            r#"<svg xmlns="http://www.w3.org/2000/svg" width="{size}" height="{size}" viewBox="0 0 {size} {size}">"#,
+            r#"<rect width="100%" height="100%" fill="hsl({hue}, 45%, 55%)"/>"#,
+            r##"<text x="50%" y="50%" dy=".35em" text-anchor="middle" fill="#ffffff" "##,
+            r#"font-family="sans-serif" font-size="{font}">{initials}</text></svg>"#
+        ),
+        size = SIZE,
+        hue = hue,
+        font = SIZE * 2 / 5,
+        initials = escape_xml(&initials(name, email)),
+    );
+
+    Avatar {
+        data: Arc::new(svg.into_bytes()),
+        content_type: "image/svg+xml",
+    }
+}
+
+#[cfg(test)]
+pub fn avatar_text(avatar: &Avatar) -> String {
+    String::from_utf8(avatar.data.to_vec()).unwrap()
+}
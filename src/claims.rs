@@ -0,0 +1,140 @@
+use std::collections::BTreeMap;
+
+use serde::Deserialize;
+use serde_json::{Map, Value};
+
+use crate::common::CurrentUserData;
+
+mod defaults {
+    pub fn email() -> Vec<String> {
+        vec!["email".to_string()]
+    }
+
+    pub fn name() -> Vec<String> {
+        vec![
+            "name".to_string(),
+            "{given_name} {family_name}".to_string(),
+            "preferred_username".to_string(),
+            "email".to_string(),
+        ]
+    }
+
+    pub fn picture() -> Vec<String> {
+        vec!["picture".to_string()]
+    }
+
+    pub fn groups() -> Vec<String> {
+        vec!["groups".to_string()]
+    }
+}
+
+/// Says which claims fill each field of the user. Every field is a fallback chain,
+/// the first entry present in the token wins. An entry is either the name of a
+/// claim or a template like `{given_name} {family_name}`, which is only used when
+/// all of its claims are present.
+#[derive(Debug, Clone, Deserialize)]
+pub struct ClaimMapping {
+    #[serde(default = "defaults::email")]
+    pub email: Vec<String>,
+
+    #[serde(default = "defaults::name")]
+    pub name: Vec<String>,
+
+    #[serde(default = "defaults::picture")]
+    pub picture: Vec<String>,
+
+    #[serde(default = "defaults::groups")]
+    pub groups: Vec<String>,
+
+    /// Any other claim that should be available to the templates as `user.claims`
+    #[serde(default)]
+    pub extra: BTreeMap<String, Vec<String>>,
+}
+
+impl Default for ClaimMapping {
+    fn default() -> Self {
+        Self {
+            email: defaults::email(),
+            name: defaults::name(),
+            picture: defaults::picture(),
+            groups: defaults::groups(),
+            extra: BTreeMap::new(),
+        }
+    }
+}
+
+fn as_text(value: &Value) -> Option<String> {
+    match value {
+        Value::String(s) if !s.is_empty() => Some(s.clone()),
+        Value::Number(n) => Some(n.to_string()),
+        Value::Bool(b) => Some(b.to_string()),
+        _ => None,
+    }
+}
+
+fn fill_template(template: &str, claims: &Map<String, Value>) -> Option<String> {
+    let mut res = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        let end = start + rest[start..].find('}')?;
+        res.push_str(&rest[..start]);
+        res.push_str(&claims.get(&rest[start + 1..end]).and_then(as_text)?);
+        rest = &rest[end + 1..];
+    }
+    res.push_str(rest);
+    Some(res)
+}
+
+/// First entry of the chain that can be resolved, templates always give text
+fn resolve(chain: &[String], claims: &Map<String, Value>) -> Option<Value> {
+    chain.iter().find_map(|entry| {
+        if entry.contains('{') {
+            fill_template(entry, claims).map(Value::String)
+        } else {
+            claims
+                .get(entry)
+                .filter(|v| !v.is_null() && v.as_str() != Some(""))
+                .cloned()
+        }
+    })
+}
+
+impl ClaimMapping {
+    /// Builds the user out of the claims, it fails only if no email can be found
+    pub fn map(&self, claims: &Map<String, Value>) -> Option<CurrentUserData> {
+        let email = resolve(&self.email, claims).as_ref().and_then(as_text)?;
+        let name = resolve(&self.name, claims)
+            .as_ref()
+            .and_then(as_text)
+            .unwrap_or_else(|| email.clone());
+        let picture = resolve(&self.picture, claims).as_ref().and_then(as_text);
+        // Some providers join groups with commas, in one string or in each item
+        let split = |groups: &str| -> Vec<String> {
+            groups
+                .split(',')
+                .map(|g| g.trim().to_string())
+                .filter(|g| !g.is_empty())
+                .collect()
+        };
+        let groups = match resolve(&self.groups, claims) {
+            Some(Value::Array(groups)) => groups.iter().filter_map(as_text).flat_map(|g| split(&g)).collect(),
+            Some(Value::String(groups)) => split(&groups),
+            _ => Vec::new(),
+        };
+        let extra = self
+            .extra
+            .iter()
+            .filter_map(|(key, chain)| resolve(chain, claims).map(|v| (key.clone(), v)))
+            .collect();
+
+        Some(CurrentUserData {
+            subject: claims.get("sub").and_then(as_text),
+            email,
+            name,
+            picture,
+            groups,
+            claims: extra,
+            client_certificate: None,
+        })
+    }
+}
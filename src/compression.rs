@@ -0,0 +1,371 @@
+use std::{
+    collections::{HashMap, HashSet},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use flate2::write::GzEncoder;
+use http_body_util::BodyExt;
+use httpdate::HttpDate;
+use openssl::sha::sha256;
+use tracing::{debug, warn};
+use warp::{
+    filters::path::{FullPath, Peek, Tail},
+    http::{header, HeaderValue, Response as HttpResponse, StatusCode},
+    hyper::body::Bytes,
+    reject,
+    reply::Response,
+    Filter, Rejection, Reply,
+};
+
+/// Content codings we know about, in the order we prefer them when the client
+/// likes them as much
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Encoding {
+    Brotli,
+    Zstd,
+    Gzip,
+}
+
+impl Encoding {
+    const ALL: [Encoding; 3] = [Encoding::Brotli, Encoding::Zstd, Encoding::Gzip];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Encoding::Brotli => "br",
+            Encoding::Zstd => "zstd",
+            Encoding::Gzip => "gzip",
+        }
+    }
+
+    /// Extension of the precompressed files next to the original
+    fn extension(self) -> &'static str {
+        match self {
+            Encoding::Brotli => "br",
+            Encoding::Zstd => "zst",
+            Encoding::Gzip => "gz",
+        }
+    }
+}
+
+/// Weight the client gave to `coding` in its `Accept-Encoding`, `*` standing in
+/// for whatever isn't named
+fn weight(accept: &str, coding: &str) -> f32 {
+    let mut wildcard = None;
+    for item in accept.split(',') {
+        let mut params = item.split(';');
+        let name = params.next().unwrap_or_default().trim();
+        let q = params
+            .filter_map(|p| p.trim().strip_prefix("q="))
+            .find_map(|q| q.trim().parse::<f32>().ok())
+            .unwrap_or(1.0);
+        if name.eq_ignore_ascii_case(coding) || (coding == "gzip" && name.eq_ignore_ascii_case("x-gzip")) {
+            return q;
+        }
+        if name == "*" {
+            wildcard = Some(q);
+        }
+    }
+    wildcard.unwrap_or(0.0)
+}
+
+/// Best of `available` for a client sending `accept`, `None` meaning it gets
+/// the content as it is
+pub fn negotiate(accept: Option<&str>, available: &[Encoding]) -> Option<Encoding> {
+    let accept = accept?;
+    Encoding::ALL
+        .into_iter()
+        .filter(|e| available.contains(e))
+        .map(|e| (e, weight(accept, e.name())))
+        .filter(|(_, q)| *q > 0.0)
+        // `max_by` keeps the last of equals, going backwards keeps our preference
+        .rev()
+        .max_by(|(_, a), (_, b)| a.total_cmp(b))
+        .map(|(e, _)| e)
+}
+
+fn brotli(data: &[u8], quality: i32) -> io::Result<Vec<u8>> {
+    let params = brotli::enc::BrotliEncoderParams {
+        quality,
+        lgwin: 22,
+        ..Default::default()
+    };
+    let mut out = Vec::new();
+    brotli::BrotliCompress(&mut &data[..], &mut out, &params)?;
+    Ok(out)
+}
+
+fn gzip(data: &[u8], level: flate2::Compression) -> io::Result<Vec<u8>> {
+    let mut encoder = GzEncoder::new(Vec::new(), level);
+    encoder.write_all(data)?;
+    encoder.finish()
+}
+
+/// Files that are compressed already, nothing to win there
+const INCOMPRESSIBLE: [&str; 11] = ["avif", "webp", "png", "jpg", "jpeg", "gif", "woff", "woff2", "br", "gz", "zst"];
+
+struct Variant {
+    body: Bytes,
+    /// Differs between encodings, caches mustn't mix them up
+    etag: String,
+}
+
+/// The compressed variants of one asset, modified when the original was
+struct Asset {
+    modified: Option<HttpDate>,
+    variants: HashMap<Encoding, Variant>,
+}
+
+impl Asset {
+    /// Whether the client's copy, as told by its conditional headers, is
+    /// still this variant
+    fn unchanged(&self, variant: &Variant, if_none_match: Option<&str>, if_modified_since: Option<&str>) -> bool {
+        if let Some(tags) = if_none_match {
+            return tags
+                .split(',')
+                .map(|t| t.trim().trim_start_matches("W/"))
+                .any(|t| t == "*" || t == variant.etag);
+        }
+        match (self.modified, if_modified_since.and_then(|s| s.parse::<HttpDate>().ok())) {
+            (Some(modified), Some(since)) => modified <= since,
+            _ => false,
+        }
+    }
+}
+
+/// Compressed variants of the assets, read from the `.br`, `.gz` and `.zst`
+/// files the build left next to them. Brotli and gzip are made on startup when
+/// missing, zstd always comes from the build
+#[derive(Clone, Default)]
+pub struct Precompressed {
+    dir: PathBuf,
+    assets: Arc<HashMap<String, Asset>>,
+    /// The build's variant files, only ever served in place of their original
+    hidden: Arc<HashSet<String>>,
+}
+
+impl Precompressed {
+    pub fn load(dir: &Path) -> Self {
+        fn walk(dir: &Path, files: &mut Vec<PathBuf>) -> io::Result<()> {
+            for entry in std::fs::read_dir(dir)? {
+                let path = entry?.path();
+                if path.is_dir() {
+                    walk(&path, files)?;
+                } else {
+                    files.push(path);
+                }
+            }
+            Ok(())
+        }
+
+        let mut files = Vec::new();
+        if let Err(e) = walk(dir, &mut files) {
+            warn!("Couldn't look for assets to compress in '{}': {}", dir.display(), e);
+        }
+        let relative = |path: &Path| {
+            path.strip_prefix(dir)
+                .ok()
+                .and_then(|p| p.to_str())
+                .map(|p| p.replace(std::path::MAIN_SEPARATOR, "/"))
+        };
+        let names: HashSet<String> = files.iter().filter_map(|p| relative(p)).collect();
+        let hidden = names
+            .iter()
+            .filter(|name| {
+                Encoding::ALL.into_iter().any(|e| {
+                    name.strip_suffix(e.extension())
+                        .and_then(|n| n.strip_suffix('.'))
+                        .is_some_and(|original| names.contains(original))
+                })
+            })
+            .cloned()
+            .collect();
+
+        let mut assets = HashMap::new();
+        let mut missing_zstd = 0;
+        for path in files {
+            let compressible = path
+                .extension()
+                .and_then(|e| e.to_str())
+                .is_some_and(|e| !INCOMPRESSIBLE.contains(&e.to_ascii_lowercase().as_str()));
+            let Some(relative) = relative(&path) else {
+                continue;
+            };
+            if !compressible {
+                continue;
+            }
+            let data = match std::fs::read(&path) {
+                Ok(data) => data,
+                Err(e) => {
+                    warn!("Couldn't read '{}' to compress it: {}", path.display(), e);
+                    continue;
+                }
+            };
+
+            let modified = std::fs::metadata(&path).and_then(|m| m.modified()).ok();
+
+            let mut variants = HashMap::new();
+            for encoding in Encoding::ALL {
+                let mut built = path.clone().into_os_string();
+                built.push(".");
+                built.push(encoding.extension());
+                let compressed = match (std::fs::read(&built), encoding) {
+                    (Ok(compressed), _) => Ok(compressed),
+                    (Err(_), Encoding::Brotli) => brotli(&data, 11),
+                    (Err(_), Encoding::Gzip) => gzip(&data, flate2::Compression::best()),
+                    (Err(_), Encoding::Zstd) => {
+                        missing_zstd += 1;
+                        continue;
+                    }
+                };
+                match compressed {
+                    // Not worth the client's time to decompress otherwise
+                    Ok(compressed) if compressed.len() < data.len() => {
+                        let hash: String = sha256(&compressed)[..8].iter().map(|b| format!("{:02x}", b)).collect();
+                        let variant = Variant {
+                            etag: format!("\"{}-{}\"", hash, encoding.extension()),
+                            body: Bytes::from(compressed),
+                        };
+                        variants.insert(encoding, variant);
+                    }
+                    Ok(_) => (),
+                    Err(e) => warn!("Couldn't compress '{}': {}", path.display(), e),
+                }
+            }
+            if !variants.is_empty() {
+                debug!("Compressed variants of '{}': {:?}", relative, variants.keys());
+                let asset = Asset {
+                    modified: modified.map(HttpDate::from),
+                    variants,
+                };
+                assets.insert(relative, asset);
+            }
+        }
+
+        if missing_zstd > 0 {
+            warn!("{} assets have no .zst next to them and won't be served as zstd", missing_zstd);
+        }
+
+        Self {
+            dir: dir.to_path_buf(),
+            assets: Arc::new(assets),
+            hidden: Arc::new(hidden),
+        }
+    }
+
+    /// Serves the assets, as the best compressed variant when the client takes
+    /// one and as they are otherwise
+    pub fn serve(&self) -> impl Filter<Extract = (Response,), Error = Rejection> + Clone {
+        let assets = self.assets.clone();
+        let compressed = warp::get()
+            .and(warp::path::tail())
+            .and(warp::header::optional::<String>("accept-encoding"))
+            .and(warp::header::optional::<String>("if-none-match"))
+            .and(warp::header::optional::<String>("if-modified-since"))
+            .and_then(
+                move |tail: Tail, accept: Option<String>, if_none_match: Option<String>, if_modified_since: Option<String>| {
+                    let assets = assets.clone();
+                    async move {
+                        let asset = assets.get(tail.as_str()).ok_or_else(reject::not_found)?;
+                        let available: Vec<_> = asset.variants.keys().copied().collect();
+                        let encoding = negotiate(accept.as_deref(), &available).ok_or_else(reject::not_found)?;
+                        let variant = &asset.variants[&encoding];
+
+                        let mut response = HttpResponse::builder()
+                            .header(header::CONTENT_ENCODING, encoding.name())
+                            .header(header::ETAG, &variant.etag);
+                        if let Some(modified) = asset.modified {
+                            response = response.header(header::LAST_MODIFIED, modified.to_string());
+                        }
+                        let response = match asset.unchanged(variant, if_none_match.as_deref(), if_modified_since.as_deref()) {
+                            true => response.status(StatusCode::NOT_MODIFIED).body(Bytes::new()),
+                            false => response
+                                .header(header::CONTENT_TYPE, mime_guess::from_path(tail.as_str()).first_or_octet_stream().as_ref())
+                                .body(variant.body.clone()),
+                        };
+                        Ok::<_, Rejection>(response.expect("Valid headers").into_response())
+                    }
+                },
+            );
+
+        let hidden = self.hidden.clone();
+        let originals = warp::path::peek()
+            .and_then(move |peek: Peek| {
+                let hidden = hidden.contains(peek.as_str());
+                async move {
+                    match hidden {
+                        true => Err(reject::not_found()),
+                        false => Ok(()),
+                    }
+                }
+            })
+            .untuple_one()
+            .and(warp::fs::dir(self.dir.clone()))
+            .map(Reply::into_response);
+
+        compressed.or(originals).unify()
+    }
+}
+
+/// Pages that echo what's in the request next to secrets, like the audit
+/// filters or the login forms. Compressing them would let the size of the
+/// response tell those secrets away (BREACH)
+fn reflects_request(path: &str) -> bool {
+    path == "/audit" || path.starts_with("/auth/")
+}
+
+/// Compresses rendered pages as well as the client allows. Everything else,
+/// and whatever is encoded already, goes out as it is
+pub async fn html<R: Reply>(path: FullPath, accept: Option<String>, reply: R) -> Response {
+    let mut response = reply.into_response();
+    let is_html = response
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|t| t.to_str().ok())
+        .is_some_and(|t| t.starts_with("text/html"));
+    if !is_html || response.headers().contains_key(header::CONTENT_ENCODING) || reflects_request(path.as_str()) {
+        return response;
+    }
+    response
+        .headers_mut()
+        .append(header::VARY, HeaderValue::from_static("accept-encoding"));
+    let Some(encoding) = negotiate(accept.as_deref(), &[Encoding::Brotli, Encoding::Gzip]) else {
+        return response;
+    };
+
+    let (mut parts, body) = response.into_parts();
+    let body = match body.collect().await {
+        Ok(body) => body.to_bytes(),
+        Err(e) => {
+            warn!("Couldn't read the page to compress it: {}", e);
+            return HttpResponse::from_parts(parts, Vec::<u8>::new().into());
+        }
+    };
+    // Pages are made for each request, so speed matters more than size here
+    let compressed = match encoding {
+        Encoding::Brotli => brotli(&body, 5),
+        _ => gzip(&body, flate2::Compression::default()),
+    };
+    match compressed {
+        Ok(compressed) => {
+            parts.headers.insert(header::CONTENT_ENCODING, HeaderValue::from_static(encoding.name()));
+            parts.headers.remove(header::CONTENT_LENGTH);
+            HttpResponse::from_parts(parts, compressed.into())
+        }
+        Err(e) => {
+            warn!("Couldn't compress the page: {}", e);
+            HttpResponse::from_parts(parts, body.into())
+        }
+    }
+}
+
+/// Tells caches the assets depend on `Accept-Encoding`, whichever variant was
+/// served
+pub fn vary<R: Reply>(reply: R) -> Response {
+    let mut response = reply.into_response();
+    response
+        .headers_mut()
+        .append(header::VARY, HeaderValue::from_static("accept-encoding"));
+    response
+}
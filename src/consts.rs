@@ -1,9 +1,44 @@
 pub mod defaults {
     pub const HTTP_PORT: u16 = 8080;
-    pub const SERVE_ADRESS: [u8; 4] = [0, 0, 0, 0];
+    pub const SERVE_ADDRESS: &str = "0.0.0.0"; // Comma separated, `::` for IPv6 as well
+    pub const SOCKET_MODE: u32 = 0o660; // Owner and group can talk to the Unix socket
     pub const CLEAN_TIME: u64 = 5 * 60 * 60; // 5 hours to check for old caches
     pub const MAX_TIME: u64 = 2 * 24 * 60 * 60; // 2 days max for cache
     pub const BACKGROUND: &str = "background.avif";
+    pub const JWT_CACHE_SIZE: usize = 1024; // Verified tokens kept around
+    pub const JWKS_REFRESH: u64 = 60 * 60; // Check for rotated keys every hour
+    pub const AVATAR_TTL: u64 = 24 * 60 * 60; // Download pictures again once a day
+    pub const SESSION_LIFETIME: u64 = 12 * 60 * 60; // Log in again twice a day
+    pub const LOGIN_FLOW_LIFETIME: u64 = 10 * 60; // Time given to finish logging in
+    pub const LOGIN_MAX_FAILURES: u32 = 5; // Wrong passwords allowed per user from one address
+    pub const LOGIN_MAX_FAILURES_PER_IP: u32 = 20; // And from one address whoever the user
+    pub const LOGIN_LOCKOUT: u64 = 15 * 60; // Until failed logins are forgotten
+    pub const USERS_FILE: &str = "users";
+    pub const GROUPS_FILE: &str = "groups.yaml";
+    pub const TLS_RELOAD_CHECK: u64 = 60; // Look for renewed certificates every minute
+    pub const TLS_HANDSHAKE_TIMEOUT: u64 = 10; // Clients that don't finish by then are dropped
+    pub const GROUPS_TTL: u64 = 5 * 60; // Memberships are looked up again after 5 minutes
+    pub const GROUPS_FAILURE_TTL: u64 = 30; // Backends that failed are left alone for a while
+    pub const GROUPS_MAX_STALE: u64 = 24 * 60 * 60; // Past this, users wait for the backend again
+    pub const CONTENT_SECURITY_POLICY: &str = "default-src 'self'; script-src 'self' 'nonce-{nonce}'; \
+        style-src 'self' 'unsafe-inline'; img-src 'self' data:; object-src 'none'; base-uri 'none'; \
+        frame-ancestors 'none'; form-action 'self'";
+    pub const REFERRER_POLICY: &str = "same-origin"; // Services behind us don't need to know where users came from
+    pub const PERMISSIONS_POLICY: &str = "camera=(), microphone=(), geolocation=(), payment=(), usb=()";
+    pub const AUDIT_DIR: &str = "audit";
+    pub const AUDIT_MAX_FILE_SIZE: u64 = 10 * 1024 * 1024; // Start a new file after 10MiB
+    pub const AUDIT_RETENTION_DAYS: u64 = 90; // Keep a quarter's worth of audit log
+    pub const AUDIT_PAGE_SIZE: usize = 200; // Events shown at once when browsing
+    pub const AUDIT_MAX_PAGE_SIZE: usize = 5000;
+    pub const RATE_LIMIT_IP_BURST: u32 = 60; // A few page loads at once, behind a shared NAT too
+    pub const RATE_LIMIT_IP_PER_MINUTE: u32 = 120;
+    pub const RATE_LIMIT_IDENTITY_BURST: u32 = 30;
+    pub const RATE_LIMIT_IDENTITY_PER_MINUTE: u32 = 60;
+
+    pub mod discovery {
+        pub const MIN_BACKOFF: u64 = 1; // Seconds before retrying discovery for the first time
+        pub const MAX_BACKOFF: u64 = 60; // Never wait more than a minute between retries
+    }
 
     #[cfg(not(feature = "container"))]
     pub mod debug {
@@ -0,0 +1,86 @@
+use std::{
+    collections::BTreeMap,
+    path::PathBuf,
+    sync::{Arc, RwLock},
+    time::SystemTime,
+};
+
+use tracing::info;
+
+use super::{GroupBackend, GroupsError, Lookup};
+use crate::{common::CurrentUserData, metrics::metrics, pomerium::Canonicalization};
+
+struct Memberships {
+    modified: Option<SystemTime>,
+    groups: Arc<BTreeMap<String, Vec<String>>>,
+}
+
+/// Groups kept in a YAML file in the config dir, each group listing the emails
+/// or subjects of its members:
+///
+/// ```yaml
+/// admins:
+///   - alice@example.com
+/// family:
+///   - alice@example.com
+///   - bob@example.com
+/// ```
+pub struct GroupsFile {
+    path: PathBuf,
+    canonicalization: Canonicalization,
+    memberships: RwLock<Memberships>,
+}
+
+impl GroupsFile {
+    pub fn new(path: PathBuf, canonicalization: Canonicalization) -> Self {
+        Self {
+            path,
+            canonicalization,
+            memberships: RwLock::new(Memberships {
+                modified: None,
+                groups: Default::default(),
+            }),
+        }
+    }
+
+    /// Current groups, read again whenever the file changes
+    fn groups(&self) -> Result<Arc<BTreeMap<String, Vec<String>>>, GroupsError> {
+        let modified = std::fs::metadata(&self.path).and_then(|m| m.modified()).ok();
+        {
+            let current = self.memberships.read().unwrap();
+            if current.modified.is_some() && current.modified == modified {
+                return Ok(current.groups.clone());
+            }
+        }
+
+        let groups = std::fs::read_to_string(&self.path)
+            .map_err(GroupsError::from)
+            .and_then(|content| Ok(serde_yaml::from_str::<BTreeMap<String, Vec<String>>>(&content)?));
+        metrics().reloaded("groups", groups.is_ok());
+        let groups = groups?;
+        info!("Loaded {} groups from {}", groups.len(), self.path.display());
+        let groups = Arc::new(groups);
+        *self.memberships.write().unwrap() = Memberships {
+            modified,
+            groups: groups.clone(),
+        };
+        Ok(groups)
+    }
+}
+
+impl GroupBackend for GroupsFile {
+    fn groups_for<'a>(&'a self, user: &'a CurrentUserData) -> Lookup<'a> {
+        Box::pin(async move {
+            let email = self.canonicalization.email(&user.email);
+            let is_user = |member: &String| {
+                Some(member) == user.subject.as_ref() || self.canonicalization.email(member) == email
+            };
+            Ok(self
+                .groups()?
+                .iter()
+                .filter(|(_, members)| members.iter().any(is_user))
+                .map(|(group, _)| group.clone())
+                .collect())
+        })
+    }
+}
@@ -0,0 +1,153 @@
+use std::time::Duration;
+
+use ldap3::{ldap_escape, LdapConnAsync, LdapConnSettings, Scope, SearchEntry};
+use serde::Deserialize;
+use tracing::{debug, warn};
+
+use super::{GroupBackend, GroupsError, Lookup};
+use crate::{common::CurrentUserData, privacy};
+
+mod defaults {
+    pub fn user_filter() -> String {
+        "(mail={email})".to_string()
+    }
+
+    // Covers groupOfNames and groupOfUniqueNames, posixGroup needs `(memberUid={user})`
+    pub fn group_filter() -> String {
+        "(|(member={dn})(uniqueMember={dn}))".to_string()
+    }
+
+    pub fn group_attribute() -> String {
+        "cn".to_string()
+    }
+
+    pub fn timeout() -> u64 {
+        5
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct LdapConfig {
+    /// `ldap://` or `ldaps://` url of the directory
+    pub url: String,
+
+    #[serde(default)]
+    pub starttls: bool,
+
+    /// Searches are anonymous without it
+    #[serde(default)]
+    pub bind_dn: Option<String>,
+
+    #[serde(default)]
+    pub bind_password: String,
+
+    /// Where users are, searched with `user_filter` to find the user's DN
+    pub user_base_dn: String,
+
+    /// `{email}` and `{user}` are replaced by the escaped email and subject
+    #[serde(default = "defaults::user_filter")]
+    pub user_filter: String,
+
+    pub group_base_dn: String,
+
+    /// Like `user_filter`, with the user's DN as `{dn}` as well
+    #[serde(default = "defaults::group_filter")]
+    pub group_filter: String,
+
+    /// The attribute that names each group
+    #[serde(default = "defaults::group_attribute")]
+    pub group_attribute: String,
+
+    /// Seconds to wait for the directory
+    #[serde(default = "defaults::timeout")]
+    pub timeout: u64,
+}
+
+/// Memberships from an LDAP directory, like OpenLDAP, lldap or Active Directory
+pub struct Ldap {
+    config: LdapConfig,
+}
+
+impl Ldap {
+    pub fn new(config: LdapConfig) -> Self {
+        Self { config }
+    }
+
+    fn filter(template: &str, user: &CurrentUserData, dn: &str) -> String {
+        let subject = user.subject.as_deref().unwrap_or(&user.email);
+        template
+            .replace("{email}", &ldap_escape(user.email.as_str()))
+            .replace("{user}", &ldap_escape(subject))
+            .replace("{dn}", &ldap_escape(dn))
+    }
+
+    async fn lookup(&self, user: &CurrentUserData) -> Result<Vec<String>, GroupsError> {
+        let config = &self.config;
+        let timeout = Duration::from_secs(config.timeout);
+        let settings = LdapConnSettings::new()
+            .set_conn_timeout(timeout)
+            .set_starttls(config.starttls);
+        let (conn, mut ldap) = LdapConnAsync::with_settings(settings, &config.url).await?;
+        tokio::spawn(async move {
+            if let Err(e) = conn.drive().await {
+                warn!("LDAP connection failed: {}", e);
+            }
+        });
+
+        if let Some(bind_dn) = &config.bind_dn {
+            ldap.with_timeout(timeout)
+                .simple_bind(bind_dn, &config.bind_password)
+                .await?
+                .success()?;
+        }
+
+        // "1.1" asks for no attributes at all, only the DN is needed
+        let (users, _) = ldap
+            .with_timeout(timeout)
+            .search(
+                &config.user_base_dn,
+                Scope::Subtree,
+                &Self::filter(&config.user_filter, user, ""),
+                vec!["1.1"],
+            )
+            .await?
+            .success()?;
+        let Some(user_entry) = users.into_iter().next() else {
+            debug!("'{}' is not in the directory", privacy::email(&user.email));
+            ldap.unbind().await?;
+            return Ok(Vec::new());
+        };
+        let dn = SearchEntry::construct(user_entry).dn;
+
+        let (groups, _) = ldap
+            .with_timeout(timeout)
+            .search(
+                &config.group_base_dn,
+                Scope::Subtree,
+                &Self::filter(&config.group_filter, user, &dn),
+                vec![config.group_attribute.as_str()],
+            )
+            .await?
+            .success()?;
+        ldap.unbind().await?;
+
+        Ok(groups
+            .into_iter()
+            .map(SearchEntry::construct)
+            .filter_map(|g| {
+                g.attrs
+                    .into_iter()
+                    .find(|(attr, _)| attr.eq_ignore_ascii_case(&config.group_attribute))?
+                    .1
+                    .into_iter()
+                    .next()
+            })
+            .collect())
+    }
+}
+
+impl GroupBackend for Ldap {
+    fn groups_for<'a>(&'a self, user: &'a CurrentUserData) -> Lookup<'a> {
+        Box::pin(self.lookup(user))
+    }
+}
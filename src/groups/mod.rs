@@ -0,0 +1,235 @@
+use std::{
+    collections::HashMap,
+    future::Future,
+    path::Path,
+    pin::Pin,
+    sync::{Arc, Mutex, RwLock},
+    time::Duration,
+};
+
+use serde::Deserialize;
+use tokio::{sync::watch, time::Instant};
+use tracing::{debug, info, warn};
+
+use crate::{common::CurrentUserData, consts, privacy};
+
+mod file;
+mod ldap;
+
+pub use file::GroupsFile;
+pub use ldap::{Ldap, LdapConfig};
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BackendKind {
+    /// Only the groups the identity source knows about
+    #[default]
+    None,
+    File,
+    Ldap,
+}
+
+fn default_ttl() -> u64 {
+    consts::defaults::GROUPS_TTL
+}
+
+fn default_failure_ttl() -> u64 {
+    consts::defaults::GROUPS_FAILURE_TTL
+}
+
+fn default_groups_file() -> String {
+    consts::defaults::GROUPS_FILE.to_string()
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Config {
+    #[serde(default)]
+    pub backend: BackendKind,
+
+    /// Seconds a membership is remembered before asking the backend again
+    #[serde(default = "default_ttl")]
+    pub ttl: u64,
+
+    /// Seconds a failed lookup is remembered, so that a backend that is down
+    /// isn't waited on by every request
+    #[serde(default = "default_failure_ttl")]
+    pub failure_ttl: u64,
+
+    /// Groups file for the file backend, relative to the config dir
+    #[serde(default = "default_groups_file")]
+    pub file: String,
+
+    #[serde(default)]
+    pub ldap: Option<LdapConfig>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            backend: BackendKind::default(),
+            ttl: default_ttl(),
+            failure_ttl: default_failure_ttl(),
+            file: default_groups_file(),
+            ldap: None,
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum GroupsError {
+    #[error("couldn't read the groups file: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("malformed groups file: {0}")]
+    Yaml(#[from] serde_yaml::Error),
+    #[error("{0}")]
+    Ldap(#[from] ldap3::LdapError),
+}
+
+pub type Lookup<'a> = Pin<Box<dyn Future<Output = Result<Vec<String>, GroupsError>> + Send + 'a>>;
+
+/// Somewhere group memberships can be looked up
+pub trait GroupBackend: Send + Sync {
+    fn groups_for<'a>(&'a self, user: &'a CurrentUserData) -> Lookup<'a>;
+}
+
+struct Cached {
+    groups: Arc<Vec<String>>,
+    fetched: Instant,
+    /// The backend failed, `groups` are the ones we knew before
+    failed: bool,
+}
+
+/// A lookup still waiting for the backend, `None` until it's done
+type Pending = watch::Receiver<Option<Arc<Vec<String>>>>;
+
+struct Inner {
+    backend: Box<dyn GroupBackend>,
+    ttl: Duration,
+    failure_ttl: Duration,
+    cache: RwLock<HashMap<String, Cached>>,
+    pending: Mutex<HashMap<String, Pending>>,
+}
+
+/// Adds the groups found in the configured backend to the ones the identity
+/// source already gave us, for when the provider doesn't put them in its tokens
+#[derive(Clone, Default)]
+pub struct Groups(Option<Arc<Inner>>);
+
+impl Groups {
+    pub fn from_config(config: &crate::config::Config) -> Self {
+        let groups = &config.groups;
+        let backend: Box<dyn GroupBackend> = match groups.backend {
+            BackendKind::None => return Self::default(),
+            BackendKind::File => Box::new(GroupsFile::new(
+                Path::new(consts::paths::get_conf_dir()).join(&groups.file),
+                config.canonicalization,
+            )),
+            BackendKind::Ldap => Box::new(Ldap::new(
+                groups
+                    .ldap
+                    .clone()
+                    .expect("The ldap groups backend needs a [groups.ldap] section"),
+            )),
+        };
+        info!("Looking up groups in the {:?} backend", groups.backend);
+        Self::new(
+            backend,
+            Duration::from_secs(groups.ttl),
+            Duration::from_secs(groups.failure_ttl),
+        )
+    }
+
+    pub fn new(backend: Box<dyn GroupBackend>, ttl: Duration, failure_ttl: Duration) -> Self {
+        Self(Some(Arc::new(Inner {
+            backend,
+            ttl,
+            failure_ttl,
+            cache: RwLock::new(HashMap::new()),
+            pending: Mutex::new(HashMap::new()),
+        })))
+    }
+
+    /// The user with their memberships from the backend added
+    pub async fn resolve(&self, mut user: CurrentUserData) -> CurrentUserData {
+        let Some(inner) = &self.0 else {
+            return user;
+        };
+        for group in inner.lookup(&user).await.iter() {
+            if !user.groups.contains(group) {
+                user.groups.push(group.clone());
+            }
+        }
+        user
+    }
+}
+
+impl Inner {
+    /// Memberships are only waited for the first time, after that the ones we
+    /// have are used while they are refreshed
+    async fn lookup(self: &Arc<Self>, user: &CurrentUserData) -> Arc<Vec<String>> {
+        let cached = self.cache.read().unwrap().get(&user.email).map(|c| {
+            let ttl = if c.failed { self.failure_ttl } else { self.ttl };
+            (c.fetched.elapsed() < ttl, c.groups.clone())
+        });
+        match cached {
+            Some((true, groups)) => groups,
+            Some((false, groups)) => {
+                self.refresh(user);
+                groups
+            }
+            None => {
+                let mut pending = self.refresh(user);
+                let done = pending.wait_for(Option::is_some).await.map(|groups| groups.clone());
+                done.ok().flatten().unwrap_or_default()
+            }
+        }
+    }
+
+    /// Asks the backend again, unless that is already being done for the user
+    fn refresh(self: &Arc<Self>, user: &CurrentUserData) -> Pending {
+        let mut pending = self.pending.lock().unwrap();
+        if let Some(lookup) = pending.get(&user.email) {
+            return lookup.clone();
+        }
+        let (sender, receiver) = watch::channel(None);
+        pending.insert(user.email.clone(), receiver.clone());
+
+        let this = self.clone();
+        let user = user.clone();
+        tokio::spawn(async move {
+            let groups = this.fetch(&user).await;
+            this.pending.lock().unwrap().remove(&user.email);
+            let _ = sender.send(Some(groups));
+        });
+        receiver
+    }
+
+    async fn fetch(&self, user: &CurrentUserData) -> Arc<Vec<String>> {
+        let result = self.backend.groups_for(user).await;
+        let mut cache = self.cache.write().unwrap();
+        let max_age = self.ttl.max(Duration::from_secs(consts::defaults::GROUPS_MAX_STALE));
+        cache.retain(|_, c| c.fetched.elapsed() < max_age);
+        let entry = match result {
+            Ok(groups) => {
+                debug!("'{}' is in groups {:?}", privacy::email(&user.email), groups);
+                Cached {
+                    groups: Arc::new(groups),
+                    fetched: Instant::now(),
+                    failed: false,
+                }
+            }
+            Err(e) => {
+                // Better to show what we knew than to drop every group tile
+                warn!("Couldn't look up the groups of '{}': {}", privacy::email(&user.email), e);
+                Cached {
+                    groups: cache.get(&user.email).map(|c| c.groups.clone()).unwrap_or_default(),
+                    fetched: Instant::now(),
+                    failed: true,
+                }
+            }
+        };
+        let groups = entry.groups.clone();
+        cache.insert(user.email.clone(), entry);
+        groups
+    }
+}
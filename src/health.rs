@@ -0,0 +1,111 @@
+use std::{
+    collections::BTreeMap,
+    convert::Infallible,
+    path::{Path, PathBuf},
+};
+
+use serde::Serialize;
+use warp::{filters::BoxedFilter, http::StatusCode, reply::Response, Filter, Reply};
+
+use crate::{readiness::Readiness, rendering::Renderer};
+
+#[derive(Debug, Serialize)]
+pub struct Check {
+    pub ok: bool,
+    pub detail: String,
+}
+
+impl Check {
+    fn new(ok: bool, detail: impl Into<String>) -> Self {
+        Self {
+            ok,
+            detail: detail.into(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct Report {
+    pub ready: bool,
+    pub checks: BTreeMap<&'static str, Check>,
+}
+
+/// Pages and their assets are read from disk as they are requested, so this
+/// blocks and is kept off the runtime
+fn assets(html_files: &Path) -> Check {
+    let assets = html_files.join("assets");
+    match std::fs::read_dir(&assets) {
+        Ok(_) => Check::new(true, "readable"),
+        Err(e) => Check::new(false, format!("can't read {}: {}", assets.display(), e)),
+    }
+}
+
+/// What stands between hallway and serving users
+pub fn report(readiness: &Readiness, renderer: &Renderer, assets: Check) -> Report {
+    let discovered = readiness.get();
+    let mut checks = BTreeMap::new();
+
+    checks.insert(
+        "config",
+        match renderer.loaded() {
+            (0, _) => Check::new(false, "no routes, nobody would see a tile"),
+            (routes, policies) => Check::new(true, format!("routes: {}, pomerium routes: {}", routes, policies)),
+        },
+    );
+    checks.insert(
+        "identity",
+        match &discovered {
+            Some(_) => Check::new(true, "discovered"),
+            None => Check::new(false, "waiting for the identity source"),
+        },
+    );
+    checks.insert(
+        "keys",
+        match discovered.as_ref().map(|d| d.jwt_decoder.as_ref().map(|j| j.key_count())) {
+            None => Check::new(false, "not fetched yet"),
+            Some(None) => Check::new(true, "not needed by the identity source"),
+            Some(Some(0)) => Check::new(false, "no signing keys"),
+            Some(Some(count)) => Check::new(true, format!("signing keys: {}", count)),
+        },
+    );
+    let mut templates = renderer.templates();
+    templates.sort_unstable();
+    checks.insert(
+        "templates",
+        match templates.contains(&"index.html") {
+            true => Check::new(true, format!("compiled: {}", templates.join(", "))),
+            false => Check::new(false, "index.html is missing"),
+        },
+    );
+    checks.insert("assets", assets);
+
+    Report {
+        ready: checks.values().all(|c| c.ok),
+        checks,
+    }
+}
+
+/// Probes for Docker and Kubernetes, they need no identity at all
+pub fn routes(readiness: Readiness, renderer: Renderer<'static>, html_files: PathBuf) -> BoxedFilter<(Response,)> {
+    let healthz = warp::path!("healthz").map(|| warp::reply::json(&serde_json::json!({ "status": "ok" })).into_response());
+    let readyz = warp::path!("readyz").and_then(move || {
+        let (readiness, renderer, html_files) = (readiness.clone(), renderer.clone(), html_files.clone());
+        async move {
+            let assets = tokio::task::spawn_blocking(move || assets(&html_files))
+                .await
+                .unwrap_or_else(|e| Check::new(false, format!("couldn't look at the files: {}", e)));
+            let report = report(&readiness, &renderer, assets);
+            let status = match report.ready {
+                true => StatusCode::OK,
+                false => StatusCode::SERVICE_UNAVAILABLE,
+            };
+            Ok::<_, Infallible>(warp::reply::with_status(warp::reply::json(&report), status).into_response())
+        }
+    });
+
+    warp::get()
+        .and(healthz.or(readyz).unify())
+        .with(crate::filters::disable_cache())
+        .map(Reply::into_response)
+        .boxed()
+}
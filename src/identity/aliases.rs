@@ -0,0 +1,92 @@
+use std::{collections::HashMap, sync::Arc};
+
+use serde::Deserialize;
+use tracing::debug;
+use warp::{filters::BoxedFilter, reply::Response};
+
+use super::{IdentityError, IdentitySource, RequestInfo};
+use crate::{common::CurrentUserData, privacy};
+
+/// One person known by several identities
+#[derive(Debug, Clone, Deserialize)]
+pub struct Alias {
+    /// The email policies know this person by
+    pub canonical: String,
+
+    /// The user id policies know this person by, for the `user` criterion
+    #[serde(default)]
+    pub canonical_subject: Option<String>,
+
+    #[serde(default)]
+    pub emails: Vec<String>,
+
+    /// Subjects as given by the identity provider
+    #[serde(default)]
+    pub subjects: Vec<String>,
+}
+
+/// Turns alternate emails and subjects into their canonical identity, so
+/// everything after the identity source only ever sees that one
+pub struct Aliased {
+    inner: Arc<dyn IdentitySource>,
+    // Emails are looked up lowercased
+    by_email: HashMap<String, Arc<Alias>>,
+    by_subject: HashMap<String, Arc<Alias>>,
+}
+
+impl Aliased {
+    pub fn new(inner: Arc<dyn IdentitySource>, aliases: &[Alias]) -> Self {
+        let aliases: Vec<_> = aliases.iter().cloned().map(Arc::new).collect();
+        let by_email = aliases
+            .iter()
+            .flat_map(|a| a.emails.iter().map(|e| (e.to_lowercase(), a.clone())))
+            .collect();
+        let by_subject = aliases
+            .iter()
+            .flat_map(|a| a.subjects.iter().map(|s| (s.clone(), a.clone())))
+            .collect();
+
+        Self {
+            inner,
+            by_email,
+            by_subject,
+        }
+    }
+
+    fn canonical(&self, user: &CurrentUserData) -> Option<&Alias> {
+        user.subject
+            .as_ref()
+            .and_then(|s| self.by_subject.get(s))
+            .or_else(|| self.by_email.get(&user.email.to_lowercase()))
+            .map(|a| a.as_ref())
+    }
+}
+
+impl IdentitySource for Aliased {
+    fn identify(&self, request: &RequestInfo) -> Result<CurrentUserData, IdentityError> {
+        let mut user = self.inner.identify(request)?;
+        if let Some(alias) = self.canonical(&user) {
+            debug!("{} is known as {}", privacy::email(&user.email), privacy::email(&alias.canonical));
+            if user.name == user.email {
+                user.name = alias.canonical.clone();
+            }
+            user.email = alias.canonical.clone();
+            if let Some(subject) = &alias.canonical_subject {
+                user.subject = Some(subject.clone());
+            }
+        }
+        Ok(user)
+    }
+
+    fn claimed(&self, request: &RequestInfo) -> Option<String> {
+        let email = self.inner.claimed(request)?;
+        match self.by_email.get(&email.to_lowercase()) {
+            Some(alias) => Some(alias.canonical.clone()),
+            None => Some(email),
+        }
+    }
+
+    fn routes(&self) -> BoxedFilter<(Response,)> {
+        self.inner.routes()
+    }
+}
@@ -0,0 +1,160 @@
+use base64::{engine::general_purpose::STANDARD, Engine};
+use ipnet::IpNet;
+use openssl::{hash::MessageDigest, nid::Nid, sha::sha256, x509::X509};
+use percent_encoding::percent_decode_str;
+use serde::{Deserialize, Serialize};
+use tracing::debug;
+
+use super::{is_trusted, IdentityError, IdentitySource, RequestInfo};
+use crate::common::CurrentUserData;
+
+fn default_header() -> String {
+    "X-Forwarded-Client-Cert".to_string()
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct CertificateConfig {
+    /// Header where the proxy puts the verified certificate
+    #[serde(default = "default_header")]
+    pub header: String,
+}
+
+impl Default for CertificateConfig {
+    fn default() -> Self {
+        Self {
+            header: default_header(),
+        }
+    }
+}
+
+/// The certificate the user presented, as the `client_certificate` criterion sees it
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ClientCertificate {
+    /// Hex SHA-256 of the DER certificate
+    pub fingerprint: String,
+    /// Base64 SHA-256 of the DER SubjectPublicKeyInfo
+    pub spki_hash: String,
+}
+
+/// A certificate with everything we care about already taken out of it
+pub struct ParsedCertificate {
+    pub certificate: ClientCertificate,
+    pub email: Option<String>,
+    pub common_name: Option<String>,
+}
+
+impl ParsedCertificate {
+    pub fn from_x509(cert: &X509) -> Option<Self> {
+        let fingerprint = cert
+            .digest(MessageDigest::sha256())
+            .ok()?
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect();
+        let spki_hash = STANDARD.encode(sha256(&cert.public_key().ok()?.public_key_to_der().ok()?));
+
+        let entry = |nid| {
+            cert.subject_name()
+                .entries_by_nid(nid)
+                .next()
+                .and_then(|e| e.data().to_string().ok())
+        };
+        // SANs are where emails are supposed to be, the subject is the old way
+        let email = cert
+            .subject_alt_names()
+            .and_then(|names| names.iter().find_map(|n| n.email().map(String::from)))
+            .or_else(|| entry(Nid::PKCS9_EMAILADDRESS));
+
+        Some(Self {
+            certificate: ClientCertificate {
+                fingerprint,
+                spki_hash,
+            },
+            email,
+            common_name: entry(Nid::COMMONNAME),
+        })
+    }
+
+    pub fn from_der(der: &[u8]) -> Option<Self> {
+        Self::from_x509(&X509::from_der(der).ok()?)
+    }
+
+    /// Proxies forward certificates in a few ways: Envoy and Pomerium use the
+    /// `Cert="..."` field of `X-Forwarded-Client-Cert`, nginx sends the PEM url
+    /// encoded, and some send the PEM as is
+    pub fn from_header(value: &str) -> Option<Self> {
+        let encoded = value
+            .split([';', ','])
+            .find_map(|f| f.trim().strip_prefix("Cert="))
+            .map(|c| c.trim_matches('"'))
+            .unwrap_or(value.trim());
+        let pem = percent_decode_str(encoded).decode_utf8().ok()?;
+        Self::from_x509(&X509::from_pem(pem.as_bytes()).ok()?)
+    }
+
+    pub fn into_user(self) -> Option<CurrentUserData> {
+        let email = self.email?;
+        Some(CurrentUserData {
+            subject: None,
+            name: self.common_name.unwrap_or_else(|| email.clone()),
+            email,
+            picture: None,
+            groups: Vec::new(),
+            claims: Default::default(),
+            client_certificate: Some(self.certificate),
+        })
+    }
+}
+
+/// Identities from client certificates, verified either by our own TLS listener
+/// or by a proxy in front
+pub struct ClientCertificates {
+    trusted_proxies: Vec<IpNet>,
+    trust_unix_socket: bool,
+    header: String,
+}
+
+impl ClientCertificates {
+    pub fn new(trusted_proxies: Vec<IpNet>, config: CertificateConfig) -> Self {
+        Self {
+            trusted_proxies,
+            trust_unix_socket: false,
+            header: config.header,
+        }
+    }
+
+    /// Believes the header from whoever connects over the Unix socket too
+    pub fn with_unix_socket(self, trust_unix_socket: bool) -> Self {
+        Self {
+            trust_unix_socket,
+            ..self
+        }
+    }
+}
+
+impl IdentitySource for ClientCertificates {
+    fn identify(&self, request: &RequestInfo) -> Result<CurrentUserData, IdentityError> {
+        let parsed = match &request.peer_certificate {
+            Some(der) => ParsedCertificate::from_der(der),
+            None => {
+                if !is_trusted(&self.trusted_proxies, self.trust_unix_socket, request) {
+                    return Err(IdentityError::Untrusted);
+                }
+                let value = request
+                    .headers
+                    .get(&self.header)
+                    .and_then(|v| v.to_str().ok())
+                    .ok_or(IdentityError::Missing)?;
+                ParsedCertificate::from_header(value)
+            }
+        };
+        let parsed = parsed.ok_or_else(|| {
+            debug!("Client certificate couldn't be parsed");
+            IdentityError::Invalid
+        })?;
+        parsed.into_user().ok_or_else(|| {
+            debug!("Client certificate has no email");
+            IdentityError::Invalid
+        })
+    }
+}
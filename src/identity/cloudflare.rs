@@ -0,0 +1,55 @@
+use std::sync::Arc;
+
+use serde::Deserialize;
+
+use super::{IdentitySource, JwtAssertion};
+use crate::{
+    config::Config,
+    jwt::{cloudflare, JwtDecoder},
+    readiness::{JwtEndpoints, Readiness},
+};
+
+/// The Access application hallway is exposed through
+#[derive(Debug, Clone, Deserialize)]
+pub struct CloudflareConfig {
+    /// Either `myteam` or `myteam.cloudflareaccess.com`
+    pub team_domain: String,
+
+    /// Application Audience (AUD) tags, any of them is accepted
+    pub audiences: Vec<String>,
+
+    /// Where the team's keys are, only needed when they aren't at the usual place
+    #[serde(default)]
+    pub certs_url: Option<String>,
+}
+
+/// Identities asserted by Cloudflare Access through a signed JWT
+pub fn source(config: &Config, readiness: &Readiness) -> Arc<dyn IdentitySource> {
+    let cf = config
+        .identity
+        .cloudflare
+        .clone()
+        .expect("The cloudflare_access identity source needs a [identity.cloudflare] section");
+    assert!(
+        !cf.audiences.is_empty(),
+        "The cloudflare_access identity source needs at least one audience tag"
+    );
+
+    let sign_out_url = match config.identity.sign_out_url.as_str() {
+        "" => cloudflare::LOGOUT.to_string(),
+        url => url.to_string(),
+    };
+    let endpoints = JwtEndpoints {
+        jwks_uri: cf
+            .certs_url
+            .clone()
+            .unwrap_or_else(|| cloudflare::certs_url(&cf.team_domain)),
+        sign_out_url,
+    };
+
+    let claim_mapping = Arc::new(config.claims.clone());
+    readiness.start_discovery(async { endpoints }, move |keys| {
+        JwtDecoder::cloudflare_access(&cf.team_domain, &cf.audiences, keys, claim_mapping)
+    });
+    Arc::new(JwtAssertion::new(cloudflare::HEADER, readiness.clone()))
+}
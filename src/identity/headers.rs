@@ -0,0 +1,204 @@
+use std::sync::Arc;
+
+use ipnet::IpNet;
+use serde::Deserialize;
+use serde_json::{Map, Value};
+
+use super::{is_trusted, IdentityError, IdentitySource, RequestInfo};
+use crate::{claims::ClaimMapping, common::CurrentUserData};
+
+const POMERIUM_CLAIM_PREFIX: &str = "x-pomerium-claim-";
+
+mod defaults {
+    // Covers oauth2-proxy, Authelia and Authentik out of the box
+    pub fn email() -> Vec<String> {
+        ["X-Forwarded-Email", "Remote-Email", "X-Authentik-Email"]
+            .map(String::from)
+            .to_vec()
+    }
+
+    pub fn user() -> Vec<String> {
+        ["X-Forwarded-User", "Remote-User", "X-Authentik-Username"]
+            .map(String::from)
+            .to_vec()
+    }
+
+    pub fn name() -> Vec<String> {
+        ["X-Forwarded-Preferred-Username", "Remote-Name", "X-Authentik-Name"]
+            .map(String::from)
+            .to_vec()
+    }
+
+    pub fn groups() -> Vec<String> {
+        ["X-Forwarded-Groups", "Remote-Groups", "X-Authentik-Groups"]
+            .map(String::from)
+            .to_vec()
+    }
+}
+
+/// Headers looked at for each field, the first one present wins
+#[derive(Debug, Clone, Deserialize)]
+pub struct HeaderNames {
+    #[serde(default = "defaults::email")]
+    pub email: Vec<String>,
+
+    #[serde(default = "defaults::user")]
+    pub user: Vec<String>,
+
+    #[serde(default = "defaults::name")]
+    pub name: Vec<String>,
+
+    #[serde(default = "defaults::groups")]
+    pub groups: Vec<String>,
+}
+
+impl Default for HeaderNames {
+    fn default() -> Self {
+        Self {
+            email: defaults::email(),
+            user: defaults::user(),
+            name: defaults::name(),
+            groups: defaults::groups(),
+        }
+    }
+}
+
+/// Identities asserted through plain headers by a forward-auth proxy like
+/// oauth2-proxy, Authelia or Authentik. Anyone could send those headers, so they
+/// are only believed when they come from a trusted proxy.
+pub struct TrustedHeaders {
+    trusted_proxies: Vec<IpNet>,
+    trust_unix_socket: bool,
+    names: HeaderNames,
+}
+
+impl TrustedHeaders {
+    pub fn new(trusted_proxies: Vec<IpNet>, names: HeaderNames) -> Self {
+        Self {
+            trusted_proxies,
+            trust_unix_socket: false,
+            names,
+        }
+    }
+
+    /// Believes the headers from whoever connects over the Unix socket too
+    pub fn with_unix_socket(self, trust_unix_socket: bool) -> Self {
+        Self {
+            trust_unix_socket,
+            ..self
+        }
+    }
+
+    fn first(request: &RequestInfo, names: &[String]) -> Option<String> {
+        names.iter().find_map(|n| {
+            request
+                .headers
+                .get(n)
+                .and_then(|v| v.to_str().ok())
+                .map(str::trim)
+                .filter(|v| !v.is_empty())
+                .map(String::from)
+        })
+    }
+}
+
+impl IdentitySource for TrustedHeaders {
+    fn identify(&self, request: &RequestInfo) -> Result<CurrentUserData, IdentityError> {
+        if !is_trusted(&self.trusted_proxies, self.trust_unix_socket, request) {
+            return Err(IdentityError::Untrusted);
+        }
+
+        let user = Self::first(request, &self.names.user);
+        let email = Self::first(request, &self.names.email)
+            .or_else(|| user.clone().filter(|u| u.contains('@')))
+            .ok_or(IdentityError::Missing)?;
+        let name = Self::first(request, &self.names.name)
+            .or(user.clone())
+            .unwrap_or_else(|| email.clone());
+
+        // oauth2-proxy and Authelia separate groups with commas, Authentik uses pipes
+        let groups = self
+            .names
+            .groups
+            .iter()
+            .flat_map(|n| request.headers.get_all(n))
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split([',', '|']))
+            .map(str::trim)
+            .filter(|g| !g.is_empty())
+            .map(String::from)
+            .collect();
+
+        Ok(CurrentUserData {
+            subject: user,
+            email,
+            name,
+            picture: None,
+            groups,
+            claims: Default::default(),
+            client_certificate: None,
+        })
+    }
+}
+
+/// Claims forwarded one by one by pomerium's `jwt_claims_headers`, as
+/// `X-Pomerium-Claim-<Claim>`. Like any other header they are only believed
+/// when they come from a trusted proxy
+pub struct PomeriumClaimHeaders {
+    trusted_proxies: Vec<IpNet>,
+    trust_unix_socket: bool,
+    claim_mapping: Arc<ClaimMapping>,
+}
+
+impl PomeriumClaimHeaders {
+    pub fn new(trusted_proxies: Vec<IpNet>, claim_mapping: Arc<ClaimMapping>) -> Self {
+        Self {
+            trusted_proxies,
+            trust_unix_socket: false,
+            claim_mapping,
+        }
+    }
+
+    /// Believes the headers from whoever connects over the Unix socket too
+    pub fn with_unix_socket(self, trust_unix_socket: bool) -> Self {
+        Self {
+            trust_unix_socket,
+            ..self
+        }
+    }
+
+    /// Header names are case insensitive, so `X-Pomerium-Claim-Given-Name`
+    /// becomes `given_name`. Claims sent more than once become arrays
+    fn claims(request: &RequestInfo) -> Map<String, Value> {
+        let mut claims = Map::new();
+        for (name, value) in request.headers.iter() {
+            let (Some(claim), Ok(value)) = (name.as_str().strip_prefix(POMERIUM_CLAIM_PREFIX), value.to_str()) else {
+                continue;
+            };
+            let claim = claim.replace('-', "_");
+            let value = Value::String(value.trim().to_string());
+            match claims.get_mut(&claim) {
+                Some(Value::Array(values)) => values.push(value),
+                Some(first) => *first = Value::Array(vec![first.take(), value]),
+                None => {
+                    claims.insert(claim, value);
+                }
+            }
+        }
+        claims
+    }
+}
+
+impl IdentitySource for PomeriumClaimHeaders {
+    fn identify(&self, request: &RequestInfo) -> Result<CurrentUserData, IdentityError> {
+        if !is_trusted(&self.trusted_proxies, self.trust_unix_socket, request) {
+            return Err(IdentityError::Untrusted);
+        }
+
+        let claims = Self::claims(request);
+        if claims.is_empty() {
+            return Err(IdentityError::Missing);
+        }
+        self.claim_mapping.map(&claims).ok_or(IdentityError::Missing)
+    }
+}
@@ -0,0 +1,40 @@
+use aliri::Jwt;
+
+use super::{IdentityError, IdentitySource, RequestInfo};
+use crate::{common::CurrentUserData, readiness::Readiness};
+
+/// Identities asserted through a signed JWT in a request header, verified with
+/// the keys found on discovery
+pub struct JwtAssertion {
+    header: &'static str,
+    readiness: Readiness,
+}
+
+impl JwtAssertion {
+    pub fn new(header: &'static str, readiness: Readiness) -> Self {
+        Self { header, readiness }
+    }
+
+    fn token<'a>(&self, request: &'a RequestInfo) -> Option<&'a str> {
+        request.headers.get(self.header).and_then(|h| h.to_str().ok())
+    }
+}
+
+impl IdentitySource for JwtAssertion {
+    fn identify(&self, request: &RequestInfo) -> Result<CurrentUserData, IdentityError> {
+        let jwt = self.token(request).ok_or(IdentityError::Missing)?;
+
+        let discovered = self.readiness.get().ok_or(IdentityError::NotReady)?;
+        discovered
+            .jwt_decoder
+            .as_ref()
+            .ok_or(IdentityError::NotReady)?
+            .decode(Jwt::from(jwt))
+            .ok_or(IdentityError::Invalid)
+    }
+
+    fn claimed(&self, request: &RequestInfo) -> Option<String> {
+        let jwt = self.token(request)?;
+        self.readiness.get()?.jwt_decoder.as_ref()?.claimed_email(jwt)
+    }
+}
@@ -0,0 +1,436 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, OnceLock, RwLock},
+    time::{Duration, Instant, SystemTime},
+};
+
+use argon2::{
+    password_hash::{PasswordHash, PasswordHasher, SaltString},
+    Argon2, PasswordVerifier,
+};
+use handlebars::Handlebars;
+use ipnet::IpNet;
+use openssl::sha::sha256;
+use serde::{Deserialize, Serialize};
+use tokio::task;
+use tracing::{debug, error, info, warn};
+use warp::{
+    filters::BoxedFilter,
+    http::{header, HeaderValue, StatusCode, Uri},
+    reply::Response,
+    Filter, Reply,
+};
+
+use super::{IdentityError, IdentitySource, RequestInfo};
+use crate::{
+    audit,
+    common::CurrentUserData,
+    config::Config,
+    consts,
+    metrics::metrics,
+    readiness::{Discovered, Readiness},
+    rendering::GlobalData,
+    session::{self, SessionConfig, SessionKey, SESSION_COOKIE},
+};
+
+fn default_users_file() -> String {
+    consts::defaults::USERS_FILE.to_string()
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct LocalConfig {
+    /// Accounts file, relative to the config dir
+    #[serde(default = "default_users_file")]
+    pub users_file: String,
+
+    #[serde(flatten)]
+    pub session: SessionConfig,
+}
+
+impl Default for LocalConfig {
+    fn default() -> Self {
+        Self {
+            users_file: default_users_file(),
+            session: SessionConfig::default(),
+        }
+    }
+}
+
+struct Account {
+    hash: String,
+    user: CurrentUserData,
+}
+
+/// Accounts are kept in an htpasswd-like file, one per line:
+/// `username:hash[:email[:display name[:group,group]]]`. Hashes can be argon2
+/// or bcrypt, as made by `htpasswd -B` or `argon2`. Without an email the
+/// username is used in its place
+fn parse_accounts(content: &str) -> HashMap<String, Account> {
+    content
+        .lines()
+        .map(str::trim)
+        .filter(|l| !l.is_empty() && !l.starts_with('#'))
+        .filter_map(|line| {
+            let mut fields = line.splitn(5, ':');
+            let (Some(username), Some(hash)) = (fields.next(), fields.next()) else {
+                warn!("Ignoring malformed account line");
+                return None;
+            };
+            let non_empty = |f: Option<&str>| f.map(str::trim).filter(|f| !f.is_empty()).map(String::from);
+            let email = non_empty(fields.next()).unwrap_or_else(|| username.to_string());
+            let name = non_empty(fields.next()).unwrap_or_else(|| username.to_string());
+            let groups = fields
+                .next()
+                .map(|g| {
+                    g.split(',')
+                        .map(str::trim)
+                        .filter(|g| !g.is_empty())
+                        .map(String::from)
+                        .collect()
+                })
+                .unwrap_or_default();
+
+            let account = Account {
+                hash: hash.to_string(),
+                user: CurrentUserData {
+                    subject: Some(username.to_string()),
+                    email,
+                    name,
+                    picture: None,
+                    groups,
+                    claims: Default::default(),
+                    client_certificate: None,
+                },
+            };
+            Some((username.to_string(), account))
+        })
+        .collect()
+}
+
+fn verify_password(password: &str, hash: &str) -> bool {
+    if hash.starts_with("$argon2") {
+        PasswordHash::new(hash)
+            .map(|h| Argon2::default().verify_password(password.as_bytes(), &h).is_ok())
+            .unwrap_or(false)
+    } else if hash.starts_with("$2") {
+        bcrypt::verify(password, hash).unwrap_or(false)
+    } else {
+        warn!("Unsupported password hash, only argon2 and bcrypt can be used");
+        false
+    }
+}
+
+/// Checked against when there's no such user, so unknown usernames take as
+/// long to turn down as wrong passwords
+fn dummy_hash() -> &'static str {
+    static HASH: OnceLock<String> = OnceLock::new();
+    HASH.get_or_init(|| {
+        let salt = SaltString::encode_b64(b"hallway dummy salt").expect("Valid salt");
+        Argon2::default()
+            .hash_password(b"not anyone's password", &salt)
+            .expect("Hashing with the default parameters")
+            .to_string()
+    })
+}
+
+/// Identifies the password hash without giving it away, so that sessions
+/// end when the password changes
+fn fingerprint(hash: &str) -> String {
+    sha256(hash.as_bytes())[..16].iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// What the session cookie holds, the user's details are looked up in the
+/// accounts file on each request so that changes to it apply right away
+#[derive(Serialize, Deserialize)]
+struct LocalSession {
+    username: String,
+    fingerprint: String,
+}
+
+struct Failures {
+    count: u32,
+    since: Instant,
+}
+
+/// What failed logins are counted against, and how many of them it takes
+pub struct LoginKey {
+    key: String,
+    max_failures: u32,
+}
+
+impl LoginKey {
+    /// The user from one address, so that nobody can lock someone else out,
+    /// and the address as a whole, allowed more so that one forgetful user
+    /// doesn't lock out everyone behind the same NAT
+    fn for_login(username: &str, address: IpAddr) -> [LoginKey; 2] {
+        [
+            LoginKey {
+                key: format!("user:{}@{}", username, address),
+                max_failures: consts::defaults::LOGIN_MAX_FAILURES,
+            },
+            LoginKey {
+                key: format!("ip:{}", address),
+                max_failures: consts::defaults::LOGIN_MAX_FAILURES_PER_IP,
+            },
+        ]
+    }
+}
+
+/// Counts failed logins, refusing to even check the password once there were
+/// too many of them
+#[derive(Default)]
+pub struct LoginLimiter {
+    failures: Mutex<HashMap<String, Failures>>,
+}
+
+impl LoginLimiter {
+    /// How long until `keys` can try again, if they are locked out
+    pub fn locked_for(&self, keys: &[LoginKey]) -> Option<Duration> {
+        let lockout = Duration::from_secs(consts::defaults::LOGIN_LOCKOUT);
+        let mut failures = self.failures.lock().unwrap();
+        failures.retain(|_, f| f.since.elapsed() < lockout);
+        keys.iter()
+            .filter_map(|k| failures.get(&k.key).filter(|f| f.count >= k.max_failures))
+            .map(|f| lockout.saturating_sub(f.since.elapsed()))
+            .max()
+    }
+
+    pub fn failed(&self, keys: &[LoginKey]) {
+        let mut failures = self.failures.lock().unwrap();
+        for key in keys {
+            failures
+                .entry(key.key.clone())
+                .or_insert_with(|| Failures {
+                    count: 0,
+                    since: Instant::now(),
+                })
+                .count += 1;
+        }
+    }
+
+    /// Only the user's own failures are forgotten, a valid account mustn't
+    /// be a way to keep guessing others from the same address
+    pub fn succeeded(&self, keys: &[LoginKey]) {
+        let mut failures = self.failures.lock().unwrap();
+        keys.iter().filter(|k| k.max_failures == consts::defaults::LOGIN_MAX_FAILURES).for_each(|k| {
+            failures.remove(&k.key);
+        });
+    }
+}
+
+#[derive(Deserialize)]
+struct LoginForm {
+    username: String,
+    password: String,
+}
+
+struct Accounts {
+    modified: Option<SystemTime>,
+    accounts: Arc<HashMap<String, Account>>,
+}
+
+struct Inner {
+    path: PathBuf,
+    accounts: RwLock<Accounts>,
+    key: SessionKey,
+    lifetime: Duration,
+    session: SessionConfig,
+    domain: String,
+    trusted: Arc<[IpNet]>,
+    limiter: LoginLimiter,
+}
+
+/// Users log in with a password against a local accounts file, for setups
+/// where running an identity provider would be overkill
+#[derive(Clone)]
+pub struct LocalAccounts(Arc<Inner>);
+
+pub fn source(config: &Config, readiness: &Readiness) -> Arc<dyn IdentitySource> {
+    let local = config.identity.local.clone().unwrap_or_default();
+    let path = Path::new(consts::paths::get_conf_dir()).join(&local.users_file);
+
+    readiness.set_ready(Discovered {
+        jwt_decoder: None,
+        global_data: Arc::new(GlobalData {
+            sign_out_url: "/auth/logout".to_string(),
+        }),
+    });
+    Arc::new(LocalAccounts::new(
+        path,
+        &local.session,
+        &config.domain.name,
+        config.identity.trusted_proxies.clone().into(),
+    ))
+}
+
+impl LocalAccounts {
+    fn new(path: PathBuf, session: &SessionConfig, domain: &str, trusted: Arc<[IpNet]>) -> Self {
+        let this = Self(Arc::new(Inner {
+            path,
+            accounts: RwLock::new(Accounts {
+                modified: None,
+                accounts: Default::default(),
+            }),
+            key: session.key(),
+            lifetime: session.lifetime(),
+            session: session.clone(),
+            domain: domain.to_string(),
+            trusted,
+            limiter: LoginLimiter::default(),
+        }));
+        dummy_hash();
+        let count = this.accounts().len();
+        info!("Loaded {} local accounts from {}", count, this.0.path.display());
+        this
+    }
+
+    /// Current accounts, read again whenever the file changes
+    fn accounts(&self) -> Arc<HashMap<String, Account>> {
+        let modified = std::fs::metadata(&self.0.path).and_then(|m| m.modified()).ok();
+        {
+            let current = self.0.accounts.read().unwrap();
+            if current.modified.is_some() && current.modified == modified {
+                return current.accounts.clone();
+            }
+        }
+
+        let accounts = match std::fs::read_to_string(&self.0.path) {
+            Ok(content) => {
+                metrics().reloaded("users", true);
+                Arc::new(parse_accounts(&content))
+            }
+            Err(e) => {
+                metrics().reloaded("users", false);
+                error!("Couldn't read accounts at '{}': {}", self.0.path.display(), e);
+                Default::default()
+            }
+        };
+        *self.0.accounts.write().unwrap() = Accounts {
+            modified,
+            accounts: accounts.clone(),
+        };
+        accounts
+    }
+
+    fn render_login(error: Option<&str>, status: StatusCode) -> Response {
+        #[derive(Serialize)]
+        struct LoginData<'a> {
+            error: Option<&'a str>,
+        }
+
+        let path = Path::new(consts::paths::get_html_files_dir()).join("login.html");
+        let html = std::fs::read_to_string(&path)
+            .map_err(|e| e.to_string())
+            .and_then(|html| {
+                Handlebars::new()
+                    .render_template(&html, &LoginData { error })
+                    .map_err(|e| e.to_string())
+            })
+            .unwrap_or_else(|e| {
+                error!("Can't render login page: {}", e);
+                "Sorry we had an error!".to_string()
+            });
+        warp::reply::with_status(warp::reply::html(html), status).into_response()
+    }
+
+    async fn login(&self, form: LoginForm, source: Option<IpAddr>, secure: bool) -> Response {
+        let address = source.unwrap_or(IpAddr::from([0, 0, 0, 0]));
+        let keys = LoginKey::for_login(&form.username, address);
+
+        if let Some(wait) = self.0.limiter.locked_for(&keys) {
+            warn!("Too many failed logins from {}", address);
+            let minutes = wait.as_secs().div_ceil(60);
+            let mut response = Self::render_login(
+                Some(&format!("Too many attempts, try again in {} minutes", minutes)),
+                StatusCode::TOO_MANY_REQUESTS,
+            );
+            response
+                .headers_mut()
+                .insert(header::RETRY_AFTER, HeaderValue::from(wait.as_secs()));
+            return response;
+        }
+
+        let account = self.accounts().get(&form.username).map(|a| a.hash.clone());
+        let username = form.username;
+        // Hashing is slow on purpose, keep it away from the request handlers
+        let verified = task::spawn_blocking(move || match account {
+            Some(hash) => verify_password(&form.password, &hash).then(|| LocalSession {
+                username,
+                fingerprint: fingerprint(&hash),
+            }),
+            None => {
+                verify_password(&form.password, dummy_hash());
+                None
+            }
+        })
+        .await
+        .expect("Password verification panicked");
+
+        match verified {
+            Some(local_session) => {
+                self.0.limiter.succeeded(&keys);
+                debug!("Local user logged in");
+                let user_session = self.0.key.seal(SESSION_COOKIE, &local_session, self.0.lifetime);
+                let mut response = warp::redirect::see_other(Uri::from_static("/")).into_response();
+                response.headers_mut().insert(
+                    header::SET_COOKIE,
+                    HeaderValue::from_str(&session::set_cookie(SESSION_COOKIE, &user_session, self.0.lifetime, secure))
+                        .expect("Cookies are always valid headers"),
+                );
+                response
+            }
+            None => {
+                self.0.limiter.failed(&keys);
+                info!("Failed login from {}", address);
+                Self::render_login(Some("Wrong username or password"), StatusCode::UNAUTHORIZED)
+            }
+        }
+    }
+}
+
+impl IdentitySource for LocalAccounts {
+    fn identify(&self, request: &RequestInfo) -> Result<CurrentUserData, IdentityError> {
+        let local_session: LocalSession = session::get_cookie(&request.headers, SESSION_COOKIE)
+            .and_then(|c| self.0.key.open(SESSION_COOKIE, c))
+            .ok_or(IdentityError::LoginRequired)?;
+        // Accounts that were removed or had their password changed since
+        self.accounts()
+            .get(&local_session.username)
+            .filter(|account| fingerprint(&account.hash) == local_session.fingerprint)
+            .map(|account| account.user.clone())
+            .ok_or(IdentityError::LoginRequired)
+    }
+
+    fn routes(&self) -> BoxedFilter<(Response,)> {
+        let secure = self.0.session.secure_cookies(&self.0.domain);
+        let form = warp::path!("auth" / "login")
+            .and(warp::get())
+            .map(|| Self::render_login(None, StatusCode::OK));
+        let login = {
+            let this = self.clone();
+            warp::path!("auth" / "login")
+                .and(warp::post())
+                .and(warp::body::content_length_limit(4 * 1024))
+                .and(warp::body::form::<LoginForm>())
+                .and(audit::source(self.0.trusted.clone()))
+                .and(secure.clone())
+                .then(move |form, source, secure| {
+                    let this = this.clone();
+                    async move { this.login(form, source, secure).await }
+                })
+        };
+        let logout = warp::path!("auth" / "logout").and(warp::get()).and(secure).map(|secure| {
+            let mut response = warp::redirect::found(Uri::from_static("/auth/login")).into_response();
+            response.headers_mut().insert(
+                header::SET_COOKIE,
+                HeaderValue::from_str(&session::clear_cookie(SESSION_COOKIE, secure))
+                    .expect("Cookies are always valid headers"),
+            );
+            response
+        });
+
+        form.or(login).unify().or(logout).unify().boxed()
+    }
+}
@@ -0,0 +1,203 @@
+use std::{net::SocketAddr, sync::Arc};
+
+use ipnet::IpNet;
+use serde::Deserialize;
+use tracing::warn;
+use warp::{filters::BoxedFilter, http::HeaderMap, reject, reply::Response, Filter};
+
+use crate::{
+    common::CurrentUserData,
+    readiness::{Discovered, Readiness},
+    rendering::GlobalData,
+};
+
+mod aliases;
+mod certificate;
+mod cloudflare;
+mod headers;
+mod jwt;
+mod local;
+mod oidc;
+mod pomerium;
+
+pub use certificate::ClientCertificate;
+#[cfg(test)]
+pub use certificate::ParsedCertificate;
+pub use headers::{PomeriumClaimHeaders, TrustedHeaders};
+pub use jwt::JwtAssertion;
+
+/// The parts of a request identity sources get to look at
+pub struct RequestInfo {
+    pub headers: HeaderMap,
+    pub remote: Option<SocketAddr>,
+    /// Whether it came over a Unix socket, which has no address to check
+    pub unix_socket: bool,
+    /// DER client certificate, when we verified it on our own TLS listener
+    pub peer_certificate: Option<Arc<[u8]>>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum IdentityError {
+    /// The request carries no identity at all
+    Missing,
+    /// There's an identity, but it couldn't be verified
+    Invalid,
+    /// The identity came from a peer we don't trust to assert it
+    Untrusted,
+    /// We can't verify identities yet
+    NotReady,
+    /// The user has to log in through hallway first
+    LoginRequired,
+}
+
+impl reject::Reject for IdentityError {}
+
+/// Something that can tell who is behind a request
+pub trait IdentitySource: Send + Sync {
+    fn identify(&self, request: &RequestInfo) -> Result<CurrentUserData, IdentityError>;
+
+    /// Email the request claims to be from, before any of it is verified. Only
+    /// good for turning away users who are rate limited already, for sources
+    /// where verifying takes a while
+    fn claimed(&self, _request: &RequestInfo) -> Option<String> {
+        None
+    }
+
+    /// Endpoints the source needs to be served, like the ones for logging in
+    fn routes(&self) -> BoxedFilter<(Response,)> {
+        warp::any()
+            .and_then(|| async { Err(reject::not_found()) })
+            .boxed()
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SourceKind {
+    #[default]
+    Pomerium,
+    PomeriumHeaders,
+    TrustedHeaders,
+    CloudflareAccess,
+    Oidc,
+    Local,
+    ClientCertificate,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct Config {
+    #[serde(default)]
+    pub source: SourceKind,
+
+    /// Peers that are allowed to tell us who the user is through headers
+    #[serde(default)]
+    pub trusted_proxies: Vec<IpNet>,
+
+    /// Whether whoever connects over the Unix socket is such a peer too
+    #[serde(default)]
+    pub trust_unix_socket: bool,
+
+    /// Where to sign out, for sources that can't discover it by themselves
+    #[serde(default)]
+    pub sign_out_url: String,
+
+    #[serde(default)]
+    pub headers: headers::HeaderNames,
+
+    #[serde(default)]
+    pub cloudflare: Option<cloudflare::CloudflareConfig>,
+
+    #[serde(default)]
+    pub oidc: Option<oidc::OidcConfig>,
+
+    #[serde(default)]
+    pub local: Option<local::LocalConfig>,
+
+    #[serde(default)]
+    pub client_certificate: certificate::CertificateConfig,
+
+    /// Alternate identities of the same person
+    #[serde(default)]
+    pub aliases: Vec<aliases::Alias>,
+}
+
+/// Whether the connection comes from one of the trusted networks, or over the
+/// Unix socket when its peers are trusted
+pub fn is_trusted(trusted: &[IpNet], trust_unix_socket: bool, request: &RequestInfo) -> bool {
+    let trusted = match request.remote {
+        Some(remote) => trusted.iter().any(|net| net.contains(&remote.ip().to_canonical())),
+        None => request.unix_socket && trust_unix_socket,
+    };
+
+    if !trusted {
+        match request.remote {
+            Some(remote) => warn!("Ignoring identity headers from untrusted peer {}", remote),
+            None => warn!("Ignoring identity headers from an untrusted Unix socket peer"),
+        }
+    }
+    trusted
+}
+
+/// Builds the configured identity source, starting whatever discovery it needs
+pub fn from_config(config: &crate::config::Config, readiness: &Readiness) -> Arc<dyn IdentitySource> {
+    let identity = &config.identity;
+    // For sources that just trust whoever is in front, nothing to discover
+    let nothing_to_discover = |default_sign_out: &str| {
+        readiness.set_ready(Discovered {
+            jwt_decoder: None,
+            global_data: Arc::new(GlobalData {
+                sign_out_url: match identity.sign_out_url.as_str() {
+                    "" => default_sign_out.to_string(),
+                    url => url.to_string(),
+                },
+            }),
+        });
+    };
+    let trusting_proxies = |name: &str, default_sign_out: &str| {
+        assert!(
+            !identity.trusted_proxies.is_empty() || identity.trust_unix_socket,
+            "The {} identity source needs some trusted_proxies",
+            name
+        );
+        nothing_to_discover(default_sign_out);
+        identity.trusted_proxies.clone()
+    };
+
+    let source: Arc<dyn IdentitySource> = match identity.source {
+        SourceKind::Pomerium => pomerium::source(config, readiness),
+        SourceKind::PomeriumHeaders => Arc::new(
+            PomeriumClaimHeaders::new(
+                trusting_proxies("pomerium_headers", pomerium::SIGN_OUT),
+                Arc::new(config.claims.clone()),
+            )
+            .with_unix_socket(identity.trust_unix_socket),
+        ),
+        SourceKind::CloudflareAccess => cloudflare::source(config, readiness),
+        SourceKind::Oidc => oidc::source(config, readiness),
+        SourceKind::Local => local::source(config, readiness),
+        // Proxies aren't needed when we check the certificates ourselves
+        SourceKind::ClientCertificate => Arc::new(
+            certificate::ClientCertificates::new(
+                match config.tls.as_ref().is_some_and(|t| t.client_ca.is_some()) {
+                    true => {
+                        nothing_to_discover("");
+                        identity.trusted_proxies.clone()
+                    }
+                    false => trusting_proxies("client_certificate", ""),
+                },
+                identity.client_certificate.clone(),
+            )
+            .with_unix_socket(identity.trust_unix_socket),
+        ),
+        SourceKind::TrustedHeaders => Arc::new(
+            TrustedHeaders::new(trusting_proxies("trusted_headers", ""), identity.headers.clone())
+                .with_unix_socket(identity.trust_unix_socket),
+        ),
+    };
+
+    if identity.aliases.is_empty() {
+        source
+    } else {
+        Arc::new(aliases::Aliased::new(source, &identity.aliases))
+    }
+}
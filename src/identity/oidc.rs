@@ -0,0 +1,425 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex, OnceLock},
+    time::Duration,
+};
+
+use aliri::Jwt;
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use openssl::sha::sha256;
+use serde::{Deserialize, Serialize};
+use tokio::time::Instant;
+use tracing::{debug, warn};
+use warp::{
+    filters::BoxedFilter,
+    http::{header, HeaderMap, HeaderValue, Uri},
+    reject,
+    reply::Response,
+    Filter, Rejection, Reply,
+};
+
+use super::{IdentityError, IdentitySource, RequestInfo};
+use crate::{
+    claims::ClaimMapping,
+    common::CurrentUserData,
+    config::Config,
+    consts,
+    jwt::JwtDecoder,
+    privacy,
+    readiness::{JwtEndpoints, NotReady, Readiness},
+    session::{self, SessionConfig, SessionKey, SESSION_COOKIE},
+    utils,
+};
+
+const FLOW_COOKIE: &str = "hallway_login";
+
+/// Browsers silently drop cookies bigger than about 4KB, sessions that would
+/// need more than this are kept in memory instead
+const MAX_SESSION_COOKIE: usize = 3800;
+
+mod defaults {
+    pub fn scopes() -> Vec<String> {
+        ["openid", "email", "profile"].map(String::from).to_vec()
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct OidcConfig {
+    /// The provider's discovery document is expected at `<issuer>/.well-known/openid-configuration`
+    pub issuer: String,
+    pub client_id: String,
+
+    /// Not needed for public clients, PKCE is used either way
+    #[serde(default)]
+    pub client_secret: Option<String>,
+
+    /// Where the provider sends users back, `<domain>/auth/callback` by default
+    #[serde(default)]
+    pub redirect_url: Option<String>,
+
+    #[serde(default = "defaults::scopes")]
+    pub scopes: Vec<String>,
+
+    #[serde(flatten)]
+    pub session: SessionConfig,
+}
+
+/// The parts of the provider's discovery document we care about
+#[derive(Debug, Clone, Deserialize)]
+struct ProviderMetadata {
+    issuer: String,
+    authorization_endpoint: String,
+    token_endpoint: String,
+    jwks_uri: String,
+    #[serde(default)]
+    end_session_endpoint: Option<String>,
+}
+
+/// What has to be remembered while the user is away at the provider
+#[derive(Serialize, Deserialize)]
+struct LoginFlow {
+    state: String,
+    nonce: String,
+    verifier: String,
+}
+
+#[derive(Deserialize)]
+struct Callback {
+    code: Option<String>,
+    state: Option<String>,
+    error: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    id_token: String,
+}
+
+/// What the session cookie holds. Users with lots of groups or claims only get
+/// a reference to the session kept in memory, which is lost on restart
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum SessionCookie {
+    Stored {
+        id: String,
+        subject: Option<String>,
+        email: String,
+        name: String,
+    },
+    Full(CurrentUserData),
+}
+
+struct StoredSession {
+    user: CurrentUserData,
+    expires: Instant,
+}
+
+struct Inner {
+    config: OidcConfig,
+    redirect_url: String,
+    domain: String,
+    key: SessionKey,
+    claim_mapping: Arc<ClaimMapping>,
+    provider: OnceLock<ProviderMetadata>,
+    readiness: Readiness,
+    client: reqwest::Client,
+    sessions: Mutex<HashMap<String, StoredSession>>,
+}
+
+/// Hallway logs users in by itself against an OpenID Connect provider, through
+/// the authorization code flow with PKCE. Identities are then kept in an
+/// encrypted cookie
+#[derive(Clone)]
+pub struct Oidc(Arc<Inner>);
+
+/// Builds the source and starts discovering the provider
+pub fn source(config: &Config, readiness: &Readiness) -> Arc<dyn IdentitySource> {
+    let oidc_config = config
+        .identity
+        .oidc
+        .clone()
+        .expect("The oidc identity source needs a [identity.oidc] section");
+
+    let key = oidc_config.session.key();
+    let redirect_url = oidc_config
+        .redirect_url
+        .clone()
+        .unwrap_or_else(|| format!("{}/auth/callback", config.domain.name.trim_end_matches('/')));
+
+    let oidc = Oidc(Arc::new(Inner {
+        config: oidc_config,
+        redirect_url,
+        domain: config.domain.name.clone(),
+        key,
+        claim_mapping: Arc::new(config.claims.clone()),
+        provider: OnceLock::new(),
+        readiness: readiness.clone(),
+        client: reqwest::Client::builder()
+            .timeout(Duration::from_secs(10))
+            .build()
+            .expect("Couldn't create HTTP client"),
+        sessions: Mutex::new(HashMap::new()),
+    }));
+    oidc.start_discovery();
+    Arc::new(oidc)
+}
+
+fn redirect(location: &str, cookies: &[String]) -> Result<Response, Rejection> {
+    let uri = Uri::try_from(location).map_err(|e| {
+        warn!("Can't redirect to '{}': {}", location, e);
+        reject::custom(IdentityError::Invalid)
+    })?;
+    let mut response = warp::redirect::found(uri).into_response();
+    for cookie in cookies {
+        response.headers_mut().append(
+            header::SET_COOKIE,
+            HeaderValue::from_str(cookie).expect("Cookies are always valid headers"),
+        );
+    }
+    Ok(response)
+}
+
+impl Oidc {
+    fn start_discovery(&self) {
+        let discovery_url = format!(
+            "{}/.well-known/openid-configuration",
+            self.0.config.issuer.trim_end_matches('/')
+        );
+        let this = self.clone();
+        let endpoints = async move {
+            let metadata: ProviderMetadata = utils::get_json(&discovery_url).await;
+            let endpoints = JwtEndpoints {
+                jwks_uri: metadata.jwks_uri.clone(),
+                sign_out_url: "/auth/logout".to_string(),
+            };
+            let _ = this.0.provider.set(metadata);
+            endpoints
+        };
+
+        let this = self.clone();
+        self.0.readiness.start_discovery(endpoints, move |keys| {
+            let provider = this.0.provider.get().expect("Provider is known before its keys");
+            JwtDecoder::oidc(
+                &provider.issuer,
+                &this.0.config.client_id,
+                keys,
+                this.0.claim_mapping.clone(),
+            )
+        });
+    }
+
+    /// Sends the user to the provider, remembering how to check their return
+    fn login(&self, secure: bool) -> Result<Response, Rejection> {
+        let provider = self.0.provider.get().ok_or_else(|| reject::custom(NotReady))?;
+        let flow = LoginFlow {
+            state: session::random_token(16),
+            nonce: session::random_token(16),
+            verifier: session::random_token(32),
+        };
+        let challenge = URL_SAFE_NO_PAD.encode(sha256(flow.verifier.as_bytes()));
+
+        let url = reqwest::Url::parse_with_params(
+            &provider.authorization_endpoint,
+            &[
+                ("response_type", "code"),
+                ("client_id", &self.0.config.client_id),
+                ("redirect_uri", &self.0.redirect_url),
+                ("scope", &self.0.config.scopes.join(" ")),
+                ("state", &flow.state),
+                ("nonce", &flow.nonce),
+                ("code_challenge", &challenge),
+                ("code_challenge_method", "S256"),
+            ],
+        )
+        .map_err(|e| {
+            warn!("Provider's authorization endpoint is not valid: {}", e);
+            reject::custom(IdentityError::Invalid)
+        })?;
+
+        let ttl = Duration::from_secs(consts::defaults::LOGIN_FLOW_LIFETIME);
+        let flow = self.0.key.seal(FLOW_COOKIE, &flow, ttl);
+        redirect(url.as_str(), &[session::set_cookie(FLOW_COOKIE, &flow, ttl, secure)])
+    }
+
+    /// The user is back from the provider, redeem the code for their identity
+    async fn callback(&self, callback: Callback, headers: HeaderMap, secure: bool) -> Result<Response, Rejection> {
+        let invalid = || reject::custom(IdentityError::Invalid);
+        if let Some(error) = callback.error {
+            warn!("Provider didn't log the user in: {}", error);
+            return Err(invalid());
+        }
+
+        let provider = self.0.provider.get().ok_or_else(|| reject::custom(NotReady))?;
+        let discovered = self.0.readiness.get().ok_or_else(|| reject::custom(NotReady))?;
+        let decoder = discovered
+            .jwt_decoder
+            .as_ref()
+            .ok_or_else(|| reject::custom(NotReady))?;
+
+        let flow: LoginFlow = session::get_cookie(&headers, FLOW_COOKIE)
+            .and_then(|c| self.0.key.open(FLOW_COOKIE, c))
+            .ok_or_else(|| {
+                debug!("Login callback without an ongoing login");
+                invalid()
+            })?;
+        if callback.state.as_deref() != Some(flow.state.as_str()) {
+            warn!("Login callback with the wrong state");
+            return Err(invalid());
+        }
+        let code = callback.code.ok_or_else(invalid)?;
+
+        let mut form = vec![
+            ("grant_type", "authorization_code"),
+            ("code", &code),
+            ("redirect_uri", &self.0.redirect_url),
+            ("client_id", &self.0.config.client_id),
+            ("code_verifier", &flow.verifier),
+        ];
+        if let Some(secret) = &self.0.config.client_secret {
+            form.push(("client_secret", secret));
+        }
+        let tokens: TokenResponse = async {
+            self.0
+                .client
+                .post(&provider.token_endpoint)
+                .form(&form)
+                .send()
+                .await?
+                .error_for_status()?
+                .json()
+                .await
+        }
+        .await
+        .map_err(|e: reqwest::Error| {
+            warn!("Couldn't redeem login code: {}", e);
+            invalid()
+        })?;
+
+        let claims = decoder
+            .verify_claims(&Jwt::from(tokens.id_token))
+            .ok_or_else(invalid)?;
+        if claims.get("nonce").and_then(|n| n.as_str()) != Some(flow.nonce.as_str()) {
+            warn!("ID token was not issued for this login");
+            return Err(invalid());
+        }
+        let user = self.0.claim_mapping.map(&claims).ok_or_else(|| {
+            warn!("ID token has no email");
+            invalid()
+        })?;
+
+        let lifetime = self.0.config.session.lifetime();
+        let user_session = self.seal_session(user, lifetime);
+        redirect(
+            "/",
+            &[
+                session::set_cookie(SESSION_COOKIE, &user_session, lifetime, secure),
+                session::clear_cookie(FLOW_COOKIE, secure),
+            ],
+        )
+    }
+
+    /// Seals the user into the session cookie, or only a reference to them
+    /// when that would be too big for browsers to keep
+    fn seal_session(&self, user: CurrentUserData, lifetime: Duration) -> String {
+        let full = self.0.key.seal(SESSION_COOKIE, &SessionCookie::Full(user.clone()), lifetime);
+        if full.len() <= MAX_SESSION_COOKIE {
+            return full;
+        }
+        debug!(
+            "Session of '{}' is {} bytes, keeping it in memory",
+            privacy::email(&user.email),
+            full.len()
+        );
+
+        let id = session::random_token(16);
+        let stored = SessionCookie::Stored {
+            id: id.clone(),
+            subject: user.subject.clone(),
+            email: user.email.clone(),
+            name: user.name.clone(),
+        };
+        let mut sessions = self.0.sessions.lock().unwrap();
+        sessions.retain(|_, s| s.expires > Instant::now());
+        sessions.insert(
+            id,
+            StoredSession {
+                user,
+                expires: Instant::now() + lifetime,
+            },
+        );
+        self.0.key.seal(SESSION_COOKIE, &stored, lifetime)
+    }
+
+    /// Forgets the session, and tells the provider to do the same if it can
+    fn logout(&self, headers: &HeaderMap, secure: bool) -> Result<Response, Rejection> {
+        if let Some(SessionCookie::Stored { id, .. }) = session::get_cookie(headers, SESSION_COOKIE)
+            .and_then(|c| self.0.key.open(SESSION_COOKIE, c))
+        {
+            self.0.sessions.lock().unwrap().remove(&id);
+        }
+        let location = self
+            .0
+            .provider
+            .get()
+            .and_then(|p| p.end_session_endpoint.as_ref())
+            .and_then(|e| {
+                reqwest::Url::parse_with_params(e, &[("client_id", &self.0.config.client_id)]).ok()
+            })
+            .map(String::from)
+            .unwrap_or_else(|| "/".to_string());
+        redirect(&location, &[session::clear_cookie(SESSION_COOKIE, secure)])
+    }
+}
+
+impl IdentitySource for Oidc {
+    fn identify(&self, request: &RequestInfo) -> Result<CurrentUserData, IdentityError> {
+        let cookie = session::get_cookie(&request.headers, SESSION_COOKIE)
+            .and_then(|c| self.0.key.open(SESSION_COOKIE, c))
+            .ok_or(IdentityError::LoginRequired)?;
+        match cookie {
+            SessionCookie::Full(user) => Ok(user),
+            SessionCookie::Stored { id, email, .. } => self
+                .0
+                .sessions
+                .lock()
+                .unwrap()
+                .get(&id)
+                .filter(|s| s.expires > Instant::now() && s.user.email == email)
+                .map(|s| s.user.clone())
+                .ok_or(IdentityError::LoginRequired),
+        }
+    }
+
+    fn routes(&self) -> BoxedFilter<(Response,)> {
+        let secure = self.0.config.session.secure_cookies(&self.0.domain);
+        let login = {
+            let this = self.clone();
+            warp::path!("auth" / "login")
+                .and(warp::get())
+                .and(secure.clone())
+                .and_then(move |secure| std::future::ready(this.login(secure)))
+        };
+        let callback = {
+            let this = self.clone();
+            warp::path!("auth" / "callback")
+                .and(warp::get())
+                .and(warp::query::<Callback>())
+                .and(warp::header::headers_cloned())
+                .and(secure.clone())
+                .and_then(move |callback, headers, secure| {
+                    let this = this.clone();
+                    async move { this.callback(callback, headers, secure).await }
+                })
+        };
+        let logout = {
+            let this = self.clone();
+            warp::path!("auth" / "logout")
+                .and(warp::get())
+                .and(warp::header::headers_cloned())
+                .and(secure)
+                .and_then(move |headers: HeaderMap, secure| std::future::ready(this.logout(&headers, secure)))
+        };
+
+        login.or(callback).unify().or(logout).unify().boxed()
+    }
+}
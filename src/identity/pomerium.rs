@@ -0,0 +1,93 @@
+use std::sync::Arc;
+
+#[cfg(feature = "container")]
+use serde::Deserialize;
+
+use super::IdentitySource;
+use crate::{config::Config, readiness::Readiness};
+
+#[cfg(feature = "container")]
+use {
+    super::JwtAssertion,
+    crate::{jwt::JwtDecoder, readiness::JwtEndpoints, utils::get_json},
+};
+
+#[cfg(not(feature = "container"))]
+use {
+    super::{IdentityError, RequestInfo},
+    tracing::debug,
+    crate::{common::CurrentUserData, consts, readiness::Discovered, rendering::GlobalData},
+};
+
+#[cfg(feature = "container")]
+pub const HEADER: &str = "X-Pomerium-Jwt-Assertion";
+
+/// Pomerium answers this path on every route it proxies
+pub const SIGN_OUT: &str = "/.pomerium/sign_out";
+
+#[cfg(feature = "container")]
+#[derive(Clone, Debug, Deserialize)]
+pub struct KnownRoutes {
+    pub frontchannel_logout_uri: String,
+    pub jwks_uri: String,
+}
+
+#[cfg(feature = "container")]
+pub async fn obtain_known(domain: &str) -> KnownRoutes {
+    get_json(&format!("{}/.well-known/pomerium", domain)).await
+}
+
+/// Identities asserted by pomerium, routes and keys are discovered from pomerium itself
+#[cfg(feature = "container")]
+pub fn source(config: &Config, readiness: &Readiness) -> Arc<dyn IdentitySource> {
+    let domain_name = config.domain.name.clone();
+    let claim_mapping = Arc::new(config.claims.clone());
+
+    readiness.start_discovery(
+        {
+            let domain_name = domain_name.clone();
+            async move {
+                let known_routes = obtain_known(&domain_name).await;
+                JwtEndpoints {
+                    jwks_uri: known_routes.jwks_uri,
+                    sign_out_url: known_routes.frontchannel_logout_uri,
+                }
+            }
+        },
+        move |keys| JwtDecoder::pomerium(&domain_name, keys, claim_mapping),
+    );
+    Arc::new(JwtAssertion::new(HEADER, readiness.clone()))
+}
+
+#[cfg(not(feature = "container"))]
+pub fn source(config: &Config, readiness: &Readiness) -> Arc<dyn IdentitySource> {
+    debug!("Testing mode, not asking pomerium at {}", config.domain.name);
+    readiness.set_ready(Discovered {
+        jwt_decoder: None,
+        global_data: Arc::new(GlobalData {
+            sign_out_url: "/test/logout".to_string(),
+        }),
+    });
+    Arc::new(DebugUser)
+}
+
+/// Stands in for pomerium on testing mode
+#[cfg(not(feature = "container"))]
+struct DebugUser;
+
+#[cfg(not(feature = "container"))]
+impl IdentitySource for DebugUser {
+    // Unfortunately, we can't just use debug here for testing since it is somehow dropping
+    // the context in my build
+    fn identify(&self, _: &RequestInfo) -> Result<CurrentUserData, IdentityError> {
+        Ok(CurrentUserData {
+            subject: None,
+            email: consts::defaults::debug::EMAIL.to_string(),
+            name: consts::defaults::debug::NAME.to_string(),
+            picture: None,
+            groups: Vec::new(),
+            claims: Default::default(),
+            client_certificate: None,
+        })
+    }
+}
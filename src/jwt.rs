@@ -1,15 +1,25 @@
-use std::{str::FromStr, time::Duration};
+use std::{
+    collections::HashMap,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, RwLock,
+    },
+    time::{Duration, SystemTime},
+};
 
-#[cfg(feature = "container")]
-use crate::utils;
+use crate::{claims::ClaimMapping, common::CurrentUserData, consts, metrics::metrics, utils};
 
 use aliri::{
+    error::{ClaimsRejected, JwtVerifyError},
     jwa,
     jwt::{self, CoreClaims, CoreHeaders, HasAlgorithm},
     Jwks, Jwt,
 };
 use aliri_clock::UnixTime;
-use tracing::{instrument, trace};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use openssl::sha::sha256;
+use tracing::{debug, info, instrument, trace};
 
 #[derive(serde::Deserialize, Clone, Debug, PartialEq, Eq)]
 #[must_use]
@@ -24,8 +34,8 @@ pub struct Oauth2Claims {
     exp: Option<serde_json::Number>,
     #[serde(default, skip_serializing_if = "Option::is_none")]
     nbf: Option<serde_json::Number>,
-    email: String,
-    name: String,
+    #[serde(flatten)]
+    other: serde_json::Map<String, serde_json::Value>,
 }
 
 fn extract_num(n: serde_json::Number) -> Option<u64> {
@@ -59,67 +69,294 @@ impl CoreClaims for Oauth2Claims {
     }
 }
 
-#[derive(Debug)]
-// On testing mode validator and keys are never used, that's alright
+/// How many seconds past `exp` tokens are still accepted
+const LEEWAY: u64 = 60;
+
+/// Cloudflare Access, for services exposed through Cloudflare Tunnel
+pub mod cloudflare {
+    pub const HEADER: &str = "Cf-Access-Jwt-Assertion";
+    pub const LOGOUT: &str = "/cdn-cgi/access/logout";
+
+    /// Teams can be given either as `myteam` or as `myteam.cloudflareaccess.com`
+    pub fn team_url(team_domain: &str) -> String {
+        let team_domain = team_domain.trim_start_matches("https://").trim_end_matches('/');
+        if team_domain.contains('.') {
+            format!("https://{}", team_domain)
+        } else {
+            format!("https://{}.cloudflareaccess.com", team_domain)
+        }
+    }
+
+    pub fn certs_url(team_domain: &str) -> String {
+        format!("{}/cdn-cgi/access/certs", team_url(team_domain))
+    }
+}
+
+/// Where the interesting claims are inside the token
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum ClaimLayout {
+    /// Everything at the top level, like pomerium does
+    Flat,
+    /// Top level claims plus whatever the IdP sent, nested under `custom`
+    CloudflareAccess,
+}
+
+struct CacheEntry {
+    user: CurrentUserData,
+    valid_until: u64,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub size: usize,
+}
+
+/// Identities that have already been verified, keyed by the token's hash so
+/// that we don't keep raw tokens around
+pub struct VerifiedCache {
+    entries: Mutex<HashMap<[u8; 32], CacheEntry>>,
+    max_entries: usize,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl VerifiedCache {
+    pub fn new(max_entries: usize) -> Self {
+        Self {
+            entries: Mutex::new(HashMap::new()),
+            max_entries,
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    pub fn get(&self, token: &str, now: u64) -> Option<CurrentUserData> {
+        let found = self
+            .entries
+            .lock()
+            .unwrap()
+            .get(&sha256(token.as_bytes()))
+            .filter(|e| now < e.valid_until)
+            .map(|e| e.user.clone());
+
+        let counter = if found.is_some() { &self.hits } else { &self.misses };
+        counter.fetch_add(1, Ordering::Relaxed);
+        found
+    }
+
+    pub fn insert(&self, token: &str, user: CurrentUserData, valid_until: u64, now: u64) {
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len() >= self.max_entries {
+            entries.retain(|_, e| now < e.valid_until);
+        }
+        if entries.len() >= self.max_entries {
+            // Still full of live tokens, make room by dropping the one that expires first
+            let soonest = entries
+                .iter()
+                .min_by_key(|(_, e)| e.valid_until)
+                .map(|(k, _)| *k);
+            if let Some(soonest) = soonest {
+                entries.remove(&soonest);
+            }
+        }
+        entries.insert(sha256(token.as_bytes()), CacheEntry { user, valid_until });
+    }
+
+    pub fn clear(&self) {
+        self.entries.lock().unwrap().clear();
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            size: self.entries.lock().unwrap().len(),
+        }
+    }
+}
+
 pub struct JwtDecoder {
-    #[allow(dead_code)]
     validator: jwt::CoreValidator,
+    layout: ClaimLayout,
+    keys: RwLock<Jwks>,
 
-    #[allow(dead_code)]
-    keys: Jwks,
+    cache: VerifiedCache,
+    claim_mapping: Arc<ClaimMapping>,
 }
 
 
 impl JwtDecoder {
-    pub fn new(domain_name: &str, jwks_route: &str) -> Self {
-        let keys = Self::get_jwks(jwks_route);
+    fn new(
+        validator: jwt::CoreValidator,
+        layout: ClaimLayout,
+        keys: Jwks,
+        claim_mapping: Arc<ClaimMapping>,
+    ) -> Self {
+        Self {
+            validator: validator
+                .check_expiration()
+                .with_leeway(Duration::from_secs(LEEWAY)),
+            layout,
+            keys: RwLock::new(keys),
+            cache: VerifiedCache::new(consts::defaults::JWT_CACHE_SIZE),
+            claim_mapping,
+        }
+    }
 
+    // On testing mode pomerium's tokens are never decoded, that's alright
+    #[cfg_attr(not(feature = "container"), allow(dead_code))]
+    pub fn pomerium(domain_name: &str, keys: Jwks, claim_mapping: Arc<ClaimMapping>) -> Self {
         let validator = jwt::CoreValidator::default()
-            .ignore_expiration()
             .add_approved_algorithm(jwa::Algorithm::ES256)
             .add_allowed_audience(jwt::Audience::from_str(domain_name).expect("Malformed domain name"))
-            .require_issuer(jwt::Issuer::from_str(domain_name).expect("Malformed domain name"))
-            .check_expiration()
-            .with_leeway(Duration::from_secs(60));
+            .require_issuer(jwt::Issuer::from_str(domain_name).expect("Malformed domain name"));
 
-     
-     
-        Self { validator, keys }
+        Self::new(validator, ClaimLayout::Flat, keys, claim_mapping)
     }
-    #[instrument]
-    pub fn decode(&self, jwt: Jwt) -> Option<crate::common::CurrentUserData> {
+
+    /// ID tokens from an OpenID Connect provider, issued for our client
+    pub fn oidc(issuer: &str, client_id: &str, keys: Jwks, claim_mapping: Arc<ClaimMapping>) -> Self {
+        let validator = jwt::CoreValidator::default()
+            .add_approved_algorithm(jwa::Algorithm::RS256)
+            .add_approved_algorithm(jwa::Algorithm::ES256)
+            .add_allowed_audience(jwt::Audience::from(client_id.to_string()))
+            .require_issuer(jwt::Issuer::from(issuer.to_string()));
+
+        Self::new(validator, ClaimLayout::Flat, keys, claim_mapping)
+    }
+
+    /// Tokens are signed by the team's keys and carry the application's audience tag
+    pub fn cloudflare_access(
+        team_domain: &str,
+        audiences: &[String],
+        keys: Jwks,
+        claim_mapping: Arc<ClaimMapping>,
+    ) -> Self {
+        let validator = audiences.iter().fold(
+            jwt::CoreValidator::default()
+                .add_approved_algorithm(jwa::Algorithm::RS256)
+                .require_issuer(jwt::Issuer::from(cloudflare::team_url(team_domain))),
+            |v, aud| v.add_allowed_audience(jwt::Audience::from(aud.clone())),
+        );
+
+        Self::new(validator, ClaimLayout::CloudflareAccess, keys, claim_mapping)
+    }
+
+    #[instrument(name = "jwt_decode", skip_all)]
+    pub fn decode(&self, jwt: Jwt) -> Option<CurrentUserData> {
+        let now = UnixTime::from(SystemTime::now()).0;
+        if let Some(user) = self.cache.get(jwt.as_str(), now) {
+            trace!("Cache hit");
+            metrics().jwt_verifications.with_label_values(&["cached"]).inc();
+            return Some(user);
+        }
+
+        let data = self.verify(&jwt)?;
+        let claims: &Oauth2Claims = data.claims();
+
+        let user = self.claim_mapping.map(&Self::flatten(self.layout, claims))?;
+
+        // Tokens without expiration are verified every time
+        if let Some(exp) = claims.exp() {
+            self.cache.insert(jwt.as_str(), user.clone(), exp.0.saturating_sub(LEEWAY), now);
+        }
+
+        Some(user)
+    }
+
+    fn verify(&self, jwt: &Jwt) -> Option<jwt::Validated<Oauth2Claims>> {
+        let verified = self.try_verify(jwt);
+        let result = match &verified {
+            Ok(_) => "valid",
+            Err(reason) => reason,
+        };
+        metrics().jwt_verifications.with_label_values(&[result]).inc();
+        verified.ok()
+    }
+
+    /// The reason for rejecting the token is what goes in the metrics
+    fn try_verify(&self, jwt: &Jwt) -> Result<jwt::Validated<Oauth2Claims>, &'static str> {
         trace!("Decomposing");
-        let decomposed: jwt::Decomposed = jwt.decompose().ok()?;
+        let decomposed: jwt::Decomposed = jwt.decompose().map_err(|_| "malformed")?;
 
         trace!("Getting key ref");
-        let key_ref = self
-            .keys
-            .get_key_by_id(decomposed.kid()?, decomposed.alg())?;
+        let keys = self.keys.read().unwrap();
+        let key_ref = decomposed
+            .kid()
+            .and_then(|kid| keys.get_key_by_id(kid, decomposed.alg()))
+            .ok_or("unknown_key")?;
 
         trace!("Verifying");
-        let data: jwt::Validated<Oauth2Claims> = jwt
-            .verify(key_ref, &self.validator)
-            .expect("JWT was invalid");
-
-        let claims: &Oauth2Claims = data.claims();
+        let data = jwt.verify(key_ref, &self.validator).map_err(|e| {
+            debug!("JWT was invalid: {}", e);
+            match e {
+                JwtVerifyError::JwkVerifyError(_) => "bad_signature",
+                JwtVerifyError::ClaimsRejected(ClaimsRejected::TokenExpired) => "expired",
+                JwtVerifyError::ClaimsRejected(ClaimsRejected::TokenNotYetValid) => "not_yet_valid",
+                JwtVerifyError::ClaimsRejected(ClaimsRejected::InvalidAudience) => "wrong_audience",
+                JwtVerifyError::ClaimsRejected(ClaimsRejected::InvalidIssuer) => "wrong_issuer",
+                JwtVerifyError::ClaimsRejected(_) => "rejected_claims",
+                _ => "malformed",
+            }
+        })?;
 
         trace!("Done!");
+        Ok(data)
+    }
+
+    /// Email in the token, without checking anything about it. Anyone can make
+    /// such a token, so it's only good for telling users apart
+    pub fn claimed_email(&self, jwt: &str) -> Option<String> {
+        let payload = URL_SAFE_NO_PAD.decode(jwt.split('.').nth(1)?).ok()?;
+        let claims: Oauth2Claims = serde_json::from_slice(&payload).ok()?;
+        self.claim_mapping.map(&Self::flatten(self.layout, &claims)).map(|user| user.email)
+    }
+
+    /// Verifies a token that is only seen once, returning its claims as they are
+    pub fn verify_claims(&self, jwt: &Jwt) -> Option<serde_json::Map<String, serde_json::Value>> {
+        let data = self.verify(jwt)?;
+        Some(Self::flatten(self.layout, data.claims()))
+    }
+
+    /// Replaces the keys if they changed, identities verified with the old ones
+    /// are forgotten
+    pub fn update_keys(&self, keys: Jwks) {
+        let mut current = self.keys.write().unwrap();
+        if *current != keys {
+            info!("Signing keys have rotated");
+            *current = keys;
+            self.cache.clear();
+        }
+    }
+
+    pub fn cache_stats(&self) -> CacheStats {
+        self.cache.stats()
+    }
 
-        Some(crate::common::CurrentUserData {
-            email: claims.email.clone(),
-            name: claims.name.clone(),
-            picture: None // Not yet supported
-        })
+    pub fn key_count(&self) -> usize {
+        self.keys.read().unwrap().keys().len()
     }
 
-    #[cfg(feature = "container")]
-    fn get_jwks(jwks_route: &str) -> Jwks {
-        utils::get_json(jwks_route)
+    /// All the claims in a single map, as the claim mapping expects them
+    fn flatten(layout: ClaimLayout, claims: &Oauth2Claims) -> serde_json::Map<String, serde_json::Value> {
+        let mut flat = match (layout, claims.other.get("custom")) {
+            (ClaimLayout::CloudflareAccess, Some(serde_json::Value::Object(custom))) => {
+                // Top level claims are Cloudflare's own, those win
+                let mut merged = custom.clone();
+                merged.extend(claims.other.iter().map(|(k, v)| (k.clone(), v.clone())));
+                merged
+            }
+            _ => claims.other.clone(),
+        };
+        if let Some(sub) = &claims.sub {
+            flat.insert("sub".to_string(), sub.as_str().into());
+        }
+        flat
     }
 
-    // Dummy version for testing
-    #[cfg(not(feature = "container"))]
-    fn get_jwks(_: &str) -> Jwks {
-        Jwks::default()
+    pub async fn get_jwks(jwks_route: &str) -> Jwks {
+        utils::get_json(jwks_route).await
     }
 }
@@ -0,0 +1,124 @@
+use std::{
+    fs::Permissions,
+    io,
+    net::{IpAddr, SocketAddr},
+    os::{
+        fd::{FromRawFd, RawFd},
+        unix::fs::PermissionsExt,
+    },
+    path::Path,
+};
+
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::net::{TcpListener, UnixListener};
+use tracing::info;
+
+use crate::{consts, server::Listener};
+
+/// Descriptors systemd passes start right after stdin, stdout and stderr
+const SD_LISTEN_FDS_START: RawFd = 3;
+
+/// Addresses from a comma separated list. Entries can be plain IPs, which get
+/// `port`, or IPs with their own port like `127.0.0.1:8080` or `[::1]:8080`
+pub fn addresses(list: &str, port: u16) -> Vec<SocketAddr> {
+    list.split(',')
+        .map(str::trim)
+        .filter(|a| !a.is_empty())
+        .map(|a| {
+            a.parse::<SocketAddr>()
+                .or_else(|_| a.trim_start_matches('[').trim_end_matches(']').parse::<IpAddr>().map(|ip| SocketAddr::new(ip, port)))
+                .unwrap_or_else(|_| panic!("Not a valid address: {}", a))
+        })
+        .collect()
+}
+
+/// `[::]` accepts IPv4 connections as well, unless `0.0.0.0` was asked for on
+/// the same port, in which case each one keeps to its own family
+pub fn bind_tcp(addr: SocketAddr, all: &[SocketAddr]) -> io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    if addr.is_ipv6() {
+        let dual_stack = addr.ip().is_unspecified()
+            && !all
+                .iter()
+                .any(|a| a.is_ipv4() && a.ip().is_unspecified() && a.port() == addr.port());
+        socket.set_only_v6(!dual_stack)?;
+    }
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?;
+    TcpListener::from_std(socket.into())
+}
+
+/// Listens on a Unix socket at `path`, replacing whatever stale socket a
+/// previous run left there
+pub fn bind_unix(path: &Path, mode: u32) -> io::Result<UnixListener> {
+    match std::fs::remove_file(path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+        _ => {}
+    }
+    let listener = UnixListener::bind(path)?;
+    std::fs::set_permissions(path, Permissions::from_mode(mode))?;
+    Ok(listener)
+}
+
+/// Takes over `count` listening sockets starting at `first`, TCP or Unix
+pub fn inherited(first: RawFd, count: usize) -> io::Result<Vec<Listener>> {
+    (first..first + count as RawFd)
+        .map(|fd| {
+            // SAFETY: the descriptors were handed to us to own, and nothing
+            // else in the process uses them
+            let socket = unsafe { Socket::from_raw_fd(fd) };
+            socket.set_nonblocking(true)?;
+            Ok(match socket.local_addr()?.as_socket() {
+                Some(_) => Listener::Tcp(TcpListener::from_std(socket.into())?),
+                None => Listener::Unix(UnixListener::from_std(socket.into())?),
+            })
+        })
+        .collect()
+}
+
+/// Sockets passed by systemd's socket activation, if they are meant for us
+fn systemd() -> Option<Vec<Listener>> {
+    let pid = std::env::var("LISTEN_PID").ok()?.parse::<u32>().ok()?;
+    let count = std::env::var("LISTEN_FDS").ok()?.parse::<usize>().ok()?;
+    // So that children don't think they are theirs
+    std::env::remove_var("LISTEN_PID");
+    std::env::remove_var("LISTEN_FDS");
+    std::env::remove_var("LISTEN_FDNAMES");
+    if pid != std::process::id() {
+        return None;
+    }
+
+    info!("Using {} sockets from systemd", count);
+    Some(inherited(SD_LISTEN_FDS_START, count).expect("Couldn't use the sockets passed by systemd"))
+}
+
+/// Every listener asked for through the environment: systemd's sockets when
+/// activated by it, otherwise `HTTP_ADDRESS` and `HTTP_SOCKET`
+pub fn from_env(addresses: &[SocketAddr]) -> Vec<Listener> {
+    if let Some(listeners) = systemd() {
+        return listeners;
+    }
+
+    let mut listeners: Vec<Listener> = addresses
+        .iter()
+        .map(|addr| {
+            info!("Listening on {}", addr);
+            bind_tcp(*addr, addresses)
+                .map(Listener::Tcp)
+                .unwrap_or_else(|e| panic!("Couldn't bind to {}: {}", addr, e))
+        })
+        .collect();
+
+    if let Ok(path) = std::env::var("HTTP_SOCKET") {
+        let mode = std::env::var("HTTP_SOCKET_MODE")
+            .ok()
+            .map(|m| u32::from_str_radix(&m, 8).expect("HTTP_SOCKET_MODE is not an octal mode"))
+            .unwrap_or(consts::defaults::SOCKET_MODE);
+        info!("Listening on {}", path);
+        let listener = bind_unix(Path::new(&path), mode).unwrap_or_else(|e| panic!("Couldn't bind to {}: {}", path, e));
+        listeners.push(Listener::Unix(listener));
+    }
+    listeners
+}
@@ -1,25 +1,58 @@
-use std::{convert::Infallible, net::Ipv4Addr, path::Path, sync::Arc};
+use std::{convert::Infallible, path::Path, sync::Arc};
 
 use handlebars::Handlebars;
+use tokio::task::JoinSet;
 use tracing::trace;
-use warp::{hyper::Uri, Filter, Rejection};
-
+use tracing_subscriber::{filter::LevelFilter, layer::SubscriberExt, Layer};
+use warp::{hyper::Uri, Filter, Rejection, Reply};
+
+mod access_log;
+mod audit;
+mod avatar;
+mod claims;
+mod compression;
 mod consts;
+mod groups;
+mod health;
+mod identity;
 mod jwt;
+mod listen;
+mod metrics;
 mod pomerium;
+mod privacy;
+mod rate_limit;
+mod readiness;
 mod rendering;
-
-#[cfg(feature = "container")]
+mod security;
+mod server;
+mod session;
+mod telemetry;
+mod tls;
 mod utils;
 
 #[cfg(test)]
+#[allow(clippy::bool_assert_comparison)]
 mod tests;
 
 mod common {
+    use std::collections::BTreeMap;
+
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Clone, Serialize, Deserialize)]
     pub struct CurrentUserData {
+        /// Stable id given by the identity provider, when there's one
+        #[serde(default)]
+        pub subject: Option<String>,
         pub email: String,
         pub name: String,
         pub picture: Option<String>,
+        pub groups: Vec<String>,
+        /// Extra claims mapped in the config, available to the templates
+        pub claims: BTreeMap<String, serde_json::Value>,
+        /// Only there when the user identified themselves with a certificate
+        #[serde(default)]
+        pub client_certificate: Option<crate::identity::ClientCertificate>,
     }
 }
 
@@ -48,6 +81,14 @@ mod config {
 
         #[serde(skip_deserializing)]
         pub is_group: bool,
+
+        /// Where the tile links to, through `/launch` when launches are audited
+        #[serde(skip_deserializing)]
+        pub href: String,
+
+        /// Replaces the global canonicalization for this route's policies
+        #[serde(default, skip_serializing)]
+        pub canonicalization: Option<crate::pomerium::Canonicalization>,
     }
 
     #[derive(Debug, Deserialize, Clone, Serialize)]
@@ -66,17 +107,74 @@ mod config {
     pub struct Config {
         pub domain: Domain,
         pub routes: Vec<Route>,
+
+        #[serde(default)]
+        pub claims: crate::claims::ClaimMapping,
+
+        #[serde(default)]
+        pub identity: crate::identity::Config,
+
+        /// How emails are compared against policies
+        #[serde(default)]
+        pub canonicalization: crate::pomerium::Canonicalization,
+
+        /// Whether pomerium's `user` criterion matches the user id or the email
+        #[serde(default)]
+        pub user_criterion: crate::pomerium::UserCriterion,
+
+        /// Serve HTTPS ourselves, for when there's no proxy in front
+        #[serde(default)]
+        pub tls: Option<crate::tls::TlsConfig>,
+
+        /// Content security policy and the other security headers
+        #[serde(default)]
+        pub security: crate::security::SecurityConfig,
+
+        /// Who opened the launcher and what they launched, only kept when the
+        /// section is there
+        #[serde(default)]
+        pub audit: Option<crate::audit::AuditConfig>,
+
+        /// How users show up in logs and traces
+        #[serde(default)]
+        pub privacy: crate::privacy::PrivacyConfig,
+
+        /// Log every request on stdout, only when the section is there
+        #[serde(default)]
+        pub access_log: Option<crate::access_log::AccessLogConfig>,
+
+        /// Requests allowed per client address and per user, only limited when
+        /// the section is there
+        #[serde(default)]
+        pub rate_limit: Option<crate::rate_limit::RateLimitConfig>,
+
+        /// Prometheus metrics, only served when the section is there
+        #[serde(default)]
+        pub metrics: Option<crate::metrics::MetricsConfig>,
+
+        /// Where to find the groups the identity source doesn't tell us about
+        #[serde(default)]
+        pub groups: crate::groups::Config,
     }
 
     pub fn load<P: AsRef<Path>>(path: P) -> Config {
-        fn fill_in_internals(routes: &mut [Route]) {
+        fn fill_in_internals(routes: &mut [Route], audited: bool) {
             routes.iter_mut().for_each(|route| {
                 route.escaped_label = route.label.replace([' ', '.'], "_");
                 match &mut route.data {
-                    RouteData::Path(_) => route.is_group = false,
+                    RouteData::Path(path) => {
+                        route.is_group = false;
+                        route.href = match audited {
+                            true => format!(
+                                "launch/{}",
+                                percent_encoding::utf8_percent_encode(&route.escaped_label, percent_encoding::NON_ALPHANUMERIC)
+                            ),
+                            false => path.clone(),
+                        };
+                    }
                     RouteData::Group(group) => {
                         route.is_group = true;
-                        fill_in_internals(group);
+                        fill_in_internals(group, audited);
                     }
                 }
             })
@@ -86,34 +184,24 @@ mod config {
             toml::from_str(&std::fs::read_to_string(path.as_ref()).expect("Config can't be read"))
                 .expect("Config can't be parsed");
         // Fill escaped names
-        fill_in_internals(&mut conf.routes);
+        let audited = conf.audit.is_some();
+        fill_in_internals(&mut conf.routes, audited);
         conf
     }
 }
 
 mod filters {
-    use std::sync::Arc;
-
-    #[cfg(feature = "container")]
-    use aliri::Jwt;
-    #[cfg(feature = "container")]
-    use tracing::trace;
-    #[cfg(feature = "container")]
-    use warp::reject;
-    use warp::{http::HeaderValue, hyper::header, Filter, Rejection};
-
-    #[cfg(not(feature = "container"))]
-    use crate::consts;
+    use std::{convert::Infallible, sync::Arc};
 
-    #[cfg(feature = "container")]
-    use crate::jwt::JwtDecoder;
+    use warp::{http::HeaderValue, hyper::header, reject, Filter, Rejection};
 
-    #[cfg(feature = "container")]
-    #[derive(Debug)]
-    struct MalformedJwt;
-
-    #[cfg(feature = "container")]
-    impl reject::Reject for MalformedJwt {}
+    use crate::{
+        access_log::RequestContext,
+        groups::Groups,
+        identity::{IdentityError, IdentitySource, RequestInfo},
+        readiness::{Discovered, NotReady, Readiness},
+        server::{PeerCertificate, RemoteAddr, UnixSocket},
+    };
 
     pub fn disable_cache() -> warp::reply::with::WithHeaders {
         let mut no_cache = warp::http::HeaderMap::new();
@@ -142,64 +230,69 @@ mod filters {
         })
     }
 
-    // Unfortunately, we can't just use debug here for testing since it is somehow dropping
-    // the context in my build
+    /// Waits for the identity source's discovery, rejecting with `NotReady` until it is done
+    pub fn discovered(
+        readiness: Readiness,
+    ) -> impl Filter<Extract = (Arc<Discovered>,), Error = Rejection> + Clone {
+        warp::any().and_then(move || {
+            let readiness = readiness.clone();
+            async move { readiness.get().ok_or(reject::custom(NotReady)) }
+        })
+    }
 
-    /// Extracts a JWT token from the header provided by pomerium
-    #[cfg(feature = "container")]
-    pub fn jwt(
-        jwt_decoder: Arc<crate::jwt::JwtDecoder>,
-    ) -> impl Filter<Extract = (crate::common::CurrentUserData,), Error = Rejection> + Clone {
-        warp::header::header("X-Pomerium-Jwt-Assertion")
-            .map(move |s| (s, jwt_decoder.clone()))
-            .and_then(
-                move |(s, jwt_decoder): (String, Arc<JwtDecoder>)| async move {
-                    trace!(jwt = s);
-                    jwt_decoder
-                        .decode(Jwt::from(s))
-                        .ok_or(reject::custom(MalformedJwt))
+    /// What identity sources get to look at
+    fn request_info() -> impl Filter<Extract = (RequestInfo,), Error = Infallible> + Clone {
+        warp::header::headers_cloned()
+            .and(warp::ext::optional::<RemoteAddr>())
+            .and(warp::ext::optional::<UnixSocket>())
+            .and(warp::ext::optional::<PeerCertificate>())
+            .map(
+                |headers, remote: Option<RemoteAddr>, unix_socket: Option<UnixSocket>, cert: Option<PeerCertificate>| {
+                    RequestInfo {
+                        headers,
+                        remote: remote.map(|r| r.0),
+                        unix_socket: unix_socket.is_some(),
+                        peer_certificate: cert.map(|c| c.0),
+                    }
                 },
             )
     }
 
-    #[cfg(not(feature = "container"))]
-    pub fn jwt(
-        _: Arc<crate::jwt::JwtDecoder>,
-    ) -> impl Filter<Extract = (crate::common::CurrentUserData,), Error = std::convert::Infallible> + Clone
-    {
-        // Unfortunately, we can't just use debug here for testing since it is somehow dropping
-        // the context in my build
-        warp::any().map(|| crate::common::CurrentUserData {
-            email: consts::defaults::debug::EMAIL.to_string(),
-            name: consts::defaults::debug::NAME.to_string(),
-            picture: None,
-        })
-    }
-}
-
-mod pomerium_routes {
-    use serde::Deserialize;
-
-    #[cfg(feature = "container")]
-    use crate::utils::get_json;
-
-    #[derive(Clone, Debug, Deserialize)]
-    pub struct KnownRoutes {
-        pub frontchannel_logout_uri: String,
-        pub jwks_uri: String,
+    /// Finds out who is making the request through the configured identity source
+    pub fn identity(
+        source: Arc<dyn IdentitySource>,
+    ) -> impl Filter<Extract = (crate::common::CurrentUserData,), Error = Rejection> + Clone {
+        request_info()
+            .and(warp::ext::optional::<RequestContext>())
+            .and_then(move |request: RequestInfo, context: Option<RequestContext>| {
+                let source = source.clone();
+                async move {
+                    let user = source.identify(&request).map_err(|e| match e {
+                        IdentityError::NotReady => reject::custom(NotReady),
+                        e => reject::custom(e),
+                    })?;
+                    if let Some(context) = context {
+                        context.identified(&user.email);
+                    }
+                    Ok::<_, Rejection>(user)
+                }
+            })
     }
 
-    #[cfg(feature = "container")]
-    pub fn obtain_known(domain: &str) -> KnownRoutes {
-        get_json(&format!("{}/.well-known/pomerium", domain))
+    /// Who the request claims to be from, unverified, see `IdentitySource::claimed`
+    pub fn claimed(source: Arc<dyn IdentitySource>) -> impl Filter<Extract = (Option<String>,), Error = Infallible> + Clone {
+        request_info().map(move |request: RequestInfo| source.claimed(&request))
     }
 
-    #[cfg(not(feature = "container"))]
-    pub fn obtain_known(_: &str) -> KnownRoutes {
-        KnownRoutes {
-            frontchannel_logout_uri: "/test/logout".to_string(),
-            jwks_uri: "/test/jwks.json".to_string(),
-        }
+    /// Like `identity`, with the memberships from the groups backend added
+    pub fn identity_with_groups(
+        source: Arc<dyn IdentitySource>,
+        groups: Groups,
+    ) -> impl Filter<Extract = (crate::common::CurrentUserData,), Error = Rejection> + Clone {
+        identity(source).then(move |user| {
+            let groups = groups.clone();
+            async move { groups.resolve(user).await }
+        })
     }
 }
 
@@ -215,10 +308,15 @@ async fn main() {
         _ => panic!("Unexpected log_level env info"),
     };
 
+    let tracer_provider = telemetry::tracer_provider();
     tracing::subscriber::set_global_default(
-        tracing_subscriber::FmtSubscriber::builder()
-            .with_max_level(log_level)
-            .finish(),
+        tracing_subscriber::registry()
+            .with(tracing_subscriber::fmt::layer().with_filter(LevelFilter::from_level(log_level)))
+            .with(
+                tracer_provider
+                    .as_ref()
+                    .map(|provider| telemetry::layer(provider).with_filter(LevelFilter::from_level(log_level))),
+            ),
     )
     .expect("initializing log failed");
 
@@ -226,30 +324,45 @@ async fn main() {
     let static_file =
         |path: &'static str| warp::path(path).and(warp::fs::file(html_files.join(path)));
 
-    let (renderer, jwt_decoder, global_data) = {
+    let (renderer, readiness, identity, groups, tls_config, metrics_config, access_log, audit_log, trusted, security, rate_limiter) = {
         let conf_dir = Path::new(consts::paths::get_conf_dir());
         let config = config::load(conf_dir.join("config.toml"));
-        let pomerium_conf = pomerium::load_conf(conf_dir.join("pomerium.yaml"));
-
-        let known_routes = pomerium_routes::obtain_known(&config.domain.name);
-        let jwt_decoder = Arc::new(jwt::JwtDecoder::new(
-            &config.domain.name,
-            &known_routes.jwks_uri,
-        ));
-        let global_data = Arc::new(rendering::GlobalData {
-            sign_out_url: known_routes.frontchannel_logout_uri,
-        });
+        privacy::init(config.privacy.clone());
+        let pomerium_conf =
+            pomerium::load_conf(conf_dir.join("pomerium.yaml")).with_user_criterion(config.user_criterion);
+
+        // Pomerium might not be up yet, so we don't wait for it before listening
+        let readiness = readiness::Readiness::default();
+        let identity = identity::from_config(&config, &readiness);
+        let groups = groups::Groups::from_config(&config);
+
         let renderer = rendering::Renderer::from(
             config.routes,
             pomerium_conf.routes,
+            config.canonicalization,
             &html_files.join("index.html"),
-            global_data.clone(),
         );
 
-        (renderer, jwt_decoder, global_data)
+        (
+            renderer,
+            readiness,
+            identity,
+            groups,
+            config.tls,
+            config.metrics,
+            config.access_log.map(access_log::AccessLog::stdout),
+            config
+                .audit
+                .map(|audit| audit::AuditLog::new(audit).expect("Couldn't set up the audit log")),
+            std::sync::Arc::<[ipnet::IpNet]>::from(config.identity.trusted_proxies),
+            config.security,
+            config.rate_limit.as_ref().map(rate_limit::RateLimiter::new),
+        )
     };
 
     let renderer_clone = renderer.clone();
+    let index_audit = audit_log.clone();
+    let health = health::routes(readiness.clone(), renderer.clone(), html_files.to_path_buf());
 
     let index = warp::path::end()
         .and_then(|| async move {
@@ -258,18 +371,54 @@ async fn main() {
         })
         .untuple_one()
         .and(warp::get())
-        .and(filters::jwt(jwt_decoder))
-        .map(move |user_data: common::CurrentUserData| {
-            trace!("Jwt received!");
-            let html = renderer_clone.clone().render(user_data);
-            trace!("Done rendering");
-            warp::reply::html(html)
-        })
+        .and(filters::discovered(readiness.clone()))
+        .and(rate_limit::per_identity(
+            rate_limiter.clone(),
+            filters::claimed(identity.clone()),
+            filters::identity_with_groups(identity.clone(), groups.clone()),
+        ))
+        .and(audit::source(trusted.clone()))
+        .and(security::nonce())
+        .then(
+            move |discovered: Arc<readiness::Discovered>,
+                  user_data: common::CurrentUserData,
+                  source_ip: Option<std::net::IpAddr>,
+                  nonce: security::Nonce| {
+                let index_audit = index_audit.clone();
+                let mut renderer = renderer_clone.clone();
+                async move {
+                    trace!("Jwt received!");
+                    if let Some(audit_log) = &index_audit {
+                        audit_log
+                            .record(&audit::Event::new(audit::Action::View, &user_data, source_ip))
+                            .await;
+                    }
+                    let html = renderer.render(user_data, &discovered.global_data, &nonce.0);
+                    trace!("Done rendering");
+                    warp::reply::html(html)
+                }
+            },
+        )
         .with(filters::disable_cache());
 
+    let avatars = avatar::Avatars::new();
+    let avatar = warp::path!("avatar")
+        .and(warp::get())
+        .and(rate_limit::per_identity(
+            rate_limiter.clone(),
+            filters::claimed(identity.clone()),
+            filters::identity(identity.clone()),
+        ))
+        .then(move |user_data: common::CurrentUserData| {
+            let avatars = avatars.clone();
+            async move { avatars.get(&user_data).await.into_response() }
+        });
+
     const TWO_WEEKS: u64 = consts::time::weeks(2);
+    let precompressed = compression::Precompressed::load(&html_files.join("assets"));
     let assets = warp::path("assets")
-        .and(warp::fs::dir(html_files.join("assets")))
+        .and(precompressed.serve())
+        .map(compression::vary)
         .or(static_file("apple-touch-icon.png"))
         .or(static_file("favicon-16x16.png"))
         .or(static_file("favicon-32x32.png"))
@@ -279,45 +428,123 @@ async fn main() {
 
     let redirect_index = warp::path!("index.html").map(|| warp::redirect(Uri::from_static("/")));
 
-    let app = index
-        .or(assets)
+    let audit = match audit_log {
+        Some(audit_log) => audit::routes(
+            audit_log,
+            identity.clone(),
+            groups,
+            renderer.clone(),
+            trusted.clone(),
+            html_files.join("audit.html"),
+            rate_limiter.clone(),
+        ),
+        None => warp::any().and_then(|| async { Err(warp::reject::not_found()) }).boxed(),
+    };
+
+    // Assets are matched first so page loads don't eat into the limits
+    let app = assets
         .or(redirect_index)
-        .recover(move |err| {
-            let global_data = global_data.clone();
+        .or(rate_limit::per_ip(rate_limiter, trusted).and(index.or(avatar).or(audit).or(identity.routes())))
+        .recover(move |err: Rejection| {
+            let global_data = readiness
+                .get()
+                .map(|d| d.global_data.clone())
+                .unwrap_or_default();
             async move {
+                if err.find() == Some(&identity::IdentityError::LoginRequired) {
+                    return Ok(warp::redirect::found(Uri::from_static("/auth/login")).into_response());
+                }
+
+                let retry_after = err.find::<rate_limit::TooManyRequests>().map(|limited| limited.retry_after);
                 let hb = Arc::new(Handlebars::new());
                 let (html, status_code) = rendering::render_error(err, &hb, &global_data);
-                Ok::<_, Infallible>(warp::reply::with_status(
-                    warp::reply::html(html),
-                    status_code,
-                ))
+                let mut response = warp::reply::with_status(warp::reply::html(html), status_code).into_response();
+                if let Some(seconds) = retry_after {
+                    response
+                        .headers_mut()
+                        .insert(warp::http::header::RETRY_AFTER, warp::http::HeaderValue::from(seconds));
+                }
+                response.extensions_mut().insert(metrics::Recovered);
+                Ok::<_, Infallible>(response)
             }
+        });
+    let app = warp::path::full()
+        .and(warp::header::optional::<String>("accept-encoding"))
+        .and(app)
+        .then(compression::html);
+
+    // Probes come first, untouched by identities, rate limits and compression
+    let metrics_on_main = metrics_config.as_ref().filter(|m| m.port.is_none());
+    let app = health
+        .or(match metrics_on_main {
+            Some(config) => metrics::routes(config),
+            None => warp::any().and_then(|| async { Err(warp::reject::not_found()) }).boxed(),
         })
-        .with(warp::filters::compression::brotli());
+        .or(app);
 
     let http_port = std::env::var("HTTP_PORT")
         .ok()
         .map(|s: String| s.parse::<u16>().expect("Not a valid port"))
         .unwrap_or(consts::defaults::HTTP_PORT);
 
-    let serve_address = std::env::var("HTTP_ADDRESS")
-        .ok()
-        .map(|s: String| {
-            s.parse::<Ipv4Addr>()
-                .expect("Not a valid IPv4 address")
-                .octets()
-        })
-        .unwrap_or(consts::defaults::SERVE_ADRESS);
+    let addresses = listen::addresses(
+        &std::env::var("HTTP_ADDRESS").unwrap_or(consts::defaults::SERVE_ADDRESS.to_string()),
+        http_port,
+    );
+
+    let tls = tls_config.as_ref().map(tls::acceptor);
+    let app = access_log::Traced::new(
+        metrics::Instrumented(security::Secured::new(warp::service(app), &security)),
+        access_log,
+    );
+    let mut servers = JoinSet::new();
+    for listener in listen::from_env(&addresses) {
+        servers.spawn(server::serve(app.clone(), listener, tls.clone(), shutdown_signal()));
+    }
+
+    // Scrapers get their own plain HTTP port when asked for
+    if let Some(config) = metrics_config.as_ref().filter(|m| m.port.is_some()) {
+        let metrics_addresses: Vec<_> = addresses
+            .iter()
+            .map(|a| std::net::SocketAddr::new(a.ip(), config.port.unwrap_or_default()))
+            .collect();
+        for addr in &metrics_addresses {
+            let listener = listen::bind_tcp(*addr, &metrics_addresses)
+                .unwrap_or_else(|e| panic!("Couldn't bind the metrics to {}: {}", addr, e));
+            servers.spawn(server::serve(
+                warp::service(metrics::routes(config)),
+                server::Listener::Tcp(listener),
+                None,
+                shutdown_signal(),
+            ));
+        }
+    }
+
+    // Plain HTTP is only redirected on the addresses we were asked to bind
+    if let Some(port) = tls_config.as_ref().and_then(|t| t.redirect_port) {
+        let redirects: Vec<_> = addresses.iter().map(|a| std::net::SocketAddr::new(a.ip(), port)).collect();
+        for addr in &redirects {
+            let listener = listen::bind_tcp(*addr, &redirects)
+                .unwrap_or_else(|e| panic!("Couldn't bind the HTTP redirect to {}: {}", addr, e));
+            servers.spawn(server::serve(
+                warp::service(tls::redirect(http_port)),
+                server::Listener::Tcp(listener),
+                None,
+                shutdown_signal(),
+            ));
+        }
+    }
+
+    while servers.join_next().await.is_some() {}
 
-    // spawn proxy server
-    warp::serve(app)
-        .bind((serve_address, http_port))
+    // Whatever spans are still waiting get sent before leaving
+    if let Some(provider) = tracer_provider {
+        let _ = tokio::task::spawn_blocking(move || provider.shutdown()).await;
+    }
+}
+
+async fn shutdown_signal() {
+    tokio::signal::ctrl_c()
         .await
-        .graceful(async move {
-            tokio::signal::ctrl_c()
-                .await
-                .expect("failed to listen to shutdown signal");
-        })
-        .run()
-        .await;
+        .expect("failed to listen to shutdown signal");
 }
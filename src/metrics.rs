@@ -0,0 +1,239 @@
+use std::{
+    convert::Infallible,
+    future::Future,
+    pin::Pin,
+    sync::OnceLock,
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+use prometheus::{
+    exponential_buckets, Encoder, Histogram, HistogramOpts, HistogramVec, IntCounterVec, IntGauge, Opts, Registry,
+    TextEncoder,
+};
+use serde::Deserialize;
+use tower_service::Service;
+use tracing::warn;
+use warp::{
+    filters::BoxedFilter,
+    http::{header, StatusCode},
+    hyper::{body::Incoming, Request},
+    reject,
+    reply::Response,
+    Filter, Reply,
+};
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct MetricsConfig {
+    /// Serve the metrics on their own port instead of next to everything else
+    #[serde(default)]
+    pub port: Option<u16>,
+
+    /// Scrapers have to send it as `Authorization: Bearer <token>`
+    #[serde(default)]
+    pub token: Option<String>,
+}
+
+pub struct Metrics {
+    registry: Registry,
+    pub requests: IntCounterVec,
+    pub request_duration: HistogramVec,
+    pub jwt_verifications: IntCounterVec,
+    pub render_cache_entries: IntGauge,
+    pub render_cache_lookups: IntCounterVec,
+    pub policy_duration: Histogram,
+    pub reloads: IntCounterVec,
+    pub rate_limited: IntCounterVec,
+}
+
+impl Metrics {
+    fn new() -> Self {
+        let registry = Registry::new_custom(Some("hallway".to_string()), None).expect("Valid metrics prefix");
+        let metrics = Self {
+            requests: IntCounterVec::new(
+                Opts::new("http_requests_total", "Requests answered, by route and status"),
+                &["route", "status"],
+            )
+            .unwrap(),
+            request_duration: HistogramVec::new(
+                HistogramOpts::new("http_request_duration_seconds", "Time taken to answer requests, by route"),
+                &["route"],
+            )
+            .unwrap(),
+            jwt_verifications: IntCounterVec::new(
+                Opts::new("jwt_verifications_total", "Tokens looked at, by result"),
+                &["result"],
+            )
+            .unwrap(),
+            render_cache_entries: IntGauge::new("render_cache_entries", "Pages kept in the render cache").unwrap(),
+            render_cache_lookups: IntCounterVec::new(
+                Opts::new("render_cache_lookups_total", "Render cache lookups, by hit or miss"),
+                &["result"],
+            )
+            .unwrap(),
+            policy_duration: Histogram::with_opts(
+                HistogramOpts::new("policy_evaluation_seconds", "Time taken to find the routes a user can see")
+                    .buckets(exponential_buckets(0.00001, 4.0, 10).unwrap()),
+            )
+            .unwrap(),
+            reloads: IntCounterVec::new(
+                Opts::new("config_reloads_total", "Files read again after changing, by file and result"),
+                &["file", "result"],
+            )
+            .unwrap(),
+            rate_limited: IntCounterVec::new(
+                Opts::new("rate_limited_total", "Requests turned away for going over a limit, by limit"),
+                &["limit"],
+            )
+            .unwrap(),
+            registry,
+        };
+
+        let collectors: [Box<dyn prometheus::core::Collector>; 8] = [
+            Box::new(metrics.requests.clone()),
+            Box::new(metrics.request_duration.clone()),
+            Box::new(metrics.jwt_verifications.clone()),
+            Box::new(metrics.render_cache_entries.clone()),
+            Box::new(metrics.render_cache_lookups.clone()),
+            Box::new(metrics.policy_duration.clone()),
+            Box::new(metrics.reloads.clone()),
+            Box::new(metrics.rate_limited.clone()),
+        ];
+        for collector in collectors {
+            metrics.registry.register(collector).expect("Metrics are only registered once");
+        }
+        metrics
+    }
+
+    /// Counts a file being read again, `ok` tells whether it could be used
+    pub fn reloaded(&self, file: &str, ok: bool) {
+        let result = if ok { "ok" } else { "failed" };
+        self.reloads.with_label_values(&[file, result]).inc();
+    }
+
+    pub fn encode(&self) -> String {
+        let mut out = Vec::new();
+        TextEncoder::new()
+            .encode(&self.registry.gather(), &mut out)
+            .expect("Metrics can always be encoded");
+        String::from_utf8(out).expect("Metrics are always UTF-8")
+    }
+}
+
+/// Metrics are updated from all over the place, so there's a single set of them
+pub fn metrics() -> &'static Metrics {
+    static METRICS: OnceLock<Metrics> = OnceLock::new();
+    METRICS.get_or_init(Metrics::new)
+}
+
+/// Marks responses made by the rejection handler, so they are counted as errors
+#[derive(Clone, Copy, Debug)]
+pub struct Recovered;
+
+/// The route filter a path ends up in
+fn route_of(path: &str) -> &'static str {
+    match path {
+        "/" | "/index.html" => "index",
+        "/avatar" => "avatar",
+        "/healthz" | "/readyz" => "health",
+        "/metrics" => "metrics",
+        "/audit" => "audit",
+        p if p.starts_with("/launch/") => "launch",
+        p if p.starts_with("/auth/") => "auth",
+        _ => "assets",
+    }
+}
+
+/// Counts and times every request going through the service
+#[derive(Clone)]
+pub struct Instrumented<S>(pub S);
+
+impl<S> Service<Request<Incoming>> for Instrumented<S>
+where
+    S: Service<Request<Incoming>, Response = Response, Error = Infallible>,
+    S::Future: Send + 'static,
+{
+    type Response = Response;
+    type Error = Infallible;
+    type Future = Pin<Box<dyn Future<Output = Result<Response, Infallible>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Infallible>> {
+        self.0.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<Incoming>) -> Self::Future {
+        let route = route_of(req.uri().path());
+        let start = Instant::now();
+        let response = self.0.call(req);
+        Box::pin(async move {
+            let response = response.await?;
+            let route = match response.extensions().get::<Recovered>() {
+                Some(_) => "errors",
+                None => route,
+            };
+            record(route, response.status(), start.elapsed());
+            Ok(response)
+        })
+    }
+}
+
+fn record(route: &str, status: StatusCode, elapsed: Duration) {
+    let metrics = metrics();
+    metrics
+        .requests
+        .with_label_values(&[route, status.as_str()])
+        .inc();
+    metrics
+        .request_duration
+        .with_label_values(&[route])
+        .observe(elapsed.as_secs_f64());
+}
+
+#[derive(Debug)]
+struct Unauthorized;
+
+impl reject::Reject for Unauthorized {}
+
+/// `/metrics` in Prometheus' text format, behind the token when there's one
+pub fn routes(config: &MetricsConfig) -> BoxedFilter<(Response,)> {
+    if config.port.is_none() && config.token.is_none() {
+        warn!("Metrics are served to anyone, set a port or a token to restrict them");
+    }
+    let token = config.token.clone().map(|t| format!("Bearer {}", t));
+
+    warp::path!("metrics")
+        .and(warp::get())
+        .and(warp::header::optional::<String>(header::AUTHORIZATION.as_str()))
+        .and_then(move |auth: Option<String>| {
+            let allowed = match (&token, auth) {
+                (None, _) => true,
+                (Some(token), Some(auth)) => {
+                    token.len() == auth.len() && openssl::memcmp::eq(token.as_bytes(), auth.as_bytes())
+                }
+                (Some(_), None) => false,
+            };
+            async move {
+                match allowed {
+                    true => Ok(()),
+                    false => Err(reject::custom(Unauthorized)),
+                }
+            }
+        })
+        .untuple_one()
+        .map(|| {
+            warp::reply::with_header(metrics().encode(), header::CONTENT_TYPE, prometheus::TEXT_FORMAT).into_response()
+        })
+        .recover(|err: warp::Rejection| async move {
+            match err.find::<Unauthorized>() {
+                Some(_) => Ok(warp::reply::with_header(
+                    StatusCode::UNAUTHORIZED,
+                    header::WWW_AUTHENTICATE,
+                    "Bearer",
+                )
+                .into_response()),
+                None => Err(err),
+            }
+        })
+        .unify()
+        .boxed()
+}
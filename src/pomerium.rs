@@ -1,14 +1,48 @@
 use policy::PolicyChecker;
 use serde::Deserialize;
-use std::{collections::HashSet, path::Path};
+use std::path::Path;
 
 use self::policy::{AndPolicy, NorPolicy, NotPolicy, OrPolicy};
+pub use self::policy::{Canonicalization, PolicyInput};
 
 #[derive(Debug, Deserialize)]
 pub struct Config {
     pub routes: Vec<Route>,
 }
 
+/// What the `user` criterion is compared with
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UserCriterion {
+    /// The identity provider's user id, like pomerium does
+    #[default]
+    Subject,
+    /// The email, like older versions of hallway did
+    Email,
+}
+
+impl Config {
+    /// Turns `user` criteria into `email` ones when asked for the old behaviour
+    pub fn with_user_criterion(mut self, criterion: UserCriterion) -> Self {
+        if criterion == UserCriterion::Email {
+            self.routes
+                .iter_mut()
+                .flat_map(|r| r.policy.0.iter_mut())
+                .flat_map(|a| [&mut a.allow, &mut a.deny])
+                .flat_map(|o| [&mut o.or.0, &mut o.and.0, &mut o.not.0, &mut o.nor.0])
+                .flat_map(|c| c.iter_mut())
+                .for_each(|c| {
+                    if let ActionCriteria::User { user } = c {
+                        *c = ActionCriteria::Email {
+                            email: std::mem::take(user),
+                        };
+                    }
+                });
+        }
+        self
+    }
+}
+
 #[derive(Debug, Deserialize)]
 pub struct Route {
     pub from: String,
@@ -33,18 +67,19 @@ impl Policy {
     pub fn allow_all() -> Policy {
         Policy(vec![PolicyAction{allow: ActionOperator::any(), deny: ActionOperator::empty()}])
     }
-    pub fn extract_emails(&self, hash_set: &mut HashSet<String>) {
-        self.0.iter().for_each(|a| a.extract_emails(hash_set));
+    #[cfg(test)]
+    pub fn check_authorized(&self, email: &str) -> bool {
+        self.check(&PolicyInput::email(email))
     }
 
-    pub fn check_authorized(&self, email: &str) -> bool {
+    pub fn check(&self, input: &PolicyInput) -> bool {
         if self.0.is_empty() {
             false
         }
         else {
             self.0
                 .iter()
-                .any(|p| p.check_authorized(email).try_into().unwrap_or(true))
+                .any(|p| p.check_authorized(input).try_into().unwrap_or(true))
         }
         
     }
@@ -59,13 +94,6 @@ struct PolicyAction {
     deny: ActionOperator,
 }
 
-impl PolicyAction {
-    pub fn extract_emails(&self, hash_set: &mut HashSet<String>) {
-        self.allow.extract_emails(hash_set);
-        self.deny.extract_emails(hash_set);
-    }
-}
-
 #[derive(Debug, Default, Deserialize)]
 struct ActionOperator {
     #[serde(default)]
@@ -99,13 +127,6 @@ impl ActionOperator {
             nor: NorPolicy(vec![])
         }
     }
-
-    fn extract_emails(&self, hash_set: &mut HashSet<String>) {
-        self.or.extract_emails(hash_set);
-        self.and.extract_emails(hash_set);
-        self.not.extract_emails(hash_set);
-        self.nor.extract_emails(hash_set);
-    }
 }
 
 #[allow(dead_code)] // We need the "dead code" since it is used as a marker
@@ -114,25 +135,18 @@ impl ActionOperator {
 enum ActionCriteria {
     User { user: matchers::String },
     Email { email: matchers::String },
+    Domain { domain: matchers::String },
+    Groups { groups: matchers::List },
+    ClientCertificate { client_certificate: matchers::Certificate },
     Accept { accept: matchers::Empty },
 }
 
-impl ActionCriteria {
-    fn extract_emails(&self, hash_set: &mut HashSet<String>) {
-        match self {
-            ActionCriteria::User { user } => user.extract_emails(hash_set),
-            ActionCriteria::Email { email } => email.extract_emails(hash_set),
-            ActionCriteria::Accept { accept: _ } => {}
-        }
-    }
-}
 mod matchers {
-    use serde::Deserialize;
-    use std::collections::HashSet;
+    use serde::{Deserialize, Deserializer};
 
-    use super::policy::PolicyCheckerResult;
+    use super::policy::{Canonicalization, PolicyCheckerResult, PolicyInput};
 
-    #[derive(Debug, Deserialize)]
+    #[derive(Debug, Default, Deserialize)]
     pub struct String {
         #[serde(default)]
         is: Option<std::string::String>,
@@ -147,40 +161,113 @@ mod matchers {
         contains: Option<std::string::String>,
     }
 
+    // This is to accept accept inputed as Yaml requires something
+    #[derive(Debug, Deserialize)]
+    pub struct Empty(#[allow(dead_code)] pub(super) serde_yaml::Value);
+
     impl String {
-        pub fn extract_emails(&self, hash_set: &mut HashSet<std::string::String>) {
-            self.is.as_ref().map(|s| hash_set.insert(s.clone()));
-            self.starts_with
-                .as_ref()
-                .map(|s| hash_set.insert(s.clone()));
-            self.ends_with.as_ref().map(|s| hash_set.insert(s.clone()));
-            self.contains.as_ref().map(|s| hash_set.insert(s.clone()));
+        /// Whether `value` matches, with both sides normalized the same way
+        pub fn matches<F>(&self, value: &str, normalize: F) -> PolicyCheckerResult
+        where
+            F: Fn(&str) -> std::string::String,
+        {
+            let value = normalize(value);
+            let check = |pattern: &Option<std::string::String>, f: fn(&str, &str) -> bool| {
+                pattern.as_ref().map(|p| f(&value, &normalize(p))).unwrap_or(true)
+            };
+
+            let is = check(&self.is, |v, p| v == p);
+            let starts_with = check(&self.starts_with, |v, p| v.starts_with(p));
+            let ends_with = check(&self.ends_with, |v, p| v.ends_with(p));
+            let contains = check(&self.contains, |v, p| v.contains(p));
+            (is && starts_with && ends_with && contains).into()
+        }
+
+        /// Like `matches` for emails. Patterns without an `@` are only part of
+        /// one side of it, so they're normalized like that side: a suffix is
+        /// part of the domain, and what's contained can be in either
+        pub fn matches_email(&self, email: &str, canon: &Canonicalization) -> PolicyCheckerResult {
+            let value = canon.email(email);
+            let (local, domain) = value.rsplit_once('@').unwrap_or((&value, ""));
+            let check = |pattern: &Option<std::string::String>, f: &dyn Fn(&str) -> bool| {
+                pattern.as_ref().map(|p| f(p)).unwrap_or(true)
+            };
+
+            let is = check(&self.is, &|p| value == canon.email(p));
+            let starts_with = check(&self.starts_with, &|p| value.starts_with(&canon.email(p)));
+            let ends_with = check(&self.ends_with, &|p| match p.contains('@') {
+                true => value.ends_with(&canon.email(p)),
+                false => domain.ends_with(&canon.domain(p)),
+            });
+            let contains = check(&self.contains, &|p| match p.contains('@') {
+                true => value.contains(&canon.email(p)),
+                false => local.contains(&canon.email(p)) || domain.contains(&canon.domain(p)),
+            });
+            (is && starts_with && ends_with && contains).into()
         }
     }
 
-    // This is to accept accept inputed as Yaml requires something
+    // Pomerium takes either a single value or a list of them
+    fn one_or_many<'de, D>(deserializer: D) -> Result<Vec<std::string::String>, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum OneOrMany {
+            One(std::string::String),
+            Many(Vec<std::string::String>),
+        }
+
+        Ok(match OneOrMany::deserialize(deserializer)? {
+            OneOrMany::One(s) => vec![s],
+            OneOrMany::Many(v) => v,
+        })
+    }
+
+    /// Matches lists like the user's groups
     #[derive(Debug, Deserialize)]
-    pub struct Empty(pub(super) serde_yaml::Value);
+    pub struct List {
+        #[serde(default)]
+        has: Option<std::string::String>,
+    }
 
-    impl super::policy::PolicyChecker for String {
-        fn check_authorized(&self, email: &str) -> PolicyCheckerResult {
-            let is = self.is.as_ref().map(|s| s == email).unwrap_or(true);
-            let starts_with = self
-                .starts_with
-                .as_ref()
-                .map(|s| email.starts_with(s))
-                .unwrap_or(true);
-            let ends_with = self
-                .ends_with
+    impl List {
+        pub fn matches(&self, values: &[std::string::String]) -> PolicyCheckerResult {
+            self.has
                 .as_ref()
-                .map(|s| email.ends_with(s))
-                .unwrap_or(true);
-            let contains = self
-                .contains
-                .as_ref()
-                .map(|s| email.contains(s))
-                .unwrap_or(true);
-            (is && starts_with && ends_with && contains).into()
+                .map(|h| values.contains(h))
+                .unwrap_or(true)
+                .into()
+        }
+    }
+
+    /// Matches the client certificate, every field given has to match
+    #[derive(Debug, Deserialize)]
+    pub struct Certificate {
+        /// Hex SHA-256 of the whole certificate
+        #[serde(default, deserialize_with = "one_or_many")]
+        fingerprint: Vec<std::string::String>,
+
+        /// Base64 SHA-256 of the certificate's public key info
+        #[serde(default, deserialize_with = "one_or_many")]
+        spki_hash: Vec<std::string::String>,
+    }
+
+    impl super::policy::PolicyChecker for Certificate {
+        fn check_authorized(&self, input: &PolicyInput) -> PolicyCheckerResult {
+            let Some(cert) = input.client_certificate else {
+                return PolicyCheckerResult::NotPassed;
+            };
+
+            // Fingerprints are often written with colons between bytes
+            let fingerprint = self.fingerprint.is_empty()
+                || self
+                    .fingerprint
+                    .iter()
+                    .any(|f| f.replace(':', "").eq_ignore_ascii_case(&cert.fingerprint));
+            let spki_hash = self.spki_hash.is_empty() || self.spki_hash.contains(&cert.spki_hash);
+            (fingerprint && spki_hash).into()
         }
     }
 }
@@ -210,12 +297,114 @@ pub fn load_from_str(conf: &str) -> Config {
 }
 
 pub mod policy {
-    use std::collections::HashSet;
-
     use super::ActionCriteria;
+    use crate::identity::ClientCertificate;
     use serde::Deserialize;
     use tracing::trace;
 
+    fn yes() -> bool {
+        true
+    }
+
+    /// How emails and domains are normalized before comparing them, so that
+    /// `Alice@Example.com` from the identity provider matches `alice@example.com`
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+    pub struct Canonicalization {
+        #[serde(default = "yes")]
+        pub case_fold: bool,
+
+        /// `alice+ops@example.com` becomes `alice@example.com`
+        #[serde(default)]
+        pub strip_plus_tags: bool,
+
+        /// Internationalized domains are compared in their punycode form
+        #[serde(default = "yes")]
+        pub punycode: bool,
+    }
+
+    impl Default for Canonicalization {
+        fn default() -> Self {
+            Self {
+                case_fold: true,
+                strip_plus_tags: false,
+                punycode: true,
+            }
+        }
+    }
+
+    impl Canonicalization {
+        /// Works on parts of emails as well, as policies match on those too
+        pub fn email(&self, email: &str) -> String {
+            let (local, domain) = match email.rsplit_once('@') {
+                Some((local, domain)) => (local, Some(domain)),
+                None => (email, None),
+            };
+            let local = match (self.strip_plus_tags, domain) {
+                (true, Some(_)) => local.split('+').next().unwrap_or(local),
+                _ => local,
+            };
+            let local = if self.case_fold { local.to_lowercase() } else { local.to_string() };
+
+            match domain {
+                Some(domain) => format!("{}@{}", local, self.domain(domain)),
+                None => local,
+            }
+        }
+
+        /// Label by label, so that parts of domains like `.bücher.example` in
+        /// patterns come out the same as in whole domains
+        pub fn domain(&self, domain: &str) -> String {
+            let domain = match self.punycode {
+                true => domain
+                    .split('.')
+                    .map(|label| idna::domain_to_ascii(label).unwrap_or_else(|_| label.to_string()))
+                    .collect::<Vec<_>>()
+                    .join("."),
+                false => domain.to_string(),
+            };
+            if self.case_fold { domain.to_lowercase() } else { domain }
+        }
+    }
+
+    /// Everything policies get to know about the user
+    #[derive(Debug, Default, Clone, Copy)]
+    pub struct PolicyInput<'a> {
+        pub email: &'a str,
+        pub subject: Option<&'a str>,
+        pub groups: &'a [std::string::String],
+        pub client_certificate: Option<&'a ClientCertificate>,
+        pub canonicalization: Canonicalization,
+    }
+
+    impl<'a> PolicyInput<'a> {
+        #[cfg(test)]
+        pub fn email(email: &'a str) -> Self {
+            Self {
+                email,
+                ..Default::default()
+            }
+        }
+
+        pub fn with_canonicalization(self, canonicalization: Canonicalization) -> Self {
+            Self {
+                canonicalization,
+                ..self
+            }
+        }
+    }
+
+    impl<'a> From<&'a crate::common::CurrentUserData> for PolicyInput<'a> {
+        fn from(user: &'a crate::common::CurrentUserData) -> Self {
+            Self {
+                email: &user.email,
+                subject: user.subject.as_deref(),
+                groups: &user.groups,
+                client_certificate: user.client_certificate.as_ref(),
+                ..Default::default()
+            }
+        }
+    }
+
     #[derive(Debug, PartialEq)]
     pub enum PolicyCheckerResult {
         Passed,
@@ -273,6 +462,7 @@ pub mod policy {
         }
     }
 
+    #[allow(clippy::to_string_trait_impl)]
     impl ToString for PolicyCheckerResult {
         fn to_string(&self) -> String {
             match self {
@@ -284,13 +474,13 @@ pub mod policy {
     }
 
     pub trait PolicyChecker {
-        fn check_authorized(&self, email: &str) -> PolicyCheckerResult;
+        fn check_authorized(&self, input: &PolicyInput) -> PolicyCheckerResult;
     }
 
     impl PolicyChecker for super::PolicyAction {
-        fn check_authorized(&self, email: &str) -> PolicyCheckerResult {
-            let allowed = self.allow.check_authorized(email);
-            let denied = self.deny.check_authorized(email);
+        fn check_authorized(&self, input: &PolicyInput) -> PolicyCheckerResult {
+            let allowed = self.allow.check_authorized(input);
+            let denied = self.deny.check_authorized(input);
 
             trace!("allowed={:?} denied={:?}", allowed, denied);
             allowed + !denied
@@ -298,11 +488,11 @@ pub mod policy {
     }
 
     impl PolicyChecker for super::ActionOperator {
-        fn check_authorized(&self, email: &str) -> PolicyCheckerResult {
-            let or_pol = self.or.check_authorized(email);
-            let and_pol = self.and.check_authorized(email);
-            let not_pol = self.not.check_authorized(email);
-            let nor_pol = self.nor.check_authorized(email);
+        fn check_authorized(&self, input: &PolicyInput) -> PolicyCheckerResult {
+            let or_pol = self.or.check_authorized(input);
+            let and_pol = self.and.check_authorized(input);
+            let not_pol = self.not.check_authorized(input);
+            let nor_pol = self.nor.check_authorized(input);
 
             trace!(
                 "or={:?}, and={:?}, not={:?}, nor={:?}",
@@ -317,10 +507,23 @@ pub mod policy {
     }
 
     impl PolicyChecker for super::ActionCriteria {
-        fn check_authorized(&self, email_in: &str) -> PolicyCheckerResult {
+        fn check_authorized(&self, input: &PolicyInput) -> PolicyCheckerResult {
+            let canon = &input.canonicalization;
             match self {
-                ActionCriteria::User { user } => user.check_authorized(email_in),
-                ActionCriteria::Email { email } => email.check_authorized(email_in),
+                // Subjects are opaque, they are compared as they are
+                ActionCriteria::User { user } => match input.subject {
+                    Some(subject) => user.matches(subject, str::to_string),
+                    None => PolicyCheckerResult::NotPassed,
+                },
+                ActionCriteria::Email { email } => email.matches_email(input.email, canon),
+                ActionCriteria::Domain { domain } => {
+                    let user_domain = input.email.rsplit_once('@').map(|(_, d)| d).unwrap_or_default();
+                    domain.matches(user_domain, |d| canon.domain(d))
+                }
+                ActionCriteria::Groups { groups } => groups.matches(input.groups),
+                ActionCriteria::ClientCertificate { client_certificate } => {
+                    client_certificate.check_authorized(input)
+                }
                 ActionCriteria::Accept { accept: _ } => PolicyCheckerResult::Passed,
             }
         }
@@ -342,29 +545,29 @@ pub mod policy {
     const PASSES: bool = false;
     const NOT_PASSES: bool = true;
 
-    fn check_into_bool(c: &ActionCriteria, email: &str, inverted: bool) -> bool {
-        TryInto::<bool>::try_into(c.check_authorized(email))
+    fn check_into_bool(c: &ActionCriteria, input: &PolicyInput, inverted: bool) -> bool {
+        TryInto::<bool>::try_into(c.check_authorized(input))
             .expect("At this point there should be no empty")
             ^ inverted
     }
 
     fn any_passes(
         crit_vec: &[super::ActionCriteria],
-        email: &str,
+        input: &PolicyInput,
         inverted: bool,
     ) -> PolicyCheckerResult {
         wrap_iter(crit_vec, |mut iter| {
-            iter.any(|c| check_into_bool(c, email, inverted)).into()
+            iter.any(|c| check_into_bool(c, input, inverted)).into()
         })
     }
 
     fn all_pass(
         crit_vec: &[super::ActionCriteria],
-        email: &str,
+        input: &PolicyInput,
         inverted: bool,
     ) -> PolicyCheckerResult {
         wrap_iter(crit_vec, |mut iter| {
-            iter.all(|c| check_into_bool(c, email, inverted)).into()
+            iter.all(|c| check_into_bool(c, input, inverted)).into()
         })
     }
 
@@ -372,60 +575,36 @@ pub mod policy {
     #[serde(transparent)]
     pub(super) struct OrPolicy(pub(super) Vec<super::ActionCriteria>);
 
-    impl OrPolicy {
-        pub fn extract_emails(&self, hash_set: &mut HashSet<String>) {
-            self.0.iter().for_each(|p| p.extract_emails(hash_set))
-        }
-    }
-
     impl PolicyChecker for OrPolicy {
-        fn check_authorized(&self, email: &str) -> PolicyCheckerResult {
-            any_passes(&self.0, email, PASSES)
+        fn check_authorized(&self, input: &PolicyInput) -> PolicyCheckerResult {
+            any_passes(&self.0, input, PASSES)
         }
     }
 
     #[derive(Debug, Default, Deserialize)]
     pub(super) struct NorPolicy(pub(super) Vec<super::ActionCriteria>);
 
-    impl NorPolicy {
-        pub fn extract_emails(&self, hash_set: &mut HashSet<String>) {
-            self.0.iter().for_each(|p| p.extract_emails(hash_set))
-        }
-    }
-
     impl PolicyChecker for NorPolicy {
-        fn check_authorized(&self, email: &str) -> PolicyCheckerResult {
-            any_passes(&self.0, email, NOT_PASSES)
+        fn check_authorized(&self, input: &PolicyInput) -> PolicyCheckerResult {
+            any_passes(&self.0, input, NOT_PASSES)
         }
     }
 
     #[derive(Debug, Default, Deserialize)]
     pub(super) struct AndPolicy(pub(super) Vec<super::ActionCriteria>);
 
-    impl AndPolicy {
-        pub fn extract_emails(&self, hash_set: &mut HashSet<String>) {
-            self.0.iter().for_each(|p| p.extract_emails(hash_set))
-        }
-    }
-
     impl PolicyChecker for AndPolicy {
-        fn check_authorized(&self, email: &str) -> PolicyCheckerResult {
-            all_pass(&self.0, email, PASSES)
+        fn check_authorized(&self, input: &PolicyInput) -> PolicyCheckerResult {
+            all_pass(&self.0, input, PASSES)
         }
     }
 
     #[derive(Debug, Default, Deserialize)]
     pub(super) struct NotPolicy(pub(super) Vec<super::ActionCriteria>);
 
-    impl NotPolicy {
-        pub fn extract_emails(&self, hash_set: &mut HashSet<String>) {
-            self.0.iter().for_each(|p| p.extract_emails(hash_set))
-        }
-    }
-
     impl PolicyChecker for NotPolicy {
-        fn check_authorized(&self, email: &str) -> PolicyCheckerResult {
-            all_pass(&self.0, email, NOT_PASSES)
+        fn check_authorized(&self, input: &PolicyInput) -> PolicyCheckerResult {
+            all_pass(&self.0, input, NOT_PASSES)
         }
     }
 }
@@ -0,0 +1,78 @@
+use std::sync::OnceLock;
+
+use serde::Deserialize;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Redaction {
+    /// Logged as they are
+    #[default]
+    None,
+    /// Only the first letter is kept, and the domain of emails
+    Mask,
+    /// Replaced by a short hash, so lines about the same user can still be
+    /// told apart
+    Hash,
+}
+
+/// How emails and names show up in logs, the access log and traces
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct PrivacyConfig {
+    #[serde(default)]
+    pub redaction: Redaction,
+
+    /// Mixed into the hashes, so they can't be matched against a list of emails
+    #[serde(default)]
+    pub salt: String,
+}
+
+impl PrivacyConfig {
+    fn hash(&self, value: &str) -> String {
+        let digest = openssl::sha::sha256(format!("{}{}", self.salt, value).as_bytes());
+        digest[..6].iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    pub fn email(&self, email: &str) -> String {
+        match self.redaction {
+            Redaction::None => email.to_string(),
+            Redaction::Mask => match email.rsplit_once('@') {
+                Some((local, domain)) => format!("{}***@{}", first_letter(local), domain),
+                None => format!("{}***", first_letter(email)),
+            },
+            Redaction::Hash => self.hash(email),
+        }
+    }
+
+    pub fn name(&self, name: &str) -> String {
+        match self.redaction {
+            Redaction::None => name.to_string(),
+            Redaction::Mask => format!("{}***", first_letter(name)),
+            Redaction::Hash => self.hash(name),
+        }
+    }
+}
+
+fn first_letter(value: &str) -> &str {
+    value.char_indices().nth(1).map_or(value, |(end, _)| &value[..end])
+}
+
+static PRIVACY: OnceLock<PrivacyConfig> = OnceLock::new();
+
+/// Sets the redaction used from then on, only the first call counts
+pub fn init(config: PrivacyConfig) {
+    let _ = PRIVACY.set(config);
+}
+
+fn privacy() -> &'static PrivacyConfig {
+    PRIVACY.get_or_init(Default::default)
+}
+
+/// `email` the way it can be logged
+pub fn email(email: &str) -> String {
+    privacy().email(email)
+}
+
+/// `name` the way it can be logged
+pub fn name(name: &str) -> String {
+    privacy().name(name)
+}
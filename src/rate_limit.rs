@@ -0,0 +1,246 @@
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    net::IpAddr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use ipnet::IpNet;
+use serde::{de::Error as _, Deserialize, Deserializer};
+use tokio::time::Instant;
+use tracing::debug;
+use warp::{reject, Filter, Rejection};
+
+use crate::{audit, common::CurrentUserData, metrics::metrics, privacy};
+
+mod config_defaults {
+    use super::Limit;
+    use crate::consts::defaults;
+
+    pub fn per_ip() -> Limit {
+        Limit {
+            burst: defaults::RATE_LIMIT_IP_BURST,
+            per_minute: defaults::RATE_LIMIT_IP_PER_MINUTE,
+        }
+    }
+
+    pub fn per_identity() -> Limit {
+        Limit {
+            burst: defaults::RATE_LIMIT_IDENTITY_BURST,
+            per_minute: defaults::RATE_LIMIT_IDENTITY_PER_MINUTE,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+pub struct Limit {
+    /// Requests that can be made at once after a quiet while
+    pub burst: u32,
+    /// Requests the bucket gets back every minute, an empty bucket would
+    /// never refill with none
+    #[serde(deserialize_with = "at_least_one")]
+    pub per_minute: u32,
+}
+
+fn at_least_one<'de, D>(deserializer: D) -> Result<u32, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    match u32::deserialize(deserializer)? {
+        0 => Err(D::Error::custom(
+            "per_minute must be at least 1, leave [rate_limit] out to not limit requests",
+        )),
+        per_minute => Ok(per_minute),
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct RateLimitConfig {
+    /// Counted before anything else is done with the request
+    #[serde(default = "config_defaults::per_ip")]
+    pub per_ip: Limit,
+
+    /// Counted once we know who the user is
+    #[serde(default = "config_defaults::per_identity")]
+    pub per_identity: Limit,
+}
+
+/// Why the request was turned away, and when to come back
+#[derive(Debug)]
+pub struct TooManyRequests {
+    pub retry_after: u64,
+}
+
+impl reject::Reject for TooManyRequests {}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+struct Buckets {
+    limit: Limit,
+    buckets: HashMap<String, Bucket>,
+    cleaned: Instant,
+}
+
+impl Buckets {
+    fn new(limit: Limit) -> Self {
+        Self {
+            limit,
+            buckets: HashMap::new(),
+            cleaned: Instant::now(),
+        }
+    }
+
+    fn per_second(&self) -> f64 {
+        self.limit.per_minute as f64 / 60.0
+    }
+
+    fn refilled(&self, bucket: &Bucket, now: Instant) -> f64 {
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        (bucket.tokens + elapsed * self.per_second()).min(self.limit.burst as f64)
+    }
+
+    fn retry_after(&self, tokens: f64) -> u64 {
+        ((1.0 - tokens) / self.per_second()).ceil() as u64
+    }
+
+    /// Whether `key`'s bucket has a token left, without taking it
+    fn peek(&self, key: &str, now: Instant) -> Result<(), u64> {
+        match self.buckets.get(key).map(|bucket| self.refilled(bucket, now)) {
+            Some(tokens) if tokens < 1.0 => Err(self.retry_after(tokens)),
+            _ => Ok(()),
+        }
+    }
+
+    /// Takes a token from `key`'s bucket, or tells how many seconds until
+    /// there's one again
+    fn take(&mut self, key: &str, now: Instant) -> Result<(), u64> {
+        // Full buckets are the same as no bucket, no need to keep them around
+        if now.duration_since(self.cleaned) > Duration::from_secs(60) {
+            let buckets = std::mem::take(&mut self.buckets);
+            self.buckets = buckets
+                .into_iter()
+                .filter(|(_, bucket)| self.refilled(bucket, now) < self.limit.burst as f64)
+                .collect();
+            self.cleaned = now;
+        }
+
+        let tokens = match self.buckets.get(key) {
+            Some(bucket) => self.refilled(bucket, now),
+            None => self.limit.burst as f64,
+        };
+        if tokens >= 1.0 {
+            self.buckets.insert(
+                key.to_string(),
+                Bucket {
+                    tokens: tokens - 1.0,
+                    updated: now,
+                },
+            );
+            return Ok(());
+        }
+        Err(self.retry_after(tokens))
+    }
+}
+
+/// Token buckets per client address and per user, so that a single one of
+/// them can't keep hallway busy verifying tokens and rendering pages
+#[derive(Clone)]
+pub struct RateLimiter {
+    per_ip: Arc<Mutex<Buckets>>,
+    per_identity: Arc<Mutex<Buckets>>,
+}
+
+impl RateLimiter {
+    pub fn new(config: &RateLimitConfig) -> Self {
+        Self {
+            per_ip: Arc::new(Mutex::new(Buckets::new(config.per_ip))),
+            per_identity: Arc::new(Mutex::new(Buckets::new(config.per_identity))),
+        }
+    }
+
+    fn limited(limit: &str) -> impl FnOnce(u64) -> Rejection + '_ {
+        move |retry_after| {
+            metrics().rate_limited.with_label_values(&[limit]).inc();
+            reject::custom(TooManyRequests { retry_after })
+        }
+    }
+
+    fn take(buckets: &Mutex<Buckets>, limit: &str, key: &str) -> Result<(), Rejection> {
+        buckets.lock().unwrap().take(key, Instant::now()).map_err(Self::limited(limit))
+    }
+
+    pub fn check_ip(&self, ip: Option<IpAddr>) -> Result<(), Rejection> {
+        let key = ip.map_or_else(|| "unix".to_string(), |ip| ip.to_string());
+        Self::take(&self.per_ip, "ip", &key).inspect_err(|_| debug!("Too many requests from {}", key))
+    }
+
+    /// Turns away whoever the request claims to be when they ran out of
+    /// tokens, before time goes into verifying the claim. Nothing is taken,
+    /// as anyone can claim to be anyone
+    pub fn check_claimed(&self, email: &str) -> Result<(), Rejection> {
+        self.per_identity
+            .lock()
+            .unwrap()
+            .peek(email, Instant::now())
+            .map_err(Self::limited("identity"))
+            .inspect_err(|_| debug!("Too many requests from {}", privacy::email(email)))
+    }
+
+    pub fn check_identity(&self, user: &CurrentUserData) -> Result<(), Rejection> {
+        Self::take(&self.per_identity, "identity", &user.email)
+            .inspect_err(|_| debug!("Too many requests from {}", privacy::email(&user.email)))
+    }
+}
+
+/// Rejects with `TooManyRequests` once the client's address ran out of tokens.
+/// Without a limiter every request goes through
+pub fn per_ip(
+    limiter: Option<RateLimiter>,
+    trusted: Arc<[IpNet]>,
+) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    audit::source(trusted)
+        .and_then(move |ip: Option<IpAddr>| {
+            let limiter = limiter.clone();
+            async move { limiter.map_or(Ok(()), |limiter| limiter.check_ip(ip)) }
+        })
+        .untuple_one()
+}
+
+/// Like `per_ip`, for the user found by `identity`. Users who are out of
+/// tokens are turned away on what `claimed` says, before `identity` runs
+pub fn per_identity<C, F>(
+    limiter: Option<RateLimiter>,
+    claimed: C,
+    identity: F,
+) -> impl Filter<Extract = (CurrentUserData,), Error = Rejection> + Clone
+where
+    C: Filter<Extract = (Option<String>,), Error = Infallible> + Clone,
+    F: Filter<Extract = (CurrentUserData,), Error = Rejection> + Clone + Send,
+{
+    let claimed_limiter = limiter.clone();
+    claimed
+        .and_then(move |claimed: Option<String>| {
+            let limiter = claimed_limiter.clone();
+            async move {
+                match (&limiter, claimed) {
+                    (Some(limiter), Some(email)) => limiter.check_claimed(&email),
+                    _ => Ok(()),
+                }
+            }
+        })
+        .untuple_one()
+        .and(identity)
+        .and_then(move |user: CurrentUserData| {
+            let limiter = limiter.clone();
+            async move {
+                match &limiter {
+                    Some(limiter) => limiter.check_identity(&user).map(|_| user),
+                    None => Ok(user),
+                }
+            }
+        })
+}
@@ -0,0 +1,89 @@
+use std::{
+    future::Future,
+    sync::{Arc, OnceLock},
+    time::Duration,
+};
+
+use aliri::Jwks;
+use tokio::{task, time};
+use tracing::{debug, info};
+use warp::reject;
+
+use crate::{consts, jwt::JwtDecoder, rendering::GlobalData};
+
+/// Everything we can only know once the identity source has answered
+pub struct Discovered {
+    /// Only present for identity sources that verify JWTs
+    pub jwt_decoder: Option<JwtDecoder>,
+    pub global_data: Arc<GlobalData>,
+}
+
+/// Where the keys of a JWT-based identity source live
+pub struct JwtEndpoints {
+    pub jwks_uri: String,
+    pub sign_out_url: String,
+}
+
+/// Rejection used while discovery is still running
+#[derive(Debug)]
+pub struct NotReady;
+
+impl reject::Reject for NotReady {}
+
+/// Shared view of the startup state, hallway starts listening before the identity
+/// provider is reachable and only reports itself as ready once discovery has finished
+#[derive(Clone, Default)]
+pub struct Readiness(Arc<OnceLock<Arc<Discovered>>>);
+
+impl Readiness {
+    pub fn get(&self) -> Option<Arc<Discovered>> {
+        self.0.get().cloned()
+    }
+
+    /// For identity sources that don't need to discover anything
+    pub fn set_ready(&self, discovered: Discovered) {
+        let _ = self.0.set(Arc::new(discovered));
+    }
+
+    /// Obtains the signing keys in the background, retrying until they are
+    /// available. Afterwards keys are refreshed periodically in case they rotate
+    pub fn start_discovery<E, F>(&self, endpoints: E, make_decoder: F)
+    where
+        E: Future<Output = JwtEndpoints> + Send + 'static,
+        F: FnOnce(Jwks) -> JwtDecoder + Send + 'static,
+    {
+        let discovered = self.0.clone();
+        task::spawn(async move {
+            let endpoints = endpoints.await;
+            let keys = JwtDecoder::get_jwks(&endpoints.jwks_uri).await;
+
+            let current = discovered.get_or_init(|| {
+                Arc::new(Discovered {
+                    jwt_decoder: Some(make_decoder(keys)),
+                    global_data: Arc::new(GlobalData {
+                        sign_out_url: endpoints.sign_out_url,
+                    }),
+                })
+            });
+            info!("Discovery finished, hallway is ready");
+
+            let mut interval = time::interval(Duration::from_secs(consts::defaults::JWKS_REFRESH));
+            interval.tick().await; // First tick is immediate, we just got the keys
+            let Some(decoder) = &current.jwt_decoder else {
+                return;
+            };
+            loop {
+                interval.tick().await;
+                decoder.update_keys(JwtDecoder::get_jwks(&endpoints.jwks_uri).await);
+
+                let stats = decoder.cache_stats();
+                debug!(
+                    hits = stats.hits,
+                    misses = stats.misses,
+                    size = stats.size,
+                    "JWT cache stats"
+                );
+            }
+        });
+    }
+}
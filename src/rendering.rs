@@ -1,19 +1,25 @@
 use std::{
-    collections::{HashMap, HashSet},
+    collections::{BTreeMap, HashMap},
     path::Path,
     sync::{Arc, RwLock},
     time::{Duration, SystemTime},
 };
 
 use crate::consts;
+use crate::metrics::metrics;
 use crate::pomerium;
+use crate::rate_limit::TooManyRequests;
+use crate::readiness::NotReady;
 
 use handlebars::Handlebars;
 use serde::Serialize;
 use tokio::{task, time};
-use tracing::{error, trace, warn};
+use tracing::{error, info_span, trace, warn};
 use warp::{http::StatusCode, Rejection};
 
+/// Stands in for the nonce in cached renders
+const NONCE_PLACEHOLDER: &str = "hallway-nonce-placeholder";
+
 #[derive(Clone)]
 struct RenderCacheItem {
     render: String,
@@ -39,46 +45,56 @@ impl RenderCache {
         user_data: &UserDataRender,
         global_data: &GlobalData,
         handlebars: &Arc<Handlebars>,
+        nonce: &str,
     ) -> String {
         #[derive(Clone, Serialize)]
         struct RenderData<'a> {
             user: &'a UserDataRender,
-            global: &'a GlobalData
+            global: &'a GlobalData,
+            nonce: &'static str,
         }
 
         let cached = self
             .dict
             .read()
             .unwrap()
-            .get(&user_data.email)
+            .get(&user_data.cache_key)
             .map(|i| i.render.clone());
+        let result = if cached.is_some() { "hit" } else { "miss" };
+        metrics().render_cache_lookups.with_label_values(&[result]).inc();
 
-        cached.unwrap_or_else(|| {
+        let render = cached.unwrap_or_else(|| {
             trace!("Start rendering");
-            let email = user_data.email.clone();
+            let key = user_data.cache_key.clone();
 
-            let data =  RenderData{user: user_data, global: global_data};
-            let render = handlebars.render("index.html", &data).expect("Failed to render index file");
+            let data =  RenderData{user: user_data, global: global_data, nonce: NONCE_PLACEHOLDER};
+            let render = info_span!("template_render")
+                .in_scope(|| handlebars.render("index.html", &data))
+                .expect("Failed to render index file");
 
             let item = RenderCacheItem {
                 render: render.clone(),
                 time: SystemTime::now()
             };
 
-            self.dict.write().unwrap().insert(email, item);
+            let mut dict = self.dict.write().unwrap();
+            dict.insert(key, item);
+            metrics().render_cache_entries.set(dict.len() as i64);
             render
-        })
+        });
+        // Renders are shared between requests, their nonces aren't
+        render.replace(NONCE_PLACEHOLDER, nonce)
     }
 
     fn clean_old(dict: &Arc<RwLock<HashMap<String, RenderCacheItem>>>) {
-        dict.write()
-            .unwrap()
-            .retain(|_, v|
+        let mut dict = dict.write().unwrap();
+        dict.retain(|_, v|
                 SystemTime::now()
                 .duration_since(v.time)
                 .map(|d|d.as_secs())
                 .unwrap_or_else(|e|{warn!("Somehow got a cache entry in the future, did the clock change? {}", e);consts::defaults::MAX_TIME + 1}) 
-            < consts::defaults::MAX_TIME)
+            < consts::defaults::MAX_TIME);
+        metrics().render_cache_entries.set(dict.len() as i64);
     }
 
     fn start_maintenance(self) {
@@ -93,7 +109,7 @@ impl RenderCache {
     }
 }
 
-#[derive(Clone, Serialize)]
+#[derive(Clone, Default, Serialize)]
 pub struct GlobalData {
     pub sign_out_url: String
 }
@@ -103,49 +119,62 @@ pub struct Renderer<'a> {
     handlebars: Arc<Handlebars<'a>>,
     render_cache: RenderCache,
     user_data_holder: collections::UserDataHolder,
-    global_data: Arc<GlobalData>
 }
 
 impl<'a> Renderer<'a> {
     pub fn from(
         conf_routes: Vec<crate::config::Route>,
         pomerium_data: Vec<pomerium::Route>,
+        canonicalization: pomerium::Canonicalization,
         index_path: &Path,
-        global_data: Arc<GlobalData>
     ) -> Self {
         let mut handlebars = Handlebars::new();
         handlebars
             .register_template_string("index.html", std::fs::read_to_string(index_path).expect("Couldn't read index.html: {}"))
             .expect("Malformed template");
 
-        let emails = Self::extract_emails(&pomerium_data);
         let routes = collections::RouteHolder::from(conf_routes);
         let policies = collections::PolicyHolder::from(pomerium_data);
 
-        let user_data_holder = collections::UserDataHolder::from(emails, &routes, policies);
+        let user_data_holder = collections::UserDataHolder::from(routes, policies, canonicalization);
 
         Self {
             handlebars: Arc::new(handlebars),
             render_cache: RenderCache::new(),
             user_data_holder,
-            global_data
         }
     }
 
-    pub fn render(&mut self, user_data: crate::common::CurrentUserData) -> String {
-        let user_data = self.user_data_holder.get_render(&user_data);
-        trace!("Got user data");
-        self.render_cache
-            .get_or_render(&user_data, &self.global_data, &self.handlebars)
+    /// Names of the templates that were compiled
+    pub fn templates(&self) -> Vec<&str> {
+        self.handlebars.get_templates().keys().map(String::as_str).collect()
+    }
+
+    /// How many hallway routes and pomerium policies were loaded
+    pub fn loaded(&self) -> (usize, usize) {
+        self.user_data_holder.loaded()
     }
 
-    pub fn extract_emails(pomerium_data: &[pomerium::Route]) -> HashSet<String> {
-        pomerium_data
-            .iter()
-            .fold(HashSet::new(), |mut h, r: &pomerium::Route| {
-                r.policy.extract_emails(&mut h);
-                h
+    /// The label and address of a tile the user can see, by its escaped label
+    pub fn launch_target(&self, user: &crate::common::CurrentUserData, escaped_label: &str) -> Option<(String, String)> {
+        fn find(routes: &[crate::config::Route], escaped_label: &str) -> Option<(String, String)> {
+            routes.iter().find_map(|route| match &route.data {
+                crate::config::RouteData::Path(url) if route.escaped_label == escaped_label => {
+                    Some((route.label.clone(), url.clone()))
+                }
+                crate::config::RouteData::Path(_) => None,
+                crate::config::RouteData::Group(group) => find(group, escaped_label),
             })
+        }
+
+        find(&self.user_data_holder.get_render(user).accessible_routes, escaped_label)
+    }
+
+    pub fn render(&mut self, user_data: crate::common::CurrentUserData, global_data: &GlobalData, nonce: &str) -> String {
+        let user_data = self.user_data_holder.get_render(&user_data);
+        trace!("Got user data");
+        self.render_cache
+            .get_or_render(&user_data, global_data, &self.handlebars, nonce)
     }
 }
 
@@ -159,7 +188,25 @@ pub fn render_error(err: Rejection, handlebars: &Arc<Handlebars<'_>>, global_dat
         handlebars.render_template(&html, &data).unwrap_or_else(|e|{error!("Can't render page: {}", e); "Sorry we had an error!".to_string()})
     }
 
-    if err.is_not_found() {
+    if err.find::<NotReady>().is_some() {
+        (
+            load_html_and_render(
+                Path::new(consts::paths::get_html_files_dir()).join("starting.html"),
+                handlebars,
+                global_data,
+            ),
+            StatusCode::SERVICE_UNAVAILABLE,
+        )
+    } else if err.find::<TooManyRequests>().is_some() {
+        (
+            load_html_and_render(
+                Path::new(consts::paths::get_html_files_dir()).join("429.html"),
+                handlebars,
+                global_data,
+            ),
+            StatusCode::TOO_MANY_REQUESTS,
+        )
+    } else if err.is_not_found() {
         (
             load_html_and_render(
                 Path::new(consts::paths::get_html_files_dir()).join("404.html"),
@@ -173,7 +220,7 @@ pub fn render_error(err: Rejection, handlebars: &Arc<Handlebars<'_>>, global_dat
             load_html_and_render(
                 Path::new(consts::paths::get_html_files_dir()).join("50x.html"),
                 handlebars,
-                &global_data,
+                global_data,
             ),
             StatusCode::INTERNAL_SERVER_ERROR,
         )
@@ -185,18 +232,20 @@ pub struct UserDataRender {
     name: String,
     email: String,
     picture: Option<String>,
+    groups: Vec<String>,
+    claims: BTreeMap<String, serde_json::Value>,
     background: String,
     accessible_routes: Vec<crate::config::Route>,
+    /// Hash of everything above, users only share renders when it's all the same
+    #[serde(skip)]
+    cache_key: String,
 }
 
 mod collections {
-    use crate::{config::{self, RouteData}, consts, pomerium};
-    use std::{
-        collections::{HashMap, HashSet},
-        sync::Arc,
-    };
+    use crate::{avatar::Avatars, config::{self, RouteData}, consts, metrics::metrics, pomerium::{self, Canonicalization, PolicyInput}, privacy};
+    use std::{collections::HashMap, sync::Arc};
 
-    use tracing::{info, trace, warn};
+    use tracing::{info, info_span, trace, warn};
 
     pub struct RouteHolder {
         routes: Arc<Vec<Arc<config::Route>>>,
@@ -213,35 +262,44 @@ mod collections {
 
         pub fn can_be_accessed_by(
             &self,
-            email: &str,
+            input: &PolicyInput,
             policy_holder: &PolicyHolder,
         ) -> Vec<Arc<config::Route>> {
-            fn check_route(email: &str, policy_holder: &PolicyHolder, r_data: &RouteData) -> bool {
-                match &r_data {
+            fn check_route(input: &PolicyInput, policy_holder: &PolicyHolder, route: &config::Route) -> bool {
+                // Routes can ask for their own canonicalization, their children inherit it
+                let input = &route
+                    .canonicalization
+                    .map(|c| input.with_canonicalization(c))
+                    .unwrap_or(*input);
+                match &route.data {
                     RouteData::Path(path) => {
                         let res = if let Some(policy) = policy_holder.get(path){
-                            policy.check_authorized(email)
+                            policy.check(input)
                         }
                         else {
                             warn!("Path {} is invalid", &path);
                             false
                         };
-                        trace!(route = path, email = email, authed = res);
+                        trace!(route = path, email = privacy::email(input.email), authed = res);
                         res
                     }
                     RouteData::Group(group) => {
-                        group.iter().any(|r|check_route(email, policy_holder, &r.data))
+                        group.iter().any(|r|check_route(input, policy_holder, r))
                     }
                 }
             }
             self.routes
                 .iter()
                 .filter(|r| {
-                    check_route(email, policy_holder, &r.data)
+                    check_route(input, policy_holder, r)
                 })
                 .cloned()
                 .collect()
         }
+
+        pub fn len(&self) -> usize {
+            self.routes.len()
+        }
     }
 
     #[derive(Debug)]
@@ -251,9 +309,9 @@ mod collections {
 
     impl PolicyHolder {
         pub fn from(pomerium_data: Vec<pomerium::Route>) -> Self {
-            fn make_url(mut from: String, path: &String, prefix: &String) -> String {
-                from.push_str(&path);
-                from.push_str(&prefix);
+            fn make_url(mut from: String, path: &str, prefix: &str) -> String {
+                from.push_str(path);
+                from.push_str(prefix);
                 from
             }
 
@@ -277,66 +335,67 @@ mod collections {
         pub fn get(&self, from: &str) -> Option<&pomerium::Policy> {
             self.dict.get(from)
         }
-    }
 
-    pub struct UserData {
-        accessible_routes: Vec<Arc<config::Route>>,
+        pub fn len(&self) -> usize {
+            self.dict.len()
+        }
     }
 
     #[derive(Clone)]
     pub struct UserDataHolder {
-        dict: Arc<HashMap<String, UserData>>,
-        public_urls: Vec<Arc<config::Route>>
+        routes: Arc<RouteHolder>,
+        policies: Arc<PolicyHolder>,
+        canonicalization: Canonicalization,
     }
 
     impl UserDataHolder {
-        pub fn from(emails: HashSet<String>, routes: &RouteHolder, policies: PolicyHolder) -> Self {
-            let dict = emails
-                .into_iter()
-                .map(|e| {
-                    let e_data = UserData {
-                        accessible_routes: routes.can_be_accessed_by(&e, &policies),
-                    };
-                    trace!(email = e, "routes={:?}", e_data.accessible_routes);
-                    (e, e_data)
-                })
-                .collect::<HashMap<String, UserData>>();
-
-            let public_urls = routes.can_be_accessed_by("", &policies);
+        pub fn from(routes: RouteHolder, policies: PolicyHolder, canonicalization: Canonicalization) -> Self {
             Self {
-                dict: Arc::new(dict),
-                public_urls
+                routes: Arc::new(routes),
+                policies: Arc::new(policies),
+                canonicalization,
             }
         }
 
+        pub fn loaded(&self) -> (usize, usize) {
+            (self.routes.len(), self.policies.len())
+        }
+
         pub fn get_render(
             &self,
             user: &crate::common::CurrentUserData,
         ) -> super::UserDataRender {
-            self.dict.get(&user.email).map(|u| super::UserDataRender {
+            let input = PolicyInput::from(user).with_canonicalization(self.canonicalization);
+            let timer = metrics().policy_duration.start_timer();
+            let accessible_routes = info_span!("policy_evaluation")
+                .in_scope(|| self.routes.can_be_accessed_by(&input, &self.policies));
+            timer.observe_duration();
+            if accessible_routes.is_empty() {
+                info!(
+                    "User '{}' '{}' can't access any route",
+                    privacy::name(&user.name),
+                    privacy::email(&user.email)
+                );
+            }
+
+            let mut render = super::UserDataRender {
                 name: user.name.clone(),
                 email: user.email.clone(),
                 background: consts::defaults::BACKGROUND.to_string(),
-                picture: user.picture.clone(),
-                accessible_routes: u
-                    .accessible_routes
+                picture: Some(Avatars::url_for(user)),
+                groups: user.groups.clone(),
+                claims: user.claims.clone(),
+                accessible_routes: accessible_routes
                     .iter()
                     .map(|r| (**r).clone())
                     .collect::<Vec<_>>(),
-            }).unwrap_or_else(|| {
-                info!("Unregistered user '{}' '{}' has accesed the hallway", &user.name, &user.email);
-
-                super::UserDataRender {
-                    name: user.name.clone(),
-                    email: user.email.clone(),
-                    background: consts::defaults::BACKGROUND.to_string(),
-                    picture: None,
-                    accessible_routes: self
-                        .public_urls
-                        .iter()
-                        .map(|r|(**r).clone())
-                        .collect::<Vec<_>>()
-            }})
+                cache_key: String::new(),
+            };
+            // Whatever made the policies pass, the same data gives the same page.
+            // Anything the template shows can change, so all of it is in the key
+            let data = serde_json::to_vec(&render).expect("Render data is always valid JSON");
+            render.cache_key = openssl::sha::sha256(&data).iter().map(|b| format!("{:02x}", b)).collect();
+            render
         }
     }
 }
@@ -0,0 +1,174 @@
+use std::{
+    convert::Infallible,
+    future::Future,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+use serde::Deserialize;
+use tower_service::Service;
+use tracing::warn;
+use warp::{
+    http::{header, HeaderMap, HeaderName, HeaderValue, Response},
+    hyper::{body::Incoming, Request},
+    Filter,
+};
+
+use crate::session;
+
+mod config_defaults {
+    pub fn content_security_policy() -> String {
+        crate::consts::defaults::CONTENT_SECURITY_POLICY.to_string()
+    }
+
+    pub fn referrer_policy() -> String {
+        crate::consts::defaults::REFERRER_POLICY.to_string()
+    }
+
+    pub fn permissions_policy() -> String {
+        crate::consts::defaults::PERMISSIONS_POLICY.to_string()
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct SecurityConfig {
+    /// `{nonce}` is replaced by the request's nonce, which the templates get
+    /// as `{{nonce}}`
+    #[serde(default = "config_defaults::content_security_policy")]
+    pub content_security_policy: String,
+
+    #[serde(default = "config_defaults::referrer_policy")]
+    pub referrer_policy: String,
+
+    #[serde(default = "config_defaults::permissions_policy")]
+    pub permissions_policy: String,
+
+    /// Browsers only come back over HTTPS for this many seconds. Only sent when
+    /// set, as it's hard to take back
+    #[serde(default)]
+    pub hsts_max_age: Option<u64>,
+
+    #[serde(default)]
+    pub hsts_include_subdomains: bool,
+}
+
+impl Default for SecurityConfig {
+    fn default() -> Self {
+        Self {
+            content_security_policy: config_defaults::content_security_policy(),
+            referrer_policy: config_defaults::referrer_policy(),
+            permissions_policy: config_defaults::permissions_policy(),
+            hsts_max_age: None,
+            hsts_include_subdomains: false,
+        }
+    }
+}
+
+/// Lets the inline scripts of a single response run
+#[derive(Clone, Debug)]
+pub struct Nonce(pub Arc<str>);
+
+impl Nonce {
+    fn new() -> Self {
+        Self(Arc::from(session::random_token(16)))
+    }
+}
+
+/// The nonce the response's policy allows, for the templates
+pub fn nonce() -> impl Filter<Extract = (Nonce,), Error = Infallible> + Clone {
+    warp::ext::optional::<Nonce>().map(|nonce: Option<Nonce>| nonce.unwrap_or_else(Nonce::new))
+}
+
+/// Headers that are the same for every response, and the policy still missing
+/// its nonce
+struct Headers {
+    fixed: HeaderMap,
+    content_security_policy: String,
+}
+
+impl Headers {
+    fn from(config: &SecurityConfig) -> Self {
+        let value = |name: &str, value: &str| {
+            HeaderValue::from_str(value).unwrap_or_else(|_| panic!("Not a valid {} header: {}", name, value))
+        };
+        let mut fixed = HeaderMap::new();
+        fixed.insert(header::X_CONTENT_TYPE_OPTIONS, HeaderValue::from_static("nosniff"));
+        fixed.insert(header::REFERRER_POLICY, value("Referrer-Policy", &config.referrer_policy));
+        fixed.insert(
+            HeaderName::from_static("permissions-policy"),
+            value("Permissions-Policy", &config.permissions_policy),
+        );
+        if let Some(max_age) = config.hsts_max_age {
+            let hsts = match config.hsts_include_subdomains {
+                true => format!("max-age={}; includeSubDomains", max_age),
+                false => format!("max-age={}", max_age),
+            };
+            fixed.insert(header::STRICT_TRANSPORT_SECURITY, value("Strict-Transport-Security", &hsts));
+        }
+        if !config.content_security_policy.contains("{nonce}") {
+            warn!("The content security policy has no {{nonce}}, inline scripts won't run");
+        }
+        // Checked now rather than on the first request
+        value("Content-Security-Policy", &config.content_security_policy);
+
+        Self {
+            fixed,
+            content_security_policy: config.content_security_policy.clone(),
+        }
+    }
+}
+
+/// Gives every request a nonce, and adds the security headers to its response
+#[derive(Clone)]
+pub struct Secured<S> {
+    inner: S,
+    headers: Arc<Headers>,
+}
+
+impl<S> Secured<S> {
+    pub fn new(inner: S, config: &SecurityConfig) -> Self {
+        Self {
+            inner,
+            headers: Arc::new(Headers::from(config)),
+        }
+    }
+}
+
+impl<S, B> Service<Request<Incoming>> for Secured<S>
+where
+    S: Service<Request<Incoming>, Response = Response<B>, Error = Infallible>,
+    S::Future: Send + 'static,
+{
+    type Response = Response<B>;
+    type Error = Infallible;
+    type Future = Pin<Box<dyn Future<Output = Result<Response<B>, Infallible>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Infallible>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut req: Request<Incoming>) -> Self::Future {
+        let nonce = Nonce::new();
+        req.extensions_mut().insert(nonce.clone());
+        let headers = self.headers.clone();
+        let response = self.inner.call(req);
+        Box::pin(async move {
+            let mut response = response.await?;
+            let response_headers = response.headers_mut();
+            for (name, value) in &headers.fixed {
+                if !response_headers.contains_key(name) {
+                    response_headers.insert(name, value.clone());
+                }
+            }
+            if !response_headers.contains_key(header::CONTENT_SECURITY_POLICY) {
+                let policy = headers.content_security_policy.replace("{nonce}", &nonce.0);
+                response_headers.insert(
+                    header::CONTENT_SECURITY_POLICY,
+                    HeaderValue::from_str(&policy).expect("Checked when loading the config"),
+                );
+            }
+            Ok(response)
+        })
+    }
+}
@@ -0,0 +1,169 @@
+use std::{convert::Infallible, future::Future, io, net::SocketAddr, sync::Arc, time::Duration};
+
+use hyper_util::{
+    rt::{TokioExecutor, TokioIo},
+    server::{
+        conn::auto,
+        graceful::{GracefulShutdown, Watcher},
+    },
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::{TcpListener, TcpStream, UnixListener, UnixStream},
+};
+use tokio_rustls::TlsAcceptor;
+use tower_service::Service;
+use tracing::{debug, warn};
+use warp::hyper::{
+    body::{Body, Incoming},
+    service::service_fn,
+    Request, Response,
+};
+
+use crate::consts;
+
+/// Address of the peer that opened the connection, available to filters through
+/// `warp::ext`. Warp doesn't give us this on its own anymore. Connections over
+/// Unix sockets don't have one.
+#[derive(Clone, Copy, Debug)]
+pub struct RemoteAddr(pub SocketAddr);
+
+/// DER certificate the client presented during the TLS handshake, only there
+/// when we terminate TLS ourselves and it was verified against the client CAs
+#[derive(Clone, Debug)]
+pub struct PeerCertificate(pub Arc<[u8]>);
+
+/// Present when the request came over TLS we terminated ourselves
+#[derive(Clone, Copy, Debug)]
+pub struct Tls;
+
+/// Present when the request came over one of our Unix sockets
+#[derive(Clone, Copy, Debug)]
+pub struct UnixSocket;
+
+/// Somewhere connections come from
+pub enum Listener {
+    Tcp(TcpListener),
+    Unix(UnixListener),
+}
+
+enum Accepted {
+    Tcp(TcpStream, SocketAddr),
+    Unix(UnixStream),
+}
+
+impl Listener {
+    async fn accept(&self) -> io::Result<Accepted> {
+        match self {
+            Listener::Tcp(listener) => listener.accept().await.map(|(s, addr)| Accepted::Tcp(s, addr)),
+            Listener::Unix(listener) => listener.accept().await.map(|(s, _)| Accepted::Unix(s)),
+        }
+    }
+}
+
+/// Serves `service` on `listener` until `shutdown` resolves, then waits for the
+/// open connections to finish. TCP connections go through `tls` when there's one
+pub async fn serve<S, B, Fut>(service: S, listener: Listener, tls: Option<TlsAcceptor>, shutdown: Fut)
+where
+    S: Service<Request<Incoming>, Response = Response<B>, Error = Infallible> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+    B: Body + Send + 'static,
+    B::Data: Send,
+    B::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
+    Fut: Future<Output = ()>,
+{
+    let graceful = GracefulShutdown::new();
+    let mut shutdown = std::pin::pin!(shutdown);
+
+    loop {
+        let accepted = tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    // Most likely we ran out of file descriptors, give it some time
+                    warn!("Couldn't accept connection: {}", e);
+                    tokio::time::sleep(Duration::from_secs(1)).await;
+                    continue;
+                }
+            },
+            _ = &mut shutdown => {
+                debug!("Shutdown signal received, waiting for connections to finish");
+                break;
+            }
+        };
+
+        let watcher = graceful.watcher();
+        match accepted {
+            Accepted::Tcp(stream, addr) => {
+                tokio::spawn(connection(stream, Some(addr), tls.clone(), service.clone(), watcher));
+            }
+            Accepted::Unix(stream) => {
+                tokio::spawn(connection(stream, None, None, service.clone(), watcher));
+            }
+        }
+    }
+
+    drop(listener);
+    graceful.shutdown().await;
+}
+
+async fn connection<I, S, B>(stream: I, addr: Option<SocketAddr>, tls: Option<TlsAcceptor>, service: S, watcher: Watcher)
+where
+    I: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    S: Service<Request<Incoming>, Response = Response<B>, Error = Infallible> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+    B: Body + Send + 'static,
+    B::Data: Send,
+    B::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    let peer = addr.map_or_else(|| "unix socket peer".to_string(), |a| a.to_string());
+    let svc = |tls: bool, peer_certificate: Option<PeerCertificate>| {
+        service_fn(move |mut req: Request<Incoming>| {
+            if let Some(addr) = addr {
+                req.extensions_mut().insert(RemoteAddr(addr));
+            } else {
+                req.extensions_mut().insert(UnixSocket);
+            }
+            if tls {
+                req.extensions_mut().insert(Tls);
+            }
+            if let Some(cert) = &peer_certificate {
+                req.extensions_mut().insert(cert.clone());
+            }
+            service.clone().call(req)
+        })
+    };
+    let builder = auto::Builder::new(TokioExecutor::new());
+
+    let Some(tls) = tls else {
+        let conn = builder.serve_connection_with_upgrades(TokioIo::new(stream), svc(false, None));
+        if let Err(e) = watcher.watch(conn).await {
+            debug!("Connection with {} failed: {:?}", peer, e);
+        }
+        return;
+    };
+
+    // The handshake happens here so that slow clients don't hold up the others
+    let handshake = Duration::from_secs(consts::defaults::TLS_HANDSHAKE_TIMEOUT);
+    let stream = match tokio::time::timeout(handshake, tls.accept(stream)).await {
+        Ok(Ok(stream)) => stream,
+        Ok(Err(e)) => {
+            debug!("TLS handshake with {} failed: {}", peer, e);
+            return;
+        }
+        Err(_) => {
+            debug!("TLS handshake with {} timed out", peer);
+            return;
+        }
+    };
+    let peer_certificate = stream
+        .get_ref()
+        .1
+        .peer_certificates()
+        .and_then(|certs| certs.first())
+        .map(|cert| PeerCertificate(Arc::from(cert.as_ref())));
+    let conn = builder.serve_connection_with_upgrades(TokioIo::new(stream), svc(true, peer_certificate));
+    if let Err(e) = watcher.watch(conn).await {
+        debug!("Connection with {} failed: {:?}", peer, e);
+    }
+}
@@ -0,0 +1,193 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use openssl::{
+    rand::rand_bytes,
+    symm::{decrypt_aead, encrypt_aead, Cipher},
+};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use tracing::warn;
+use warp::{http::HeaderMap, Filter};
+
+use crate::{consts, server::Tls};
+
+/// Cookie where the user's identity is kept
+pub const SESSION_COOKIE: &str = "hallway_session";
+
+const KEY_LEN: usize = 32;
+const IV_LEN: usize = 12;
+const TAG_LEN: usize = 16;
+
+fn default_lifetime() -> u64 {
+    consts::defaults::SESSION_LIFETIME
+}
+
+/// Settings shared by the identity sources that keep their own sessions
+#[derive(Debug, Clone, Deserialize)]
+pub struct SessionConfig {
+    /// 32 bytes in base64 used to encrypt sessions. Without it a new key is made
+    /// on every start, logging everyone out
+    #[serde(default)]
+    pub session_key: Option<String>,
+
+    /// Seconds until users have to log in again
+    #[serde(default = "default_lifetime")]
+    pub session_lifetime: u64,
+
+    /// Whether cookies are only sent back over HTTPS. Unset, they are when the
+    /// domain is https or the request came over HTTPS, to us or to the proxy
+    /// in front. Browsers drop them over plain HTTP otherwise
+    #[serde(default)]
+    pub secure_cookies: Option<bool>,
+}
+
+impl Default for SessionConfig {
+    fn default() -> Self {
+        Self {
+            session_key: None,
+            session_lifetime: default_lifetime(),
+            secure_cookies: None,
+        }
+    }
+}
+
+impl SessionConfig {
+    pub fn key(&self) -> SessionKey {
+        match &self.session_key {
+            Some(key) => SessionKey::from_base64(key).expect("session_key must be 32 bytes in base64"),
+            None => {
+                warn!("No session_key configured, sessions won't survive a restart");
+                SessionKey::generate()
+            }
+        }
+    }
+
+    pub fn lifetime(&self) -> Duration {
+        Duration::from_secs(self.session_lifetime)
+    }
+
+    /// Whether the cookies given in answer to a request get `Secure`
+    pub fn secure_cookies(&self, domain: &str) -> impl Filter<Extract = (bool,), Error = warp::Rejection> + Clone {
+        let forced = self.secure_cookies.or(domain.starts_with("https://").then_some(true));
+        warp::ext::optional::<Tls>()
+            .and(warp::header::optional::<String>("x-forwarded-proto"))
+            .map(move |tls: Option<Tls>, proto: Option<String>| {
+                forced.unwrap_or_else(|| tls.is_some() || proto.is_some_and(|p| p.eq_ignore_ascii_case("https")))
+            })
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct Sealed<T> {
+    exp: u64,
+    value: T,
+}
+
+/// Key for the cookies hallway hands out. Values are encrypted with AES-256-GCM,
+/// so they can be neither read nor tampered with by the browser
+#[derive(Clone)]
+pub struct SessionKey([u8; KEY_LEN]);
+
+impl SessionKey {
+    /// A fresh key, sessions won't survive a restart
+    pub fn generate() -> Self {
+        let mut key = [0; KEY_LEN];
+        rand_bytes(&mut key).expect("Couldn't generate session key");
+        Self(key)
+    }
+
+    /// Keys are given as 32 bytes in base64
+    pub fn from_base64(encoded: &str) -> Option<Self> {
+        let decoded = URL_SAFE_NO_PAD
+            .decode(encoded.trim().trim_end_matches('='))
+            .or_else(|_| base64::engine::general_purpose::STANDARD.decode(encoded.trim()))
+            .ok()?;
+        Some(Self(decoded.try_into().ok()?))
+    }
+
+    /// Encrypts `value` for `ttl`. `purpose` has to match when opening, so that
+    /// one kind of cookie can't be passed as another
+    pub fn seal<T: Serialize>(&self, purpose: &str, value: &T, ttl: Duration) -> String {
+        let exp = now() + ttl.as_secs();
+        let plain = serde_json::to_vec(&Sealed { exp, value }).expect("Sealed values are always serializable");
+
+        let iv = random_bytes(IV_LEN);
+        let mut tag = [0; TAG_LEN];
+        let encrypted = encrypt_aead(
+            Cipher::aes_256_gcm(),
+            &self.0,
+            Some(&iv),
+            purpose.as_bytes(),
+            &plain,
+            &mut tag,
+        )
+        .expect("Couldn't encrypt session");
+
+        URL_SAFE_NO_PAD.encode([iv.as_slice(), &encrypted, &tag].concat())
+    }
+
+    /// Decrypts a sealed value, `None` if it was tampered with or has expired
+    pub fn open<T: DeserializeOwned>(&self, purpose: &str, sealed: &str) -> Option<T> {
+        let raw = URL_SAFE_NO_PAD.decode(sealed).ok()?;
+        if raw.len() < IV_LEN + TAG_LEN {
+            return None;
+        }
+        let (iv, rest) = raw.split_at(IV_LEN);
+        let (encrypted, tag) = rest.split_at(rest.len() - TAG_LEN);
+
+        let plain = decrypt_aead(
+            Cipher::aes_256_gcm(),
+            &self.0,
+            Some(iv),
+            purpose.as_bytes(),
+            encrypted,
+            tag,
+        )
+        .ok()?;
+        let sealed: Sealed<T> = serde_json::from_slice(&plain).ok()?;
+        (sealed.exp > now()).then_some(sealed.value)
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("Time went backwards")
+        .as_secs()
+}
+
+/// Random bytes in url-safe base64, for states, nonces and the like
+pub fn random_token(len: usize) -> String {
+    URL_SAFE_NO_PAD.encode(random_bytes(len))
+}
+
+fn random_bytes(len: usize) -> Vec<u8> {
+    let mut bytes = vec![0; len];
+    rand_bytes(&mut bytes).expect("Couldn't generate random bytes");
+    bytes
+}
+
+pub fn get_cookie<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+    headers
+        .get_all(warp::http::header::COOKIE)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(';'))
+        .filter_map(|c| c.trim().split_once('='))
+        .find_map(|(k, v)| (k == name).then_some(v))
+}
+
+/// `Set-Cookie` value for a cookie only hallway gets to see
+pub fn set_cookie(name: &str, value: &str, max_age: Duration, secure: bool) -> String {
+    format!(
+        "{}={}; Path=/; Max-Age={}; HttpOnly;{} SameSite=Lax",
+        name,
+        value,
+        max_age.as_secs(),
+        if secure { " Secure;" } else { "" }
+    )
+}
+
+pub fn clear_cookie(name: &str, secure: bool) -> String {
+    set_cookie(name, "", Duration::ZERO, secure)
+}
@@ -0,0 +1,87 @@
+use std::env;
+
+use opentelemetry::{
+    propagation::{Extractor, TextMapPropagator},
+    trace::TracerProvider,
+    Context,
+};
+use opentelemetry_otlp::{Protocol, SpanExporter, WithExportConfig};
+use opentelemetry_sdk::{propagation::TraceContextPropagator, trace::SdkTracerProvider, Resource};
+use tracing::Subscriber;
+use tracing_opentelemetry::OpenTelemetryLayer;
+use tracing_subscriber::registry::LookupSpan;
+use warp::http::HeaderMap;
+
+/// First of the variables that is set, the signal specific ones win over the
+/// general ones like the spec says
+fn var(names: &[&str]) -> Option<String> {
+    names.iter().find_map(|name| env::var(name).ok()).filter(|v| !v.is_empty())
+}
+
+/// Exports spans over OTLP when a collector was given through the standard
+/// `OTEL_EXPORTER_OTLP_*` variables. The exporter reads the endpoint, headers
+/// and timeout from them itself, and the SDK reads the sampler
+pub fn tracer_provider() -> Option<SdkTracerProvider> {
+    if var(&["OTEL_SDK_DISABLED"]).is_some_and(|v| v.eq_ignore_ascii_case("true"))
+        || var(&["OTEL_TRACES_EXPORTER"]).is_some_and(|v| v != "otlp")
+    {
+        return None;
+    }
+    var(&["OTEL_EXPORTER_OTLP_TRACES_ENDPOINT", "OTEL_EXPORTER_OTLP_ENDPOINT"])?;
+
+    let protocol = var(&["OTEL_EXPORTER_OTLP_TRACES_PROTOCOL", "OTEL_EXPORTER_OTLP_PROTOCOL"]);
+    let exporter = match protocol.as_deref().unwrap_or("http/protobuf") {
+        "grpc" => SpanExporter::builder().with_tonic().build(),
+        "http/protobuf" => SpanExporter::builder()
+            .with_http()
+            .with_protocol(Protocol::HttpBinary)
+            .build(),
+        "http/json" => SpanExporter::builder()
+            .with_http()
+            .with_protocol(Protocol::HttpJson)
+            .build(),
+        other => panic!("Unsupported OTLP protocol {}", other),
+    }
+    .expect("Couldn't set up the OTLP exporter");
+
+    // Unless told otherwise, we are hallway rather than `unknown_service`
+    let named = var(&["OTEL_SERVICE_NAME"]).is_some()
+        || var(&["OTEL_RESOURCE_ATTRIBUTES"]).is_some_and(|a| a.contains("service.name="));
+    let resource = match named {
+        true => Resource::builder().build(),
+        false => Resource::builder().with_service_name(env!("CARGO_PKG_NAME")).build(),
+    };
+
+    Some(
+        SdkTracerProvider::builder()
+            .with_resource(resource)
+            .with_batch_exporter(exporter)
+            .build(),
+    )
+}
+
+/// Turns the spans into OpenTelemetry ones for `provider`
+pub fn layer<S>(provider: &SdkTracerProvider) -> OpenTelemetryLayer<S, opentelemetry_sdk::trace::Tracer>
+where
+    S: Subscriber + for<'span> LookupSpan<'span>,
+{
+    tracing_opentelemetry::layer().with_tracer(provider.tracer(env!("CARGO_PKG_NAME")))
+}
+
+struct Headers<'a>(&'a HeaderMap);
+
+impl Extractor for Headers<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|v| v.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(|k| k.as_str()).collect()
+    }
+}
+
+/// The trace a proxy in front (Pomerium, for one) started for the request, from
+/// its W3C `traceparent` and `tracestate`
+pub fn parent_context(headers: &HeaderMap) -> Context {
+    TraceContextPropagator::new().extract(&Headers(headers))
+}
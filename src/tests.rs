@@ -1,4 +1,12 @@
-use crate::pomerium;
+use crate::{
+    avatar::{self, Avatars},
+    claims::ClaimMapping,
+    common::CurrentUserData,
+    identity::{IdentityError, IdentitySource, RequestInfo, TrustedHeaders},
+    jwt::VerifiedCache,
+    pomerium, server,
+};
+use warp::Filter;
 
 #[test]
 fn simple_conf() {
@@ -42,4 +50,2408 @@ fn allow_public_unauthenticated_access_allows() {
         pomerium::load_from_str(SIMPLE_CONF).routes[0].policy.check_authorized("myemail@place.com"), 
         true
     );
-}
\ No newline at end of file
+}
+
+fn test_user(email: &str) -> CurrentUserData {
+    CurrentUserData {
+        subject: None,
+        email: email.to_string(),
+        name: "Test User".to_string(),
+        picture: None,
+        groups: Vec::new(),
+        claims: Default::default(),
+        client_certificate: None,
+    }
+}
+
+#[test]
+fn verified_cache_honours_expiry() {
+    let cache = VerifiedCache::new(10);
+    cache.insert("token", test_user("myemail@place.com"), 100, 0);
+
+    assert_eq!(cache.get("token", 50).map(|u| u.email).as_deref(), Some("myemail@place.com"));
+    assert!(cache.get("token", 100).is_none());
+    assert!(cache.get("other_token", 50).is_none());
+
+    let stats = cache.stats();
+    assert_eq!((stats.hits, stats.misses, stats.size), (1, 2, 1));
+}
+
+#[test]
+fn verified_cache_is_bounded() {
+    let cache = VerifiedCache::new(2);
+    cache.insert("expires_soon", test_user("a@place.com"), 10, 0);
+    cache.insert("expires_late", test_user("b@place.com"), 1000, 0);
+    cache.insert("new", test_user("c@place.com"), 500, 0);
+
+    assert_eq!(cache.stats().size, 2);
+    assert!(cache.get("expires_soon", 1).is_none());
+    assert!(cache.get("expires_late", 1).is_some());
+    assert!(cache.get("new", 1).is_some());
+
+    cache.clear();
+    assert!(cache.get("new", 1).is_none());
+}
+
+fn claims_from(json: serde_json::Value) -> serde_json::Map<String, serde_json::Value> {
+    json.as_object().unwrap().clone()
+}
+
+#[test]
+fn claim_mapping_uses_fallbacks() {
+    let mapping = ClaimMapping::default();
+    let user = mapping
+        .map(&claims_from(serde_json::json!({
+            "email": "myemail@place.com",
+            "given_name": "My",
+            "family_name": "Name",
+            "picture": "https://pictures.place.com/me.png",
+            "groups": ["admins", "family"],
+        })))
+        .unwrap();
+
+    assert_eq!(user.name, "My Name");
+    assert_eq!(user.picture.as_deref(), Some("https://pictures.place.com/me.png"));
+    assert_eq!(user.groups, vec!["admins", "family"]);
+
+    let groups = |groups| {
+        let claims = serde_json::json!({"email": "myemail@place.com", "groups": groups});
+        mapping.map(&claims_from(claims)).unwrap().groups
+    };
+    assert_eq!(groups(serde_json::json!("admins, family")), vec!["admins", "family"]);
+    assert_eq!(groups(serde_json::json!(["admins,ops", " family ", ""])), vec!["admins", "ops", "family"]);
+
+    // Service accounts might have nothing but an email
+    let user = mapping
+        .map(&claims_from(serde_json::json!({"email": "robot@place.com"})))
+        .unwrap();
+    assert_eq!(user.name, "robot@place.com");
+    assert!(mapping.map(&claims_from(serde_json::json!({"name": "No Email"}))).is_none());
+}
+
+#[test]
+fn claim_mapping_from_config() {
+    let mapping: ClaimMapping = toml::from_str(
+        r#"
+        email = ["mail", "email"]
+        name = ["preferred_username"]
+        [extra]
+        department = ["dept", "ou"]
+        "#,
+    )
+    .unwrap();
+    let user = mapping
+        .map(&claims_from(serde_json::json!({
+            "mail": "myemail@place.com",
+            "preferred_username": "me",
+            "ou": "Ops",
+        })))
+        .unwrap();
+
+    assert_eq!(user.email, "myemail@place.com");
+    assert_eq!(user.name, "me");
+    assert_eq!(user.claims["department"], "Ops");
+}
+
+#[test]
+fn initials_avatar_is_deterministic() {
+    let first = avatar::avatar_text(&avatar::initials_avatar("my <name>", "myemail@place.com"));
+    let second = avatar::avatar_text(&avatar::initials_avatar("my <name>", "MyEmail@place.com"));
+
+    assert_eq!(first, second);
+    assert!(first.contains(">MN</text>"));
+    assert!(avatar::avatar_text(&avatar::initials_avatar("", "robot@place.com")).contains(">R</text>"));
+}
+
+#[tokio::test]
+async fn avatar_picture_is_proxied_and_resized() {
+    let mut png = std::io::Cursor::new(Vec::new());
+    image::DynamicImage::new_rgb8(300, 200)
+        .write_to(&mut png, image::ImageFormat::Png)
+        .unwrap();
+    let png = png.into_inner();
+    // Small to download, but it would take a lot of memory to decode
+    let mut wide = std::io::Cursor::new(Vec::new());
+    image::DynamicImage::new_luma8(20_000, 1)
+        .write_to(&mut wide, image::ImageFormat::Png)
+        .unwrap();
+    let wide = wide.into_inner();
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let downloads = std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0));
+    let counted = downloads.clone();
+    let route = warp::path!("me.png")
+        .map(move || {
+            counted.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            png.clone()
+        })
+        .or(warp::path!("huge.png").map(|| vec![0u8; 6 * 1024 * 1024]))
+        .or(warp::path!("wide.png").map(move || wide.clone()));
+    tokio::spawn(warp::serve(route).incoming(listener).run());
+
+    // Claims can't point hallway at what runs next to it
+    let mut user = test_user("myemail@place.com");
+    for picture in [
+        format!("http://{}/me.png", addr),
+        format!("http://localhost:{}/me.png", addr.port()),
+        "http://169.254.169.254/latest/meta-data".to_string(),
+        "http://[::1]/me.png".to_string(),
+        "file:///etc/passwd".to_string(),
+    ] {
+        user.picture = Some(picture);
+        let resp = Avatars::new().get(&user).await.into_response();
+        assert_eq!(resp.headers()["content-type"], "image/svg+xml");
+    }
+
+    // Users opening the launcher at once share the download
+    let avatars = Avatars::allowing_internal();
+    user.picture = Some(format!("http://{}/me.png", addr));
+    let (first, second, third) = tokio::join!(avatars.get(&user), avatars.get(&user), avatars.get(&user));
+    assert_eq!(downloads.load(std::sync::atomic::Ordering::SeqCst), 1);
+    for avatar in [first, second, third] {
+        let resp = avatar.into_response();
+        assert_eq!(resp.headers()["content-type"], "image/png");
+        let resized = image::load_from_memory(resp.body()).unwrap();
+        assert_eq!((resized.width(), resized.height()), (128, 128));
+    }
+
+    // Unreachable pictures fall back to the generated avatar
+    user.picture = Some(format!("http://{}/missing.png", addr));
+    let resp = avatars.get(&user).await.into_response();
+    assert_eq!(resp.headers()["content-type"], "image/svg+xml");
+
+    // So do pictures too big to bother with
+    for picture in ["huge.png", "wide.png"] {
+        user.picture = Some(format!("http://{}/{}", addr, picture));
+        let resp = avatars.get(&user).await.into_response();
+        assert_eq!(resp.headers()["content-type"], "image/svg+xml");
+    }
+}
+
+fn request_from(remote: &str, headers: &[(&'static str, &'static str)]) -> RequestInfo {
+    let mut map = warp::http::HeaderMap::new();
+    headers.iter().for_each(|(k, v)| {
+        map.append(*k, warp::http::HeaderValue::from_static(v));
+    });
+    RequestInfo {
+        headers: map,
+        remote: Some(remote.parse().unwrap()),
+        unix_socket: false,
+        peer_certificate: None,
+    }
+}
+
+#[test]
+fn trusted_headers_only_from_trusted_proxies() {
+    let source = TrustedHeaders::new(vec!["10.0.0.0/8".parse().unwrap()], Default::default());
+    let headers = [
+        ("Remote-User", "me"),
+        ("Remote-Email", "myemail@place.com"),
+        ("Remote-Groups", "admins,family"),
+    ];
+
+    let user = source.identify(&request_from("10.1.2.3:4567", &headers)).unwrap();
+    assert_eq!(user.email, "myemail@place.com");
+    assert_eq!(user.name, "me");
+    assert_eq!(user.groups, vec!["admins", "family"]);
+
+    // IPv4 clients reaching a dual stack socket
+    assert!(source.identify(&request_from("[::ffff:10.1.2.3]:4567", &headers)).is_ok());
+
+    assert_eq!(
+        source.identify(&request_from("192.168.1.2:4567", &headers)).err(),
+        Some(IdentityError::Untrusted)
+    );
+    assert_eq!(
+        source.identify(&request_from("10.1.2.3:4567", &[])).err(),
+        Some(IdentityError::Missing)
+    );
+}
+
+#[test]
+fn trusted_headers_authentik_groups() {
+    let source = TrustedHeaders::new(vec!["127.0.0.1/32".parse().unwrap()], Default::default());
+    let user = source
+        .identify(&request_from(
+            "127.0.0.1:4567",
+            &[
+                ("X-Forwarded-User", "myemail@place.com"),
+                ("X-Authentik-Groups", "admins|family"),
+            ],
+        ))
+        .unwrap();
+
+    assert_eq!(user.email, "myemail@place.com");
+    assert_eq!(user.groups, vec!["admins", "family"]);
+}
+
+#[tokio::test]
+async fn server_exposes_remote_address() {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let route = warp::ext::get::<server::RemoteAddr>().map(|r: server::RemoteAddr| r.0.ip().to_string());
+    tokio::spawn(server::serve(warp::service(route), server::Listener::Tcp(listener), None, std::future::pending()));
+
+    let body = reqwest::get(format!("http://{}/", addr)).await.unwrap().text().await.unwrap();
+    assert_eq!(body, "127.0.0.1");
+}
+
+fn config_from(identity: &str) -> crate::config::Config {
+    toml::from_str(&format!(
+        "routes = []\n[domain]\nname = \"https://hallway.example.com\"\n{}",
+        identity
+    ))
+    .unwrap()
+}
+
+async fn wait_ready(readiness: &crate::readiness::Readiness) {
+    for _ in 0..100 {
+        if readiness.get().is_some() {
+            return;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+    }
+    panic!("Discovery never finished");
+}
+
+/// Signs tokens like an identity provider would, the keys can be served as a JWKS
+struct TestSigner {
+    key: aliri::Jwk,
+    jwks: aliri::Jwks,
+}
+
+impl TestSigner {
+    fn new() -> Self {
+        use aliri::{jwa, jwk::KeyId, Jwk};
+
+        let rsa = jwa::Rsa::generate().unwrap();
+        let mut jwks = aliri::Jwks::default();
+        jwks.add_key(
+            Jwk::from(rsa.clone().public_only())
+                .with_key_id(KeyId::from("test-key"))
+                .with_algorithm(jwa::Algorithm::RS256),
+        );
+        let key = Jwk::from(rsa)
+            .with_key_id(KeyId::from("test-key"))
+            .with_algorithm(jwa::Algorithm::RS256);
+        Self { key, jwks }
+    }
+
+    fn mint(&self, payload: serde_json::Value) -> String {
+        use aliri::{jwa, jwk::KeyId, jwt};
+
+        let headers = jwt::BasicHeaders::with_key_id(jwa::Algorithm::RS256, KeyId::from("test-key"));
+        jwt::Jwt::try_from_parts_with_signature(&headers, &payload, &self.key)
+            .unwrap()
+            .as_str()
+            .to_string()
+    }
+}
+
+fn unix_now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+async fn serve_locally<F>(route: F) -> std::net::SocketAddr
+where
+    F: Filter + Clone + Send + Sync + 'static,
+    F::Extract: warp::Reply,
+{
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(warp::serve(route).incoming(listener).run());
+    addr
+}
+
+#[tokio::test]
+async fn cloudflare_access_tokens() {
+    let signer = TestSigner::new();
+    let jwks = signer.jwks.clone();
+    let addr = serve_locally(
+        warp::path!("cdn-cgi" / "access" / "certs").map(move || warp::reply::json(&jwks)),
+    )
+    .await;
+
+    let config = config_from(&format!(
+        r#"
+        [identity]
+        source = "cloudflare_access"
+        [identity.cloudflare]
+        team_domain = "myteam"
+        audiences = ["aud-tag"]
+        certs_url = "http://{}/cdn-cgi/access/certs"
+        "#,
+        addr
+    ));
+    let readiness = crate::readiness::Readiness::default();
+    let source = crate::identity::from_config(&config, &readiness);
+    wait_ready(&readiness).await;
+    assert_eq!(readiness.get().unwrap().global_data.sign_out_url, "/cdn-cgi/access/logout");
+
+    let now = unix_now();
+    let mint = |aud: &str| {
+        signer.mint(serde_json::json!({
+            "aud": [aud],
+            "iss": "https://myteam.cloudflareaccess.com",
+            "sub": "1234",
+            "email": "someone@example.com",
+            "exp": now + 600,
+            "nbf": now - 10,
+            "custom": { "name": "Some One", "email": "spoofed@example.com" },
+        }))
+    };
+
+    let request = |token: String| RequestInfo {
+        headers: [(
+            warp::http::HeaderName::from_static("cf-access-jwt-assertion"),
+            warp::http::HeaderValue::from_str(&token).unwrap(),
+        )]
+        .into_iter()
+        .collect(),
+        remote: None,
+        unix_socket: false,
+        peer_certificate: None,
+    };
+
+    let user = source.identify(&request(mint("aud-tag"))).unwrap();
+    assert_eq!(user.email, "someone@example.com");
+    assert_eq!(user.name, "Some One");
+
+    assert_eq!(
+        source.identify(&request(mint("other-app"))).err(),
+        Some(IdentityError::Invalid)
+    );
+    assert_eq!(
+        source.identify(&request_from("127.0.0.1:1", &[])).err(),
+        Some(IdentityError::Missing)
+    );
+}
+
+fn location_params(response: &warp::http::Response<warp::hyper::body::Bytes>) -> std::collections::HashMap<String, String> {
+    let location = response.headers()["location"].to_str().unwrap();
+    reqwest::Url::parse(location).unwrap().query_pairs().into_owned().collect()
+}
+
+fn cookies(response: &warp::http::Response<warp::hyper::body::Bytes>) -> String {
+    response
+        .headers()
+        .get_all("set-cookie")
+        .iter()
+        .map(|c| c.to_str().unwrap().split(';').next().unwrap().to_string())
+        .collect::<Vec<_>>()
+        .join("; ")
+}
+
+#[tokio::test]
+async fn oidc_login_with_pkce() {
+    use std::{
+        collections::HashMap,
+        sync::{Arc, Mutex},
+    };
+
+    use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+
+    let signer = Arc::new(TestSigner::new());
+    // The mock provider only learns about the login once the user is sent to it
+    let pending: Arc<Mutex<Option<HashMap<String, String>>>> = Default::default();
+    let groups: Arc<Mutex<Vec<String>>> = Default::default();
+    let addr_slot: Arc<std::sync::OnceLock<String>> = Default::default();
+
+    let discovery = {
+        let issuer = addr_slot.clone();
+        warp::path!(".well-known" / "openid-configuration").map(move || {
+            let issuer = issuer.get().unwrap();
+            warp::reply::json(&serde_json::json!({
+                "issuer": issuer,
+                "authorization_endpoint": format!("{}/authorize", issuer),
+                "token_endpoint": format!("{}/token", issuer),
+                "jwks_uri": format!("{}/jwks", issuer),
+                "end_session_endpoint": format!("{}/logout", issuer),
+            }))
+        })
+    };
+    let jwks = {
+        let jwks = signer.jwks.clone();
+        warp::path!("jwks").map(move || warp::reply::json(&jwks))
+    };
+    let token = {
+        let (signer, pending, groups, issuer) = (signer.clone(), pending.clone(), groups.clone(), addr_slot.clone());
+        warp::path!("token")
+            .and(warp::post())
+            .and(warp::body::form::<HashMap<String, String>>())
+            .map(move |form: HashMap<String, String>| {
+                let login = pending.lock().unwrap().take().unwrap();
+                let challenge = URL_SAFE_NO_PAD.encode(openssl::sha::sha256(form["code_verifier"].as_bytes()));
+                if form["code"] != "the-code" || challenge != login["code_challenge"] {
+                    return warp::reply::with_status(
+                        warp::reply::json(&serde_json::json!({"error": "invalid_grant"})),
+                        warp::http::StatusCode::BAD_REQUEST,
+                    );
+                }
+
+                let now = unix_now();
+                let id_token = signer.mint(serde_json::json!({
+                    "aud": "hallway",
+                    "iss": issuer.get().unwrap(),
+                    "sub": "1234",
+                    "email": "someone@example.com",
+                    "name": "Some One",
+                    "groups": *groups.lock().unwrap(),
+                    "nonce": login["nonce"],
+                    "exp": now + 600,
+                    "nbf": now - 10,
+                }));
+                warp::reply::with_status(
+                    warp::reply::json(&serde_json::json!({"id_token": id_token})),
+                    warp::http::StatusCode::OK,
+                )
+            })
+    };
+    let addr = serve_locally(discovery.or(jwks).or(token)).await;
+    addr_slot.set(format!("http://{}", addr)).unwrap();
+
+    let config = config_from(&format!(
+        r#"
+        [identity]
+        source = "oidc"
+        [identity.oidc]
+        issuer = "http://{}"
+        client_id = "hallway"
+        "#,
+        addr
+    ));
+    let readiness = crate::readiness::Readiness::default();
+    let source = crate::identity::from_config(&config, &readiness);
+    wait_ready(&readiness).await;
+    let routes = source.routes();
+
+    assert_eq!(
+        source.identify(&request_from("127.0.0.1:1", &[])).err(),
+        Some(IdentityError::LoginRequired)
+    );
+
+    let login = warp::test::request().path("/auth/login").reply(&routes).await;
+    assert_eq!(login.status(), 302);
+    let params = location_params(&login);
+    assert_eq!(params["redirect_uri"], "https://hallway.example.com/auth/callback");
+    assert_eq!(params["code_challenge_method"], "S256");
+    let flow_cookie = cookies(&login);
+
+    // Coming back with someone else's state is refused
+    let forged = warp::test::request()
+        .path("/auth/callback?code=the-code&state=forged")
+        .header("cookie", &flow_cookie)
+        .filter(&routes)
+        .await;
+    assert!(forged.is_err());
+
+    *pending.lock().unwrap() = Some(params.clone());
+    let callback = warp::test::request()
+        .path(&format!("/auth/callback?code=the-code&state={}", params["state"]))
+        .header("cookie", &flow_cookie)
+        .reply(&routes)
+        .await;
+    assert_eq!(callback.status(), 302);
+    assert_eq!(callback.headers()["location"], "/");
+
+    let session_cookie = cookies(&callback);
+    let request = RequestInfo {
+        headers: [(
+            warp::http::header::COOKIE,
+            warp::http::HeaderValue::from_str(&session_cookie).unwrap(),
+        )]
+        .into_iter()
+        .collect(),
+        remote: None,
+        unix_socket: false,
+        peer_certificate: None,
+    };
+    let user = source.identify(&request).unwrap();
+    assert_eq!(user.email, "someone@example.com");
+    assert_eq!(user.name, "Some One");
+
+    let logout = warp::test::request().path("/auth/logout").reply(&routes).await;
+    assert!(logout.headers()["location"].to_str().unwrap().starts_with(&format!("http://{}/logout", addr)));
+    assert_eq!(cookies(&logout), "hallway_session=");
+
+    // Someone in lots of groups still gets a cookie browsers will keep
+    *groups.lock().unwrap() = (0..300).map(|i| format!("some-rather-long-group-name-{}", i)).collect();
+    let login = warp::test::request().path("/auth/login").reply(&routes).await;
+    let params = location_params(&login);
+    *pending.lock().unwrap() = Some(params.clone());
+    let callback = warp::test::request()
+        .path(&format!("/auth/callback?code=the-code&state={}", params["state"]))
+        .header("cookie", cookies(&login))
+        .reply(&routes)
+        .await;
+    assert_eq!(callback.status(), 302);
+    let session_cookie = cookies(&callback);
+    assert!(session_cookie.len() < 4096);
+
+    let request = RequestInfo {
+        headers: [(
+            warp::http::header::COOKIE,
+            warp::http::HeaderValue::from_str(&session_cookie).unwrap(),
+        )]
+        .into_iter()
+        .collect(),
+        ..request
+    };
+    let user = source.identify(&request).unwrap();
+    assert_eq!(user.email, "someone@example.com");
+    assert_eq!(user.groups.len(), 300);
+
+    // And is forgotten on logout
+    warp::test::request()
+        .path("/auth/logout")
+        .header("cookie", &session_cookie)
+        .reply(&routes)
+        .await;
+    assert_eq!(source.identify(&request).err(), Some(IdentityError::LoginRequired));
+}
+
+#[tokio::test]
+async fn local_accounts_login_and_lockout() {
+    use argon2::{password_hash::SaltString, Argon2, PasswordHasher};
+
+    let argon = Argon2::default()
+        .hash_password(b"correct horse", &SaltString::encode_b64(b"some salt here").unwrap())
+        .unwrap()
+        .to_string();
+    let bcrypt = bcrypt::hash("battery staple", 4).unwrap();
+    let users = std::env::temp_dir().join(format!("hallway-users-{}", std::process::id()));
+    std::fs::write(
+        &users,
+        format!(
+            "# Local accounts\nalice:{}:alice@example.com:Alice Liddell:family,admins\nbob:{}\n",
+            argon, bcrypt
+        ),
+    )
+    .unwrap();
+
+    let config = config_from(&format!(
+        "[identity]\nsource = \"local\"\ntrusted_proxies = [\"10.0.0.9/32\"]\n[identity.local]\nusers_file = \"{}\"\n",
+        users.display()
+    ));
+    let readiness = crate::readiness::Readiness::default();
+    let source = crate::identity::from_config(&config, &readiness);
+    let routes = source.routes();
+
+    let login = |user: &str, password: &str, from: &str| {
+        warp::test::request()
+            .method("POST")
+            .path("/auth/login")
+            .header("content-type", "application/x-www-form-urlencoded")
+            .extension(server::RemoteAddr(from.parse().unwrap()))
+            .body(format!("username={}&password={}", user, password.replace(' ', "+")))
+            .reply(&routes)
+    };
+
+    let ok = login("alice", "correct horse", "10.0.0.1:1000").await;
+    assert_eq!(ok.status(), 303);
+    let request = RequestInfo {
+        headers: [(
+            warp::http::header::COOKIE,
+            warp::http::HeaderValue::from_str(&cookies(&ok)).unwrap(),
+        )]
+        .into_iter()
+        .collect(),
+        remote: None,
+        unix_socket: false,
+        peer_certificate: None,
+    };
+    let user = source.identify(&request).unwrap();
+    assert_eq!(user.email, "alice@example.com");
+    assert_eq!(user.name, "Alice Liddell");
+    assert_eq!(user.groups, ["family", "admins"]);
+
+    for _ in 0..crate::consts::defaults::LOGIN_MAX_FAILURES {
+        assert_eq!(login("bob", "wrong", "10.0.0.2:1000").await.status(), 401);
+    }
+    // Even the right password is refused now, but only for bob from there,
+    // guessing his password doesn't lock him out everywhere
+    let locked = login("bob", "battery staple", "10.0.0.2:1000").await;
+    assert_eq!(locked.status(), 429);
+    assert!(locked.headers().contains_key("retry-after"));
+    assert_eq!(login("alice", "correct horse", "10.0.0.2:1000").await.status(), 303);
+    assert_eq!(login("bob", "battery staple", "10.0.0.3:1000").await.status(), 303);
+    assert!(ok.headers()["set-cookie"].to_str().unwrap().contains("Secure"));
+
+    // Trying many users from one address locks the address out, and behind a
+    // trusted proxy that's the client's address
+    let proxied = |user: &str, password: &str, client: &str| {
+        warp::test::request()
+            .method("POST")
+            .path("/auth/login")
+            .header("content-type", "application/x-www-form-urlencoded")
+            .header("x-forwarded-for", client)
+            .extension(server::RemoteAddr("10.0.0.9:1000".parse().unwrap()))
+            .body(format!("username={}&password={}", user, password.replace(' ', "+")))
+            .reply(&routes)
+    };
+    for i in 0..crate::consts::defaults::LOGIN_MAX_FAILURES_PER_IP {
+        assert_eq!(proxied(&format!("nobody{}", i), "guess", "192.0.2.1").await.status(), 401);
+    }
+    assert_eq!(proxied("alice", "correct horse", "192.0.2.1").await.status(), 429);
+    assert_eq!(proxied("alice", "correct horse", "192.0.2.2").await.status(), 303);
+
+    // Browsers would drop a Secure cookie on a LAN box serving plain HTTP
+    let mut lan = config;
+    lan.domain.name = "http://hallway.lan".to_string();
+    let routes = crate::identity::from_config(&lan, &readiness).routes();
+    let login = |proto: Option<&str>| {
+        let request = warp::test::request()
+            .method("POST")
+            .path("/auth/login")
+            .header("content-type", "application/x-www-form-urlencoded")
+            .extension(server::RemoteAddr("10.0.0.4:1000".parse().unwrap()))
+            .body("username=alice&password=correct+horse");
+        let request = match proto {
+            Some(proto) => request.header("x-forwarded-proto", proto),
+            None => request,
+        };
+        request.reply(&routes)
+    };
+    let plain = login(None).await;
+    assert_eq!(plain.status(), 303);
+    assert!(!plain.headers()["set-cookie"].to_str().unwrap().contains("Secure"));
+    let proxied = login(Some("https")).await;
+    assert!(proxied.headers()["set-cookie"].to_str().unwrap().contains("Secure"));
+
+    // Changing the password or removing the account ends the session
+    let rewrite = |content: String| {
+        let file = std::fs::File::create(&users).unwrap();
+        std::io::Write::write_all(&mut &file, content.as_bytes()).unwrap();
+        let later = std::time::SystemTime::now() + std::time::Duration::from_secs(60);
+        file.set_modified(later).unwrap();
+    };
+    let changed = bcrypt::hash("new password", 4).unwrap();
+    rewrite(format!("alice:{}:alice@example.com\n", changed));
+    assert_eq!(source.identify(&request).err(), Some(IdentityError::LoginRequired));
+    rewrite(format!("bob:{}\n", bcrypt));
+    assert_eq!(source.identify(&request).err(), Some(IdentityError::LoginRequired));
+
+    std::fs::remove_file(users).unwrap();
+}
+
+fn test_certificate(email: &str, common_name: &str) -> openssl::x509::X509 {
+    test_certificate_with_key(email, common_name).0
+}
+
+fn test_certificate_with_key(
+    email: &str,
+    common_name: &str,
+) -> (openssl::x509::X509, openssl::pkey::PKey<openssl::pkey::Private>) {
+    use openssl::{
+        asn1::Asn1Time,
+        ec::{EcGroup, EcKey},
+        hash::MessageDigest,
+        nid::Nid,
+        pkey::PKey,
+        x509::{extension::SubjectAlternativeName, X509Builder, X509NameBuilder},
+    };
+
+    let key = PKey::from_ec_key(EcKey::generate(&EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap()).unwrap()).unwrap();
+    let mut name = X509NameBuilder::new().unwrap();
+    name.append_entry_by_nid(Nid::COMMONNAME, common_name).unwrap();
+    let name = name.build();
+
+    let mut builder = X509Builder::new().unwrap();
+    builder.set_version(2).unwrap();
+    builder.set_subject_name(&name).unwrap();
+    builder.set_issuer_name(&name).unwrap();
+    builder.set_pubkey(&key).unwrap();
+    builder.set_not_before(&Asn1Time::days_from_now(0).unwrap()).unwrap();
+    builder.set_not_after(&Asn1Time::days_from_now(1).unwrap()).unwrap();
+    let san = SubjectAlternativeName::new()
+        .email(email)
+        .build(&builder.x509v3_context(None, None))
+        .unwrap();
+    builder.append_extension(san).unwrap();
+    builder.sign(&key, MessageDigest::sha256()).unwrap();
+    (builder.build(), key)
+}
+
+#[test]
+fn client_certificate_from_forwarded_header() {
+    use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
+
+    let cert = test_certificate("ops@example.com", "Ops Laptop");
+    let pem = String::from_utf8(cert.to_pem().unwrap()).unwrap();
+    let escaped = utf8_percent_encode(&pem, NON_ALPHANUMERIC).to_string();
+    let fingerprint: String = cert
+        .digest(openssl::hash::MessageDigest::sha256())
+        .unwrap()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect();
+
+    let config = config_from("[identity]\nsource = \"client_certificate\"\ntrusted_proxies = [\"10.0.0.0/8\"]\n");
+    let source = crate::identity::from_config(&config, &crate::readiness::Readiness::default());
+    let request = |remote: &str, value: String| RequestInfo {
+        headers: [(
+            warp::http::HeaderName::from_static("x-forwarded-client-cert"),
+            warp::http::HeaderValue::from_str(&value).unwrap(),
+        )]
+        .into_iter()
+        .collect(),
+        remote: Some(remote.parse().unwrap()),
+        unix_socket: false,
+        peer_certificate: None,
+    };
+
+    // Envoy and pomerium style
+    let xfcc = format!("Hash={};Cert=\"{}\";Subject=\"CN=Ops Laptop\"", fingerprint, escaped);
+    let user = source.identify(&request("10.1.1.1:443", xfcc.clone())).unwrap();
+    assert_eq!(user.email, "ops@example.com");
+    assert_eq!(user.name, "Ops Laptop");
+    assert_eq!(user.client_certificate.as_ref().unwrap().fingerprint, fingerprint);
+
+    // nginx style
+    let user = source.identify(&request("10.1.1.1:443", escaped)).unwrap();
+    assert_eq!(user.email, "ops@example.com");
+
+    assert_eq!(
+        source.identify(&request("192.168.1.1:443", xfcc)).err(),
+        Some(IdentityError::Untrusted)
+    );
+}
+
+#[test]
+fn client_certificate_criterion() {
+    use crate::identity::ParsedCertificate;
+
+    let cert = test_certificate("ops@example.com", "Ops Laptop");
+    let pem = String::from_utf8(cert.to_pem().unwrap()).unwrap();
+    let parsed = ParsedCertificate::from_header(&pem).unwrap();
+    let other = test_certificate("ops@example.com", "Other").to_pem().unwrap();
+    let other = ParsedCertificate::from_header(std::str::from_utf8(&other).unwrap()).unwrap();
+
+    let conf = pomerium::load_from_str(&format!(
+        "
+    routes:
+    - from: https://fingerprint.com
+      policy:
+      - allow:
+          and:
+          - client_certificate:
+              fingerprint: {}
+    - from: https://spki.com
+      policy:
+      - allow:
+          or:
+          - client_certificate:
+              spki_hash: [{}]
+    - from: https://email.com
+      policy:
+      - allow:
+          or:
+          - email:
+              is: ops@example.com
+",
+        parsed.certificate.fingerprint.to_uppercase(),
+        parsed.certificate.spki_hash
+    ));
+
+    fn with(cert: &ParsedCertificate) -> pomerium::PolicyInput<'_> {
+        pomerium::PolicyInput {
+            email: "ops@example.com",
+            client_certificate: Some(&cert.certificate),
+            ..Default::default()
+        }
+    }
+    assert!(conf.routes[0].policy.check(&with(&parsed)));
+    assert!(conf.routes[1].policy.check(&with(&parsed)));
+    assert!(!conf.routes[0].policy.check(&with(&other)));
+    assert!(!conf.routes[1].policy.check(&with(&other)));
+    assert!(!conf.routes[0].policy.check(&pomerium::PolicyInput::email("ops@example.com")));
+    assert!(conf.routes[2].policy.check(&with(&other)));
+}
+
+#[test]
+fn pomerium_claim_headers() {
+    let config = config_from("[identity]\nsource = \"pomerium_headers\"\ntrusted_proxies = [\"172.16.0.0/12\"]\n");
+    let readiness = crate::readiness::Readiness::default();
+    let source = crate::identity::from_config(&config, &readiness);
+    assert_eq!(readiness.get().unwrap().global_data.sign_out_url, "/.pomerium/sign_out");
+
+    let headers = [
+        ("X-Pomerium-Claim-Email", "someone@example.com"),
+        ("X-Pomerium-Claim-Given-Name", "Some"),
+        ("X-Pomerium-Claim-Family-Name", "One"),
+        ("X-Pomerium-Claim-Groups", "admins"),
+        ("X-Pomerium-Claim-Groups", "family"),
+    ];
+    let user = source.identify(&request_from("172.17.0.2:80", &headers)).unwrap();
+    assert_eq!(user.email, "someone@example.com");
+    assert_eq!(user.name, "Some One");
+    assert_eq!(user.groups, ["admins", "family"]);
+
+    let user = source
+        .identify(&request_from(
+            "172.17.0.2:80",
+            &[("X-Pomerium-Claim-Email", "someone@example.com"), ("X-Pomerium-Claim-Groups", "admins,family")],
+        ))
+        .unwrap();
+    assert_eq!(user.groups, ["admins", "family"]);
+
+    assert_eq!(
+        source.identify(&request_from("8.8.8.8:80", &headers)).err(),
+        Some(IdentityError::Untrusted)
+    );
+    assert_eq!(
+        source.identify(&request_from("172.17.0.2:80", &[])).err(),
+        Some(IdentityError::Missing)
+    );
+}
+
+#[test]
+fn aliases_resolve_to_canonical_identity() {
+    let config = config_from(
+        r#"
+        [identity]
+        source = "trusted_headers"
+        trusted_proxies = ["127.0.0.1/32"]
+        [[identity.aliases]]
+        canonical = "me@work.com"
+        emails = ["Me@Gmail.com"]
+        subjects = ["me-on-the-nas"]
+        "#,
+    );
+    let source = crate::identity::from_config(&config, &crate::readiness::Readiness::default());
+
+    let user = source
+        .identify(&request_from("127.0.0.1:1", &[("X-Forwarded-Email", "me@gmail.com")]))
+        .unwrap();
+    assert_eq!(user.email, "me@work.com");
+    assert_eq!(user.name, "me@work.com");
+
+    let user = source
+        .identify(&request_from(
+            "127.0.0.1:1",
+            &[("Remote-User", "me-on-the-nas"), ("Remote-Email", "nas@home.lan"), ("Remote-Name", "Me")],
+        ))
+        .unwrap();
+    assert_eq!(user.email, "me@work.com");
+    assert_eq!(user.name, "Me");
+
+    let user = source
+        .identify(&request_from("127.0.0.1:1", &[("X-Forwarded-Email", "someone@else.com")]))
+        .unwrap();
+    assert_eq!(user.email, "someone@else.com");
+
+    // Policies written for the canonical user id match the other logins too
+    let config = config_from(
+        r#"
+        [identity]
+        source = "trusted_headers"
+        trusted_proxies = ["127.0.0.1/32"]
+        [[identity.aliases]]
+        canonical = "me@work.com"
+        canonical_subject = "00u1abcd"
+        emails = ["me@gmail.com"]
+        subjects = ["me-on-the-nas"]
+        "#,
+    );
+    let source = crate::identity::from_config(&config, &crate::readiness::Readiness::default());
+    let conf = pomerium::load_from_str(
+        "
+    routes:
+    - from: https://somedomain.com
+      policy:
+      - allow:
+          or:
+          - user:
+              is: 00u1abcd
+      to: http://127.0.0.1:8123
+",
+    );
+    for headers in [
+        &[("Remote-User", "me-on-the-nas"), ("Remote-Email", "nas@home.lan")][..],
+        &[("Remote-User", "gmail-id"), ("Remote-Email", "me@gmail.com")][..],
+    ] {
+        let user = source.identify(&request_from("127.0.0.1:1", headers)).unwrap();
+        assert_eq!(user.subject.as_deref(), Some("00u1abcd"));
+        assert!(conf.routes[0].policy.check(&pomerium::PolicyInput::from(&user)));
+    }
+    let other = source
+        .identify(&request_from("127.0.0.1:1", &[("Remote-User", "someone"), ("Remote-Email", "someone@else.com")]))
+        .unwrap();
+    assert!(!conf.routes[0].policy.check(&pomerium::PolicyInput::from(&other)));
+}
+
+#[test]
+fn email_canonicalization() {
+    let conf = pomerium::load_from_str(
+        "
+    routes:
+    - from: https://somedomain.com
+      policy:
+      - allow:
+          or:
+          - email:
+              is: alice@example.com
+          - email:
+              is: user@bücher.example
+      to: http://127.0.0.1:8123
+    - from: https://other.com
+      policy:
+      - allow:
+          or:
+          - domain:
+              is: Example.COM
+      to: http://127.0.0.1:8124
+    - from: https://books.com
+      policy:
+      - allow:
+          or:
+          - domain:
+              is: bücher.example
+          - domain:
+              ends_with: .bücher.example
+          - email:
+              ends_with: '@läden.example'
+          - email:
+              ends_with: .geschäft.example
+          - email:
+              contains: köln
+      to: http://127.0.0.1:8125
+",
+    );
+    let policy = &conf.routes[0].policy;
+    assert!(policy.check_authorized("Alice@Example.com"));
+    assert!(policy.check_authorized("user@xn--bcher-kva.example"));
+    assert!(!policy.check_authorized("alice+hallway@example.com"));
+    assert!(conf.routes[1].policy.check_authorized("bob@example.com"));
+    assert!(!conf.routes[1].policy.check_authorized("bob@example.com.evil"));
+
+    // Internationalized domains match however much of them the pattern has
+    let books = &conf.routes[2].policy;
+    assert!(books.check_authorized("bob@xn--bcher-kva.example"));
+    assert!(books.check_authorized("bob@shop.xn--bcher-kva.example"));
+    assert!(books.check_authorized("bob@xn--lden-loa.example"));
+    assert!(books.check_authorized("bob@kasse.xn--geschft-9wa.example"));
+    assert!(books.check_authorized("bob@xn--kln-sna.example"));
+    assert!(books.check_authorized("köln@example.com"));
+    assert!(!books.check_authorized("bob@bcher.example"));
+    assert!(!books.check_authorized("bob@example.com"));
+
+    let strict = pomerium::Canonicalization {
+        case_fold: false,
+        punycode: false,
+        ..Default::default()
+    };
+    let loose = pomerium::Canonicalization {
+        strip_plus_tags: true,
+        ..Default::default()
+    };
+    let input = |email| pomerium::PolicyInput::email(email);
+    assert!(!policy.check(&input("Alice@Example.com").with_canonicalization(strict)));
+    assert!(!policy.check(&input("user@xn--bcher-kva.example").with_canonicalization(strict)));
+    assert!(policy.check(&input("alice+hallway@example.com").with_canonicalization(loose)));
+    assert!(policy.check(&input("Alice+Hallway@EXAMPLE.com").with_canonicalization(loose)));
+}
+
+#[tokio::test]
+async fn route_canonicalization_override() {
+    let config: crate::config::Config = toml::from_str(
+        r#"
+        [domain]
+        name = "https://hallway.example.com"
+        [[routes]]
+        icon = "a"
+        label = "Loose"
+        data = "https://loose.example.com"
+        canonicalization = { strip_plus_tags = true }
+        [[routes]]
+        icon = "b"
+        label = "Strict"
+        data = "https://strict.example.com"
+        "#,
+    )
+    .unwrap();
+    let conf = pomerium::load_from_str(
+        "
+    routes:
+    - from: https://loose.example.com
+      policy:
+      - allow:
+          or:
+          - email:
+              is: alice@example.com
+      to: http://127.0.0.1:8123
+    - from: https://strict.example.com
+      policy:
+      - allow:
+          or:
+          - email:
+              is: alice@example.com
+      to: http://127.0.0.1:8124
+",
+    );
+
+    let index = std::env::temp_dir().join(format!("hallway-index-{}.html", std::process::id()));
+    std::fs::write(&index, "{{#each user.accessible_routes}}{{label}};{{/each}}").unwrap();
+    let mut renderer = crate::rendering::Renderer::from(config.routes, conf.routes, config.canonicalization, &index);
+    std::fs::remove_file(&index).unwrap();
+
+    let global = crate::rendering::GlobalData {
+        sign_out_url: String::new(),
+    };
+    assert_eq!(renderer.render(test_user("alice@example.com"), &global, "n"), "Loose;Strict;");
+    assert_eq!(renderer.render(test_user("alice+tag@example.com"), &global, "n"), "Loose;");
+}
+
+#[tokio::test]
+async fn groups_file_backend() {
+    let path = std::env::temp_dir().join(format!("hallway-groups-{}.yaml", std::process::id()));
+    std::fs::write(
+        &path,
+        "admins:\n  - Alice@Example.com\nfamily:\n  - alice@example.com\n  - bob-subject\n",
+    )
+    .unwrap();
+    let config = config_from(&format!("[groups]\nbackend = \"file\"\nfile = {:?}\n", path));
+    let groups = crate::groups::Groups::from_config(&config);
+
+    let mut alice = test_user("alice@example.com");
+    alice.groups = vec!["family".to_string()];
+    assert_eq!(groups.resolve(alice).await.groups, ["family", "admins"]);
+
+    let mut bob = test_user("bob@example.com");
+    bob.subject = Some("bob-subject".to_string());
+    let bob = groups.resolve(bob).await;
+    assert_eq!(bob.groups, ["family"]);
+    assert!(groups.resolve(test_user("eve@example.com")).await.groups.is_empty());
+    std::fs::remove_file(&path).unwrap();
+
+    let conf = pomerium::load_from_str(
+        "
+    routes:
+    - from: https://somedomain.com
+      policy:
+      - allow:
+          or:
+          - groups:
+              has: family
+      to: http://127.0.0.1:8123
+",
+    );
+    assert!(conf.routes[0].policy.check(&pomerium::PolicyInput::from(&bob)));
+    assert!(!conf.routes[0].policy.check_authorized("bob@example.com"));
+}
+
+/// Just enough of an LDAP server to answer binds and searches over a fixed
+/// directory, with BER done by hand
+mod mock_ldap {
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    pub struct Entry {
+        pub dn: &'static str,
+        pub attrs: Vec<(&'static str, Vec<&'static str>)>,
+    }
+
+    fn tlv(tag: u8, content: &[u8]) -> Vec<u8> {
+        let mut out = vec![tag];
+        match content.len() {
+            len @ 0..=127 => out.push(len as u8),
+            len => {
+                let bytes = (len as u32).to_be_bytes();
+                let skip = bytes.iter().take_while(|b| **b == 0).count();
+                out.push(0x80 | (4 - skip) as u8);
+                out.extend_from_slice(&bytes[skip..]);
+            }
+        }
+        out.extend_from_slice(content);
+        out
+    }
+
+    /// Tag, content and whatever comes after, if there's a whole element
+    fn read_tlv(buf: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+        let (&tag, rest) = buf.split_first()?;
+        let (&first, rest) = rest.split_first()?;
+        let (len, rest) = match first {
+            0..=127 => (first as usize, rest),
+            _ => {
+                let n = (first & 0x7f) as usize;
+                let len = rest.get(..n)?.iter().fold(0, |acc, b| acc << 8 | *b as usize);
+                (len, &rest[n..])
+            }
+        };
+        let content = rest.get(..len)?;
+        Some((tag, content, &rest[len..]))
+    }
+
+    fn elements(mut buf: &[u8]) -> Vec<(u8, &[u8])> {
+        let mut out = Vec::new();
+        while let Some((tag, content, rest)) = read_tlv(buf) {
+            out.push((tag, content));
+            buf = rest;
+        }
+        out
+    }
+
+    fn text(bytes: &[u8]) -> String {
+        String::from_utf8_lossy(bytes).to_lowercase()
+    }
+
+    fn matches(filter: (u8, &[u8]), entry: &Entry) -> bool {
+        let (tag, content) = filter;
+        match tag {
+            0xa0 => elements(content).into_iter().all(|f| matches(f, entry)),
+            0xa1 => elements(content).into_iter().any(|f| matches(f, entry)),
+            0xa2 => !elements(content).into_iter().all(|f| matches(f, entry)),
+            0xa3 => {
+                let parts = elements(content);
+                let (attr, value) = (text(parts[0].1), text(parts[1].1));
+                entry
+                    .attrs
+                    .iter()
+                    .any(|(a, values)| a.to_lowercase() == attr && values.iter().any(|v| v.to_lowercase() == value))
+            }
+            0x87 => entry.attrs.iter().any(|(a, _)| a.to_lowercase() == text(content)),
+            _ => false,
+        }
+    }
+
+    fn result(tag: u8, code: u8) -> Vec<u8> {
+        tlv(tag, &[tlv(0x0a, &[code]), tlv(0x04, b""), tlv(0x04, b"")].concat())
+    }
+
+    fn answer(op: u8, content: &[u8], directory: &[Entry], password: &str, searches: &AtomicUsize) -> Vec<Vec<u8>> {
+        match op {
+            // Bind
+            0x60 => {
+                let parts = elements(content);
+                let code = if parts[2].1 == password.as_bytes() { 0 } else { 49 };
+                vec![result(0x61, code)]
+            }
+            // Search
+            0x63 => {
+                searches.fetch_add(1, Ordering::SeqCst);
+                let parts = elements(content);
+                let base = text(parts[0].1);
+                let wanted: Vec<String> = elements(parts[7].1).into_iter().map(|(_, a)| text(a)).collect();
+                let mut out: Vec<Vec<u8>> = directory
+                    .iter()
+                    .filter(|e| e.dn.to_lowercase().ends_with(&base) && matches(parts[6], e))
+                    .map(|e| {
+                        let attrs: Vec<u8> = e
+                            .attrs
+                            .iter()
+                            .filter(|(a, _)| wanted.contains(&a.to_lowercase()))
+                            .flat_map(|(a, values)| {
+                                let values: Vec<u8> = values.iter().flat_map(|v| tlv(0x04, v.as_bytes())).collect();
+                                tlv(0x30, &[tlv(0x04, a.as_bytes()), tlv(0x31, &values)].concat())
+                            })
+                            .collect();
+                        tlv(0x64, &[tlv(0x04, e.dn.as_bytes()), tlv(0x30, &attrs)].concat())
+                    })
+                    .collect();
+                out.push(result(0x65, 0));
+                out
+            }
+            _ => Vec::new(),
+        }
+    }
+
+    /// Serves `directory` on a random port, counting the searches made
+    pub async fn serve(directory: Vec<Entry>, password: &'static str) -> (std::net::SocketAddr, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let searches = Arc::new(AtomicUsize::new(0));
+        let directory = Arc::new(directory);
+        let counter = searches.clone();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let directory = directory.clone();
+                let searches = counter.clone();
+                tokio::spawn(async move {
+                    let mut buf = Vec::new();
+                    let mut chunk = [0; 4096];
+                    loop {
+                        while let Some((_, message, rest)) = read_tlv(&buf) {
+                            let parts = elements(message);
+                            let (id, (op, content)) = (parts[0].1.to_vec(), parts[1]);
+                            if op == 0x42 {
+                                return;
+                            }
+                            for response in answer(op, content, &directory, password, &searches) {
+                                let message = tlv(0x30, &[tlv(0x02, &id), response].concat());
+                                stream.write_all(&message).await.unwrap();
+                            }
+                            buf = rest.to_vec();
+                        }
+                        match stream.read(&mut chunk).await {
+                            Ok(0) | Err(_) => return,
+                            Ok(n) => buf.extend_from_slice(&chunk[..n]),
+                        }
+                    }
+                });
+            }
+        });
+        (addr, searches)
+    }
+}
+
+#[tokio::test]
+async fn ldap_groups_backend() {
+    use mock_ldap::Entry;
+    use std::sync::atomic::Ordering;
+
+    let (addr, searches) = mock_ldap::serve(
+        vec![
+            Entry {
+                dn: "uid=alice,ou=people,dc=example,dc=com",
+                attrs: vec![("objectClass", vec!["inetOrgPerson"]), ("mail", vec!["alice@example.com"])],
+            },
+            Entry {
+                dn: "cn=admins,ou=groups,dc=example,dc=com",
+                attrs: vec![
+                    ("objectClass", vec!["groupOfNames"]),
+                    ("cn", vec!["admins"]),
+                    ("member", vec!["uid=alice,ou=people,dc=example,dc=com"]),
+                ],
+            },
+            Entry {
+                dn: "cn=family,ou=groups,dc=example,dc=com",
+                attrs: vec![
+                    ("objectClass", vec!["groupOfUniqueNames"]),
+                    ("CN", vec!["family"]),
+                    ("uniqueMember", vec!["uid=alice,ou=people,dc=example,dc=com"]),
+                ],
+            },
+            Entry {
+                dn: "cn=others,ou=groups,dc=example,dc=com",
+                attrs: vec![("cn", vec!["others"]), ("member", vec!["uid=bob,ou=people,dc=example,dc=com"])],
+            },
+        ],
+        "secret",
+    )
+    .await;
+
+    let ldap_config = |password: &str| {
+        config_from(&format!(
+            r#"
+            [groups]
+            backend = "ldap"
+            ttl = 60
+            [groups.ldap]
+            url = "ldap://{}"
+            bind_dn = "cn=hallway,dc=example,dc=com"
+            bind_password = "{}"
+            user_base_dn = "ou=people,dc=example,dc=com"
+            group_base_dn = "ou=groups,dc=example,dc=com"
+            "#,
+            addr, password
+        ))
+    };
+
+    let groups = crate::groups::Groups::from_config(&ldap_config("secret"));
+    let alice = groups.resolve(test_user("alice@example.com")).await;
+    assert_eq!(alice.groups, ["admins", "family"]);
+    assert_eq!(searches.load(Ordering::SeqCst), 2);
+
+    // Memberships are remembered
+    let alice = groups.resolve(test_user("alice@example.com")).await;
+    assert_eq!(alice.groups, ["admins", "family"]);
+    assert_eq!(searches.load(Ordering::SeqCst), 2);
+
+    // Users that aren't in the directory don't need the group search
+    assert!(groups.resolve(test_user("eve@example.com")).await.groups.is_empty());
+    assert_eq!(searches.load(Ordering::SeqCst), 3);
+
+    // Failures leave the user with the groups they came with
+    let groups = crate::groups::Groups::from_config(&ldap_config("wrong"));
+    assert!(groups.resolve(test_user("alice@example.com")).await.groups.is_empty());
+    assert_eq!(searches.load(Ordering::SeqCst), 3);
+}
+
+#[tokio::test]
+async fn groups_lookups_are_shared_and_failures_remembered() {
+    use crate::groups::{GroupBackend, Groups, GroupsError, Lookup};
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    };
+    use std::time::Duration;
+
+    /// Answers with whatever comes next in `answers`, slowly
+    struct Scripted {
+        calls: Arc<AtomicUsize>,
+        answers: Mutex<Vec<Option<&'static str>>>,
+    }
+
+    impl GroupBackend for Scripted {
+        fn groups_for<'a>(&'a self, _: &'a CurrentUserData) -> Lookup<'a> {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            let answer = self.answers.lock().unwrap().remove(0);
+            Box::pin(async move {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+                match answer {
+                    Some(group) => Ok(vec![group.to_string()]),
+                    None => Err(GroupsError::Io(std::io::ErrorKind::TimedOut.into())),
+                }
+            })
+        }
+    }
+
+    let calls = Arc::new(AtomicUsize::new(0));
+    let backend = Scripted {
+        calls: calls.clone(),
+        answers: Mutex::new(vec![Some("family"), None, Some("admins")]),
+    };
+    let groups = Groups::new(Box::new(backend), Duration::from_secs(60), Duration::from_secs(5));
+    tokio::time::pause();
+
+    // Everyone asking at once waits for the same lookup
+    let (a, b) = tokio::join!(
+        groups.resolve(test_user("alice@example.com")),
+        groups.resolve(test_user("alice@example.com"))
+    );
+    assert_eq!(a.groups, ["family"]);
+    assert_eq!(b.groups, ["family"]);
+    assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+    // Once expired, the groups we had are used while the backend is asked again
+    tokio::time::advance(Duration::from_secs(61)).await;
+    assert_eq!(groups.resolve(test_user("alice@example.com")).await.groups, ["family"]);
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert_eq!(calls.load(Ordering::SeqCst), 2);
+
+    // That failed, which is remembered for a while
+    assert_eq!(groups.resolve(test_user("alice@example.com")).await.groups, ["family"]);
+    assert_eq!(calls.load(Ordering::SeqCst), 2);
+    tokio::time::advance(Duration::from_secs(6)).await;
+    assert_eq!(groups.resolve(test_user("alice@example.com")).await.groups, ["family"]);
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert_eq!(calls.load(Ordering::SeqCst), 3);
+    assert_eq!(groups.resolve(test_user("alice@example.com")).await.groups, ["admins"]);
+}
+
+#[test]
+fn user_criterion_matches_subject() {
+    const CONF: &str = "
+    routes:
+    - from: https://somedomain.com
+      policy:
+      - allow:
+          or:
+          - user:
+              is: 00u1abcd
+      to: http://127.0.0.1:8123
+    - from: https://other.com
+      policy:
+      - allow:
+          or:
+          - user:
+              is: alice@example.com
+      to: http://127.0.0.1:8124
+";
+    let mut alice = test_user("alice@example.com");
+    alice.subject = Some("00u1abcd".to_string());
+    let input = pomerium::PolicyInput::from(&alice);
+
+    let conf = pomerium::load_from_str(CONF);
+    assert!(conf.routes[0].policy.check(&input));
+    assert!(!conf.routes[1].policy.check(&input));
+    assert!(!conf.routes[0].policy.check(&pomerium::PolicyInput::from(&test_user("alice@example.com"))));
+
+    let conf = pomerium::load_from_str(CONF).with_user_criterion(pomerium::UserCriterion::Email);
+    assert!(!conf.routes[0].policy.check(&input));
+    assert!(conf.routes[1].policy.check(&input));
+    assert!(conf.routes[1].policy.check_authorized("Alice@Example.com"));
+}
+
+#[tokio::test]
+async fn tls_reloads_certificates_and_verifies_clients() {
+    let dir = std::env::temp_dir().join(format!("hallway-tls-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let write_server_cert = |name: &str| {
+        let (cert, key) = test_certificate_with_key("admin@example.com", name);
+        std::fs::write(dir.join("cert.pem"), cert.to_pem().unwrap()).unwrap();
+        std::fs::write(dir.join("key.pem"), key.private_key_to_pem_pkcs8().unwrap()).unwrap();
+        cert.to_der().unwrap()
+    };
+    let first = write_server_cert("first.example.com");
+    let (client_cert, client_key) = test_certificate_with_key("ops@example.com", "Ops Laptop");
+    std::fs::write(dir.join("clients.pem"), client_cert.to_pem().unwrap()).unwrap();
+
+    let config = config_from(&format!(
+        "[identity]\nsource = \"client_certificate\"\n[tls]\ncert = {:?}\nkey = {:?}\nclient_ca = {:?}\n",
+        dir.join("cert.pem"),
+        dir.join("key.pem"),
+        dir.join("clients.pem")
+    ));
+    let source = crate::identity::from_config(&config, &crate::readiness::Readiness::default());
+    let route = crate::filters::identity(source).map(|user: CurrentUserData| user.email);
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let acceptor = crate::tls::acceptor(config.tls.as_ref().unwrap());
+    tokio::spawn(server::serve(warp::service(route), server::Listener::Tcp(listener), Some(acceptor), std::future::pending()));
+
+    let client = |identity: bool| {
+        let builder = reqwest::Client::builder()
+            .danger_accept_invalid_certs(true)
+            .tls_info(true);
+        let builder = match identity {
+            true => builder.identity(
+                reqwest::Identity::from_pem(
+                    &[client_cert.to_pem().unwrap(), client_key.private_key_to_pem_pkcs8().unwrap()].concat(),
+                )
+                .unwrap(),
+            ),
+            false => builder,
+        };
+        builder.build().unwrap()
+    };
+    let served = |resp: &reqwest::Response| {
+        resp.extensions()
+            .get::<reqwest::tls::TlsInfo>()
+            .and_then(|i| i.peer_certificate())
+            .map(<[u8]>::to_vec)
+            .unwrap()
+    };
+    let url = format!("https://{}/", addr);
+
+    let with_cert = client(true);
+    let resp = with_cert.get(&url).send().await.unwrap();
+    assert_eq!(served(&resp), first);
+    assert_eq!(resp.text().await.unwrap(), "ops@example.com");
+    assert!(!client(false).get(&url).send().await.unwrap().status().is_success());
+
+    // Renewed certificates are picked up by new connections only
+    let second = write_server_cert("second.example.com");
+    tokio::time::pause();
+    tokio::time::advance(std::time::Duration::from_secs(crate::consts::defaults::TLS_RELOAD_CHECK + 1)).await;
+    tokio::time::resume();
+    let resp = client(true).get(&url).send().await.unwrap();
+    assert_eq!(served(&resp), second);
+    let resp = with_cert.get(&url).send().await.unwrap();
+    assert_eq!(served(&resp), first);
+    assert_eq!(resp.text().await.unwrap(), "ops@example.com");
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test]
+async fn http_redirects_to_https() {
+    let redirect = crate::tls::redirect(8443);
+    let resp = warp::test::request()
+        .path("/avatar?v=1")
+        .header("host", "hallway.example.com:8080")
+        .reply(&redirect)
+        .await;
+    assert_eq!(resp.status(), warp::http::StatusCode::PERMANENT_REDIRECT);
+    assert_eq!(resp.headers()["location"], "https://hallway.example.com:8443/avatar?v=1");
+
+    let resp = warp::test::request()
+        .path("/")
+        .header("host", "[::1]")
+        .reply(&crate::tls::redirect(443))
+        .await;
+    assert_eq!(resp.headers()["location"], "https://[::1]/");
+}
+
+#[test]
+fn listen_addresses() {
+    let addresses = crate::listen::addresses("0.0.0.0, ::,[::1]:9000,127.0.0.1:8081,", 8080);
+    let expected: Vec<std::net::SocketAddr> = ["0.0.0.0:8080", "[::]:8080", "[::1]:9000", "127.0.0.1:8081"]
+        .iter()
+        .map(|a| a.parse().unwrap())
+        .collect();
+    assert_eq!(addresses, expected);
+}
+
+#[tokio::test]
+async fn listeners_ipv6_unix_and_inherited() {
+    use std::os::{fd::IntoRawFd, unix::fs::PermissionsExt};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let route = || {
+        warp::ext::optional::<server::RemoteAddr>()
+            .map(|r: Option<server::RemoteAddr>| r.map(|r| r.0.ip().to_canonical().to_string()).unwrap_or_default())
+    };
+    let serve = |listener| {
+        tokio::spawn(server::serve(warp::service(route()), listener, None, std::future::pending()));
+    };
+
+    // Dual stack takes IPv4 clients as well
+    let any = "[::]:0".parse().unwrap();
+    let listener = crate::listen::bind_tcp(any, &[any]).unwrap();
+    let port = listener.local_addr().unwrap().port();
+    serve(server::Listener::Tcp(listener));
+    let body = reqwest::get(format!("http://127.0.0.1:{}/", port)).await.unwrap().text().await.unwrap();
+    assert_eq!(body, "127.0.0.1");
+
+    let path = std::env::temp_dir().join(format!("hallway-{}.sock", std::process::id()));
+    std::fs::write(&path, "stale").unwrap();
+    serve(server::Listener::Unix(crate::listen::bind_unix(&path, 0o600).unwrap()));
+    assert_eq!(std::fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+    let mut stream = tokio::net::UnixStream::connect(&path).await.unwrap();
+    stream
+        .write_all(b"GET / HTTP/1.1\r\nHost: hallway\r\nConnection: close\r\n\r\n")
+        .await
+        .unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await.unwrap();
+    assert!(response.starts_with("HTTP/1.1 200"));
+    // No address to give over a Unix socket
+    assert!(response.ends_with("\r\n\r\n"));
+    std::fs::remove_file(&path).unwrap();
+
+    // Identity headers from Unix socket peers are only believed when asked for
+    let whoami = |trust: bool| {
+        let config = config_from(&format!(
+            "[identity]\nsource = \"trusted_headers\"\ntrusted_proxies = [\"10.0.0.0/8\"]\ntrust_unix_socket = {}\n",
+            trust
+        ));
+        let source = crate::identity::from_config(&config, &crate::readiness::Readiness::default());
+        crate::filters::identity(source)
+            .map(|user: crate::common::CurrentUserData| user.email)
+            .recover(|_| async { Ok::<_, std::convert::Infallible>("untrusted".to_string()) })
+    };
+    for (trust, expected) in [(true, "proxied@example.com"), (false, "untrusted")] {
+        let listener = server::Listener::Unix(crate::listen::bind_unix(&path, 0o600).unwrap());
+        tokio::spawn(server::serve(warp::service(whoami(trust)), listener, None, std::future::pending()));
+        let mut stream = tokio::net::UnixStream::connect(&path).await.unwrap();
+        stream
+            .write_all(b"GET / HTTP/1.1\r\nHost: hallway\r\nRemote-Email: proxied@example.com\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        assert!(response.ends_with(expected), "{}", response);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    let inherited = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = inherited.local_addr().unwrap();
+    let mut listeners = crate::listen::inherited(inherited.into_raw_fd(), 1).unwrap();
+    serve(listeners.pop().unwrap());
+    let body = reqwest::get(format!("http://{}/", addr)).await.unwrap().text().await.unwrap();
+    assert_eq!(body, "127.0.0.1");
+}
+
+#[tokio::test]
+async fn health_and_readiness_probes() {
+    use crate::readiness::{Discovered, Readiness};
+    use warp::http::StatusCode;
+
+    let html_files = std::env::temp_dir().join(format!("hallway-health-{}", std::process::id()));
+    std::fs::create_dir_all(html_files.join("assets")).unwrap();
+    let config: crate::config::Config = toml::from_str(
+        r#"
+        [domain]
+        name = "https://hallway.example.com"
+        [[routes]]
+        icon = "a"
+        label = "Wiki"
+        data = "https://wiki.example.com"
+        "#,
+    )
+    .unwrap();
+    std::fs::write(html_files.join("index.html"), "{{user.email}}").unwrap();
+    let renderer = crate::rendering::Renderer::from(config.routes, vec![], config.canonicalization, &html_files.join("index.html"));
+
+    let readiness = Readiness::default();
+    let probes = crate::health::routes(readiness.clone(), renderer, html_files.clone());
+    let probe = |path: &'static str| warp::test::request().path(path).reply(&probes);
+
+    let resp = probe("/healthz").await;
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    let resp = probe("/readyz").await;
+    assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+    let report: serde_json::Value = serde_json::from_slice(resp.body()).unwrap();
+    assert_eq!(report["ready"], false);
+    assert_eq!(report["checks"]["identity"]["ok"], false);
+    assert_eq!(report["checks"]["config"]["detail"], "routes: 1, pomerium routes: 0");
+    assert_eq!(report["checks"]["templates"]["detail"], "compiled: index.html");
+    assert_eq!(report["checks"]["assets"]["ok"], true);
+
+    readiness.set_ready(Discovered {
+        jwt_decoder: Some(crate::jwt::JwtDecoder::oidc(
+            "https://auth.example.com",
+            "hallway",
+            TestSigner::new().jwks,
+            Default::default(),
+        )),
+        global_data: Default::default(),
+    });
+    let resp = probe("/readyz").await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    let report: serde_json::Value = serde_json::from_slice(resp.body()).unwrap();
+    assert_eq!(report["checks"]["keys"]["detail"], "signing keys: 1");
+    assert_eq!(resp.headers()["cache-control"], "no-cache, no-store, must-revalidate");
+
+    // Losing the files under a running instance takes it out of rotation
+    std::fs::remove_dir_all(&html_files).unwrap();
+    let resp = probe("/readyz").await;
+    assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+    let report: serde_json::Value = serde_json::from_slice(resp.body()).unwrap();
+    assert_eq!(report["checks"]["assets"]["ok"], false);
+}
+
+#[tokio::test]
+async fn metrics_count_requests_and_need_the_token() {
+    use crate::metrics::{self, metrics};
+    use warp::{http::StatusCode, Reply};
+
+    let app = warp::path::end()
+        .map(|| "hello")
+        .recover(|_| async {
+            let mut response = warp::reply::with_status(warp::reply::html("missing"), StatusCode::NOT_FOUND).into_response();
+            response.extensions_mut().insert(metrics::Recovered);
+            Ok::<_, std::convert::Infallible>(response)
+        });
+    let app = warp::path::full()
+        .and(warp::header::optional::<String>("accept-encoding"))
+        .and(app)
+        .then(crate::compression::html);
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(server::serve(
+        metrics::Instrumented(warp::service(app)),
+        server::Listener::Tcp(listener),
+        None,
+        std::future::pending(),
+    ));
+    reqwest::get(format!("http://{}/", addr)).await.unwrap();
+    reqwest::get(format!("http://{}/nothing/here", addr)).await.unwrap();
+
+    let signer = TestSigner::new();
+    let decoder = crate::jwt::JwtDecoder::oidc("https://auth.example.com", "hallway", signer.jwks.clone(), Default::default());
+    let expired = signer.mint(serde_json::json!({
+        "iss": "https://auth.example.com",
+        "aud": "hallway",
+        "email": "alice@example.com",
+        "exp": unix_now() - 3600,
+    }));
+    assert!(decoder.decode(aliri::Jwt::from(expired)).is_none());
+
+    let scrape = crate::metrics::routes(&metrics::MetricsConfig {
+        port: None,
+        token: Some("s3cret".to_string()),
+    });
+    let resp = warp::test::request().path("/metrics").reply(&scrape).await;
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    let resp = warp::test::request()
+        .path("/metrics")
+        .header("authorization", "Bearer wrong!")
+        .reply(&scrape)
+        .await;
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    let resp = warp::test::request()
+        .path("/metrics")
+        .header("authorization", "Bearer s3cret")
+        .reply(&scrape)
+        .await;
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    let body = String::from_utf8(resp.body().to_vec()).unwrap();
+    assert!(body.contains(r#"hallway_http_requests_total{route="index",status="200"}"#));
+    assert!(body.contains(r#"hallway_http_requests_total{route="errors",status="404"}"#));
+    assert!(body.contains(r#"hallway_http_request_duration_seconds_count{route="index"}"#));
+    assert!(body.contains(r#"hallway_jwt_verifications_total{result="expired"}"#));
+    assert_eq!(metrics().encode(), metrics().encode());
+}
+
+#[tokio::test]
+async fn access_log_formats_and_request_ids() {
+    use crate::access_log::{timestamp, AccessLog, AccessLogConfig, LogFormat, Traced};
+    use std::sync::{Arc, Mutex};
+
+    // The only test touching the global redaction
+    crate::privacy::init(crate::privacy::PrivacyConfig {
+        redaction: crate::privacy::Redaction::Mask,
+        salt: String::new(),
+    });
+
+    assert_eq!(
+        timestamp(std::time::UNIX_EPOCH + std::time::Duration::from_millis(1_792_331_736_042), true),
+        "18/Oct/2026:13:55:36 +0000"
+    );
+    assert_eq!(
+        timestamp(std::time::UNIX_EPOCH + std::time::Duration::from_millis(951_782_400_000), false),
+        "2000-02-29T00:00:00.000Z"
+    );
+
+    let config = config_from("[identity]\nsource = \"trusted_headers\"\ntrusted_proxies = [\"127.0.0.1/32\"]\n");
+    let source = crate::identity::from_config(&config, &crate::readiness::Readiness::default());
+    let route = crate::filters::identity(source)
+        .map(|user: CurrentUserData| user.email)
+        .or(warp::path!("anyone").map(|| "hello".to_string()))
+        .unify();
+
+    let serve = |format: LogFormat| {
+        let route = route.clone();
+        async move {
+            let sink = Arc::new(Mutex::new(Vec::<u8>::new()));
+            let log = AccessLog::new(AccessLogConfig { format }, sink.clone());
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            tokio::spawn(server::serve(
+                Traced::new(warp::service(route), Some(log)),
+                server::Listener::Tcp(listener),
+                None,
+                std::future::pending(),
+            ));
+            (addr, sink)
+        }
+    };
+    let lines = |sink: &Arc<Mutex<Vec<u8>>>| {
+        String::from_utf8(sink.lock().unwrap().clone())
+            .unwrap()
+            .lines()
+            .map(str::to_string)
+            .collect::<Vec<_>>()
+    };
+
+    let (addr, sink) = serve(LogFormat::Combined).await;
+    let client = reqwest::Client::new();
+    let resp = client
+        .get(format!("http://{}/", addr))
+        .header("Remote-Email", "alice@example.com")
+        .header("X-Request-Id", "from-the-proxy")
+        .header("User-Agent", "tests")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.headers()["x-request-id"], "from-the-proxy");
+    assert_eq!(resp.text().await.unwrap(), "alice@example.com");
+    let resp = client
+        .get(format!("http://{}/anyone?page=2", addr))
+        .header("X-Request-Id", "not an id")
+        .send()
+        .await
+        .unwrap();
+    let generated = resp.headers()["x-request-id"].to_str().unwrap().to_string();
+    assert_ne!(generated, "not an id");
+    resp.text().await.unwrap();
+
+    let logged = lines(&sink);
+    assert_eq!(logged.len(), 2);
+    assert!(logged[0].starts_with("127.0.0.1 - a***@example.com ["), "{}", logged[0]);
+    assert!(logged[0].contains("] \"GET / HTTP/1.1\" 200 17 \"-\" \"tests\" "), "{}", logged[0]);
+    assert!(logged[0].ends_with("ms"));
+    assert!(logged[1].starts_with("127.0.0.1 - - ["), "{}", logged[1]);
+    assert!(logged[1].contains("\"GET /anyone?page HTTP/1.1\" 200 5 "), "{}", logged[1]);
+
+    let (addr, sink) = serve(LogFormat::Json).await;
+    let resp = client
+        .get(format!("http://{}/", addr))
+        .header("Remote-Email", "alice@example.com")
+        .send()
+        .await
+        .unwrap();
+    let id = resp.headers()["x-request-id"].to_str().unwrap().to_string();
+    resp.text().await.unwrap();
+    // Authorization codes stay out of the log
+    client
+        .get(format!("http://{}/auth/callback?code=s3cret&state=abc", addr))
+        .header("Referer", "https://hallway.example.com/auth/callback?code=s3cret")
+        .send()
+        .await
+        .unwrap()
+        .text()
+        .await
+        .unwrap();
+    // And so do the emails admins search the audit log for
+    client
+        .get(format!("http://{}/audit?user=bob%40example.com&route=", addr))
+        .header("Referer", "https://hallway.example.com/audit?user=bob%40example.com")
+        .send()
+        .await
+        .unwrap()
+        .text()
+        .await
+        .unwrap();
+
+    let logged = lines(&sink);
+    assert_eq!(logged.len(), 3);
+    assert!(!logged[1].contains("s3cret"), "{}", logged[1]);
+    let callback: serde_json::Value = serde_json::from_str(&logged[1]).unwrap();
+    assert_eq!(callback["path"], "/auth/callback?code&state");
+    assert_eq!(callback["referer"], "https://hallway.example.com/auth/callback?code");
+    assert!(!logged[2].contains("bob"), "{}", logged[2]);
+    let search: serde_json::Value = serde_json::from_str(&logged[2]).unwrap();
+    assert_eq!(search["path"], "/audit?user&route");
+    assert_eq!(search["referer"], "https://hallway.example.com/audit?user");
+    let entry: serde_json::Value = serde_json::from_str(&logged[0]).unwrap();
+    assert_eq!(entry["request_id"], id.as_str());
+    assert_eq!(entry["user"], "a***@example.com");
+    assert_eq!(entry["method"], "GET");
+    assert_eq!(entry["path"], "/");
+    assert_eq!(entry["status"], 200);
+    assert_eq!(entry["bytes"], 17);
+    assert_eq!(entry["remote"], "127.0.0.1");
+    assert!(entry["latency_ms"].as_f64().is_some());
+}
+
+#[tokio::test]
+async fn traces_are_exported_with_the_proxy_parent() {
+    use tracing_subscriber::layer::SubscriberExt;
+    use warp::hyper::body::Bytes;
+
+    // Stands in for the collector, keeping whatever is sent to it
+    let (sent, mut received) = tokio::sync::mpsc::unbounded_channel::<Bytes>();
+    let collector = warp::path!("v1" / "traces")
+        .and(warp::post())
+        .and(warp::body::bytes())
+        .map(move |body: Bytes| {
+            sent.send(body).unwrap();
+            warp::reply()
+        });
+    let collector = serve_locally(collector).await;
+
+    assert!(crate::telemetry::tracer_provider().is_none());
+    std::env::set_var("OTEL_EXPORTER_OTLP_ENDPOINT", format!("http://{}", collector));
+    std::env::set_var("OTEL_EXPORTER_OTLP_PROTOCOL", "http/protobuf");
+    let provider = crate::telemetry::tracer_provider().unwrap();
+    std::env::remove_var("OTEL_EXPORTER_OTLP_ENDPOINT");
+    std::env::remove_var("OTEL_EXPORTER_OTLP_PROTOCOL");
+
+    let subscriber = tracing_subscriber::registry().with(crate::telemetry::layer(&provider));
+    let _guard = tracing::subscriber::set_default(subscriber);
+
+    let route = warp::path::end().map(|| tracing::info_span!("template_render").in_scope(|| "rendered"));
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(server::serve(
+        crate::access_log::Traced::new(warp::service(route), None),
+        server::Listener::Tcp(listener),
+        None,
+        std::future::pending(),
+    ));
+
+    let body = reqwest::Client::new()
+        .get(format!("http://{}/", addr))
+        .header("traceparent", "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01")
+        .send()
+        .await
+        .unwrap()
+        .text()
+        .await
+        .unwrap();
+    assert_eq!(body, "rendered");
+
+    let flushed = tokio::task::spawn_blocking(move || provider.force_flush()).await.unwrap();
+    assert!(flushed.is_ok());
+    let export = received.recv().await.unwrap();
+
+    let contains = |needle: &[u8]| export.windows(needle.len()).any(|w| w == needle);
+    let trace_id = [
+        0x0a, 0xf7, 0x65, 0x19, 0x16, 0xcd, 0x43, 0xdd, 0x84, 0x48, 0xeb, 0x21, 0x1c, 0x80, 0x31, 0x9c,
+    ];
+    let parent_id = [0xb7, 0xad, 0x6b, 0x71, 0x69, 0x20, 0x33, 0x31];
+    assert!(contains(&trace_id));
+    assert!(contains(&parent_id));
+    assert!(contains(b"template_render"));
+    assert!(contains(b"request"));
+    assert!(contains(b"hallway"));
+}
+
+#[test]
+fn privacy_redacts_emails_and_names() {
+    use crate::privacy::{PrivacyConfig, Redaction};
+
+    let config: crate::config::Config = config_from("[privacy]\nredaction = \"hash\"\nsalt = \"pepper\"\n");
+    assert_eq!(config.privacy.redaction, Redaction::Hash);
+
+    let plain = PrivacyConfig::default();
+    assert_eq!(plain.email("alice@example.com"), "alice@example.com");
+    assert_eq!(plain.name("Alice Liddell"), "Alice Liddell");
+
+    let masked = PrivacyConfig {
+        redaction: Redaction::Mask,
+        ..Default::default()
+    };
+    assert_eq!(masked.email("alice@example.com"), "a***@example.com");
+    assert_eq!(masked.email("alice"), "a***");
+    assert_eq!(masked.name("Élodie Martin"), "É***");
+    assert_eq!(masked.name(""), "***");
+
+    let hashed = config.privacy;
+    let hash = hashed.email("alice@example.com");
+    assert_eq!(hash.len(), 12);
+    assert!(!hash.contains("alice"));
+    assert_eq!(hash, hashed.email("alice@example.com"));
+    assert_ne!(hash, hashed.email("bob@example.com"));
+    let unsalted = PrivacyConfig {
+        redaction: Redaction::Hash,
+        ..Default::default()
+    };
+    assert_ne!(hash, unsalted.email("alice@example.com"));
+    assert!(!hashed.name("Alice Liddell").contains("Alice"));
+}
+
+#[tokio::test]
+async fn audit_log_records_launches_and_rotates() {
+    use crate::audit::{Action, AuditLog, Event, Search};
+
+    let dir = std::env::temp_dir().join(format!("hallway-audit-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    let config: crate::config::Config = toml::from_str(&format!(
+        r#"
+        [domain]
+        name = "https://hallway.example.com"
+        [identity]
+        source = "trusted_headers"
+        trusted_proxies = ["127.0.0.1/32", "10.0.0.0/8"]
+        [audit]
+        dir = {:?}
+        max_file_size = 600
+        admin_group = "admins"
+        [[routes]]
+        icon = "a"
+        label = "Media Server"
+        data = "https://media.example.com"
+        [[routes]]
+        icon = "b"
+        label = "Tools"
+        data = [{{icon = "c", label = "Wiki", data = "https://wiki.example.com"}}]
+        "#,
+        dir
+    ))
+    .unwrap();
+    let conf = pomerium::load_from_str(
+        "
+    routes:
+    - from: https://media.example.com
+      policy:
+      - allow:
+          or:
+          - email:
+              is: alice@example.com
+      to: http://127.0.0.1:8123
+    - from: https://wiki.example.com
+      allow_public_unauthenticated_access: true
+      to: http://127.0.0.1:8124
+",
+    );
+
+    let audit = AuditLog::new(config.audit.clone().unwrap()).unwrap();
+    let identity = crate::identity::from_config(&config, &crate::readiness::Readiness::default());
+    let index = dir.join("index.html");
+    std::fs::write(&index, "{{#each user.accessible_routes}}{{label}};{{/each}}").unwrap();
+    let mut routes = config.routes.clone();
+    routes.iter_mut().for_each(|r| r.escaped_label = r.label.replace([' ', '.'], "_"));
+    if let crate::config::RouteData::Group(group) = &mut routes[1].data {
+        group[0].escaped_label = "Wiki".to_string();
+    }
+    let renderer = crate::rendering::Renderer::from(routes, conf.routes, config.canonicalization, &index);
+    let template = dir.join("audit.html");
+    std::fs::write(&template, "{{#each events}}{{action}} {{email}} {{route}};{{/each}}").unwrap();
+    let trusted: std::sync::Arc<[ipnet::IpNet]> = config.identity.trusted_proxies.clone().into();
+    let routes = crate::audit::routes(
+        audit.clone(),
+        identity,
+        crate::groups::Groups::default(),
+        renderer,
+        trusted,
+        template,
+        None,
+    );
+
+    let request = |path: &str, email: &'static str, groups: &'static str| {
+        warp::test::request()
+            .path(path)
+            .extension(server::RemoteAddr("127.0.0.1:4567".parse().unwrap()))
+            .header("Remote-Email", email)
+            .header("Remote-Groups", groups)
+            .header("X-Forwarded-For", "203.0.113.7, 10.1.2.3")
+    };
+
+    let resp = request("/launch/Media_Server", "alice@example.com", "").reply(&routes).await;
+    assert_eq!(resp.status(), warp::http::StatusCode::FOUND);
+    assert_eq!(resp.headers()["location"], "https://media.example.com/");
+    let resp = request("/launch/Wiki", "bob@example.com", "").reply(&routes).await;
+    assert_eq!(resp.headers()["location"], "https://wiki.example.com/");
+    // Tiles the user can't see don't send them anywhere
+    let resp = request("/launch/Media_Server", "bob@example.com", "").reply(&routes).await;
+    assert_eq!(resp.status(), warp::http::StatusCode::NOT_FOUND);
+
+    let events = audit.search(&Search::default(), 10).await;
+    assert_eq!(events.len(), 2);
+    assert_eq!(events[0].email, "bob@example.com");
+    assert_eq!(events[0].route.as_deref(), Some("Wiki"));
+    assert_eq!(events[1].action, Action::Launch);
+    assert_eq!(events[1].url.as_deref(), Some("https://media.example.com"));
+    assert_eq!(events[1].source_ip.as_deref(), Some("203.0.113.7"));
+
+    // Browsing is for admins only
+    let resp = request("/audit", "bob@example.com", "").reply(&routes).await;
+    assert_eq!(resp.status(), warp::http::StatusCode::FORBIDDEN);
+    let resp = request("/audit?user=ALICE", "carol@example.com", "admins").reply(&routes).await;
+    assert_eq!(resp.status(), warp::http::StatusCode::OK);
+    assert_eq!(resp.body(), "launch alice@example.com Media Server;");
+    let resp = request("/audit?format=json&route=wiki", "carol@example.com", "admins").reply(&routes).await;
+    let found: Vec<serde_json::Value> = serde_json::from_slice(resp.body()).unwrap();
+    assert_eq!(found.len(), 1);
+    assert_eq!(found[0]["email"], "bob@example.com");
+
+    // Files are rotated once they are full, and still searched
+    let user = test_user("dave@example.com");
+    for _ in 0..5 {
+        audit.record(&Event::new(Action::View, &user, None)).await;
+    }
+    let rotated = std::fs::read_dir(&dir)
+        .unwrap()
+        .filter(|e| e.as_ref().unwrap().file_name().to_string_lossy().starts_with("audit-"))
+        .count();
+    assert!(rotated >= 1);
+    assert!(std::fs::metadata(dir.join("audit.jsonl")).unwrap().len() <= 600);
+    let search = Search {
+        action: "view".to_string(),
+        ..Default::default()
+    };
+    assert_eq!(audit.search(&search, 100).await.len(), 5);
+    assert_eq!(audit.search(&Search::default(), 100).await.len(), 7);
+    assert_eq!(audit.search(&Search::default(), 3).await.len(), 3);
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test]
+async fn renders_follow_user_changes() {
+    let index = std::env::temp_dir().join(format!("hallway-render-key-{}.html", std::process::id()));
+    std::fs::write(&index, "{{user.name}} {{#each user.groups}}{{this}} {{/each}}{{user.claims.team}}").unwrap();
+    let mut renderer = crate::rendering::Renderer::from(vec![], vec![], Default::default(), &index);
+    std::fs::remove_file(&index).unwrap();
+    let global = crate::rendering::GlobalData::default();
+
+    let mut alice = test_user("alice@example.com");
+    alice.name = "Alice".to_string();
+    assert_eq!(renderer.render(alice.clone(), &global, "n"), "Alice ");
+    alice.name = "Alice Liddell".to_string();
+    assert_eq!(renderer.render(alice.clone(), &global, "n"), "Alice Liddell ");
+    alice.groups = vec!["family".to_string()];
+    assert_eq!(renderer.render(alice.clone(), &global, "n"), "Alice Liddell family ");
+    alice.claims.insert("team".to_string(), serde_json::json!("blue"));
+    assert_eq!(renderer.render(alice, &global, "n"), "Alice Liddell family blue");
+}
+
+#[tokio::test]
+async fn security_headers_and_nonces() {
+    use crate::security::{Nonce, SecurityConfig, Secured};
+
+    // Cached renders still get the nonce of each request
+    let index = std::env::temp_dir().join(format!("hallway-nonce-{}.html", std::process::id()));
+    std::fs::write(&index, "<script nonce=\"{{nonce}}\"></script>{{user.email}}").unwrap();
+    let mut renderer = crate::rendering::Renderer::from(vec![], vec![], Default::default(), &index);
+    std::fs::remove_file(&index).unwrap();
+    let global = crate::rendering::GlobalData::default();
+    assert_eq!(
+        renderer.render(test_user("alice@example.com"), &global, "first"),
+        "<script nonce=\"first\"></script>alice@example.com"
+    );
+    assert_eq!(
+        renderer.render(test_user("alice@example.com"), &global, "second"),
+        "<script nonce=\"second\"></script>alice@example.com"
+    );
+
+    let route = crate::security::nonce().map(|nonce: Nonce| nonce.0.to_string());
+    let serve = |config: SecurityConfig| {
+        let route = route.clone();
+        async move {
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            tokio::spawn(server::serve(
+                Secured::new(warp::service(route), &config),
+                server::Listener::Tcp(listener),
+                None,
+                std::future::pending(),
+            ));
+            addr
+        }
+    };
+
+    let addr = serve(SecurityConfig::default()).await;
+    let first = reqwest::get(format!("http://{}/", addr)).await.unwrap();
+    let headers = first.headers().clone();
+    let nonce = first.text().await.unwrap();
+    let policy = headers["content-security-policy"].to_str().unwrap();
+    assert!(policy.contains(&format!("script-src 'self' 'nonce-{}'", nonce)), "{}", policy);
+    assert!(policy.contains("frame-ancestors 'none'"));
+    assert_eq!(headers["x-content-type-options"], "nosniff");
+    assert_eq!(headers["referrer-policy"], "same-origin");
+    assert!(headers.contains_key("permissions-policy"));
+    assert!(!headers.contains_key("strict-transport-security"));
+
+    let second = reqwest::get(format!("http://{}/", addr)).await.unwrap().text().await.unwrap();
+    assert_ne!(nonce, second);
+
+    let config: crate::config::Config = config_from(
+        "[security]\nhsts_max_age = 31536000\nhsts_include_subdomains = true\ncontent_security_policy = \"script-src 'nonce-{nonce}'\"\n",
+    );
+    let addr = serve(config.security).await;
+    let resp = reqwest::get(format!("http://{}/", addr)).await.unwrap();
+    assert_eq!(resp.headers()["strict-transport-security"], "max-age=31536000; includeSubDomains");
+    let policy = resp.headers()["content-security-policy"].to_str().unwrap().to_string();
+    assert_eq!(policy, format!("script-src 'nonce-{}'", resp.text().await.unwrap()));
+}
+
+#[tokio::test]
+async fn rate_limits_per_address_and_identity() {
+    use crate::rate_limit::{self, RateLimiter, TooManyRequests};
+    use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+    use warp::http::StatusCode;
+
+    let config: crate::config::Config = config_from(
+        "[rate_limit]\nper_ip = { burst = 2, per_minute = 60 }\nper_identity = { burst = 3, per_minute = 30 }\n",
+    );
+    let limiter = RateLimiter::new(config.rate_limit.as_ref().unwrap());
+    let trusted = std::sync::Arc::<[ipnet::IpNet]>::from(vec!["10.0.0.0/8".parse().unwrap()]);
+    let route = rate_limit::per_ip(Some(limiter.clone()), trusted)
+        .map(warp::reply)
+        .recover(|err: warp::Rejection| async move {
+            let limited = err.find::<TooManyRequests>().unwrap();
+            Ok::<_, std::convert::Infallible>(warp::reply::with_status(
+                limited.retry_after.to_string(),
+                StatusCode::TOO_MANY_REQUESTS,
+            ))
+        });
+    let from = |ip: [u8; 4], forwarded: Option<&str>| {
+        let request = warp::test::request()
+            .extension(server::RemoteAddr(SocketAddr::new(IpAddr::V4(Ipv4Addr::from(ip)), 4000)));
+        match forwarded {
+            Some(client) => request.header("x-forwarded-for", client),
+            None => request,
+        }
+    };
+
+    tokio::time::pause();
+    for _ in 0..2 {
+        assert_eq!(from([192, 0, 2, 1], None).reply(&route).await.status(), StatusCode::OK);
+    }
+    let resp = from([192, 0, 2, 1], None).reply(&route).await;
+    assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(resp.body(), "1");
+
+    // Each address has its own bucket, clients behind a trusted proxy too
+    assert_eq!(from([192, 0, 2, 2], None).reply(&route).await.status(), StatusCode::OK);
+    for _ in 0..2 {
+        let resp = from([10, 0, 0, 1], Some("198.51.100.7")).reply(&route).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+    }
+    let resp = from([10, 0, 0, 1], Some("198.51.100.7")).reply(&route).await;
+    assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(from([10, 0, 0, 1], Some("198.51.100.8")).reply(&route).await.status(), StatusCode::OK);
+
+    // A token comes back every second
+    tokio::time::advance(std::time::Duration::from_secs(1)).await;
+    assert_eq!(from([192, 0, 2, 1], None).reply(&route).await.status(), StatusCode::OK);
+    assert_eq!(from([192, 0, 2, 1], None).reply(&route).await.status(), StatusCode::TOO_MANY_REQUESTS);
+
+    let alice = test_user("alice@example.com");
+    for _ in 0..3 {
+        assert!(limiter.check_identity(&alice).is_ok());
+    }
+    let limited = limiter.check_identity(&alice).unwrap_err();
+    assert_eq!(limited.find::<TooManyRequests>().unwrap().retry_after, 2);
+    assert!(limiter.check_identity(&test_user("bob@example.com")).is_ok());
+    tokio::time::advance(std::time::Duration::from_secs(2)).await;
+    assert!(limiter.check_identity(&alice).is_ok());
+
+    // Users out of tokens are turned away before their token is verified,
+    // but claiming to be someone doesn't use up their tokens
+    let signer = TestSigner::new();
+    let decoder = crate::jwt::JwtDecoder::oidc("https://auth.example.com", "hallway", signer.jwks.clone(), Default::default());
+    let forged = signer.mint(serde_json::json!({ "email": "bob@example.com", "exp": 1 }));
+    assert_eq!(decoder.claimed_email(&forged).as_deref(), Some("bob@example.com"));
+    let verified = std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0));
+    let counted = verified.clone();
+    let route = rate_limit::per_identity(
+        Some(limiter.clone()),
+        warp::header::<String>("x-claimed").map(Some).or(warp::any().map(|| None)).unify(),
+        warp::header::<String>("x-user").and_then(move |email: String| {
+            counted.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            async move { Ok::<_, warp::Rejection>(test_user(&email)) }
+        }),
+    );
+    let request = |claimed: &str, user: &str| {
+        warp::test::request()
+            .header("x-claimed", claimed)
+            .header("x-user", user)
+            .filter(&route)
+    };
+    for _ in 0..3 {
+        assert!(request("carol@example.com", "mallory@example.com").await.is_ok());
+    }
+    for _ in 0..3 {
+        assert!(request("carol@example.com", "carol@example.com").await.is_ok());
+    }
+    assert_eq!(verified.load(std::sync::atomic::Ordering::SeqCst), 6);
+    let limited = request("carol@example.com", "carol@example.com").await.err().unwrap();
+    assert!(limited.find::<TooManyRequests>().is_some());
+    assert_eq!(verified.load(std::sync::atomic::Ordering::SeqCst), 6);
+
+    // A bucket that never refills isn't a limit anyone wants
+    let zero = toml::from_str::<crate::config::Config>(
+        "routes = []\n[domain]\nname = \"https://hallway.example.com\"\n[rate_limit]\nper_ip = { burst = 10, per_minute = 0 }\n",
+    );
+    assert!(zero.unwrap_err().to_string().contains("per_minute must be at least 1"));
+
+    // Without the section nothing is limited
+    let unlimited = rate_limit::per_ip(None, std::sync::Arc::from(vec![])).map(warp::reply);
+    for _ in 0..5 {
+        assert_eq!(from([192, 0, 2, 1], None).reply(&unlimited).await.status(), StatusCode::OK);
+    }
+    assert!(config_from("").rate_limit.is_none());
+}
+
+#[tokio::test]
+async fn assets_are_precompressed_and_negotiated() {
+    use crate::compression::{self, negotiate, Encoding, Precompressed};
+    use std::io::Read;
+    use warp::Reply;
+
+    let all = [Encoding::Brotli, Encoding::Zstd, Encoding::Gzip];
+    assert_eq!(negotiate(Some("gzip, deflate, br, zstd"), &all), Some(Encoding::Brotli));
+    assert_eq!(negotiate(Some("gzip;q=1.0, br;q=0.5"), &all), Some(Encoding::Gzip));
+    assert_eq!(negotiate(Some("zstd, gzip"), &all), Some(Encoding::Zstd));
+    assert_eq!(negotiate(Some("br;q=0, *"), &[Encoding::Brotli, Encoding::Gzip]), Some(Encoding::Gzip));
+    assert_eq!(negotiate(Some("x-gzip"), &all), Some(Encoding::Gzip));
+    assert_eq!(negotiate(Some("identity"), &all), None);
+    assert_eq!(negotiate(None, &all), None);
+
+    let dir = std::env::temp_dir().join(format!("hallway-assets-{}", std::process::id()));
+    std::fs::create_dir_all(dir.join("fonts")).unwrap();
+    let css = "body { color: black; }\n".repeat(200);
+    std::fs::write(dir.join("styles.css"), &css).unwrap();
+    // Whatever the build left is used rather than compressed again
+    std::fs::write(dir.join("styles.css.zst"), "zstd from the build").unwrap();
+    std::fs::write(dir.join("fonts/app.js"), "let a = 1;\n".repeat(100)).unwrap();
+    std::fs::write(dir.join("logo.webp"), "RIFF".repeat(100)).unwrap();
+    let precompressed = Precompressed::load(&dir);
+    let assets = warp::path("assets")
+        .and(precompressed.serve())
+        .map(compression::vary)
+        .with(warp::wrap_fn(crate::filters::cache_for::<60, _, _>));
+
+    let get = |path: &'static str, accept: Option<&'static str>| {
+        let request = warp::test::request().path(path);
+        let request = match accept {
+            Some(accept) => request.header("accept-encoding", accept),
+            None => request,
+        };
+        request.reply(&assets)
+    };
+
+    let resp = get("/assets/styles.css", Some("gzip, br")).await;
+    assert_eq!(resp.headers()["content-encoding"], "br");
+    assert_eq!(resp.headers()["content-type"], "text/css");
+    assert_eq!(resp.headers()["vary"], "accept-encoding");
+    assert_eq!(resp.headers()["cache-control"], "max-age=60");
+    let etag = resp.headers()["etag"].clone();
+    let last_modified = resp.headers()["last-modified"].clone();
+    let mut decoded = String::new();
+    brotli::Decompressor::new(&resp.body()[..], 4096).read_to_string(&mut decoded).unwrap();
+    assert_eq!(decoded, css);
+
+    // Caches can revalidate, and never confuse one variant with another
+    let request = || warp::test::request().path("/assets/styles.css").header("accept-encoding", "br");
+    let resp = request().header("if-none-match", &etag).reply(&assets).await;
+    assert_eq!(resp.status(), 304);
+    assert!(resp.body().is_empty());
+    assert_eq!(resp.headers()["etag"], etag);
+    assert_eq!(resp.headers()["cache-control"], "max-age=60");
+    let resp = request().header("if-modified-since", &last_modified).reply(&assets).await;
+    assert_eq!(resp.status(), 304);
+    let resp = request().header("if-none-match", "\"something-else\"").reply(&assets).await;
+    assert_eq!(resp.status(), 200);
+    let resp = get("/assets/styles.css", Some("gzip")).await;
+    assert_ne!(resp.headers()["etag"], etag);
+    let resp = warp::test::request()
+        .path("/assets/styles.css")
+        .header("accept-encoding", "gzip")
+        .header("if-none-match", &etag)
+        .reply(&assets)
+        .await;
+    assert_eq!(resp.status(), 200);
+
+    let resp = get("/assets/styles.css", Some("gzip")).await;
+    assert_eq!(resp.headers()["content-encoding"], "gzip");
+    let mut decoded = String::new();
+    flate2::read::GzDecoder::new(&resp.body()[..]).read_to_string(&mut decoded).unwrap();
+    assert_eq!(decoded, css);
+
+    let resp = get("/assets/styles.css", Some("zstd;q=1, br;q=0.9")).await;
+    assert_eq!(resp.headers()["content-encoding"], "zstd");
+    assert_eq!(resp.body(), "zstd from the build");
+
+    let resp = get("/assets/fonts/app.js", Some("br")).await;
+    assert_eq!(resp.headers()["content-encoding"], "br");
+
+    // No zstd made by the build, and none of the others asked for
+    let resp = get("/assets/fonts/app.js", Some("zstd")).await;
+    assert!(!resp.headers().contains_key("content-encoding"));
+    assert_eq!(resp.body().len(), 1100);
+    assert_eq!(resp.headers()["vary"], "accept-encoding");
+
+    let resp = get("/assets/styles.css", None).await;
+    assert!(!resp.headers().contains_key("content-encoding"));
+    assert_eq!(resp.body(), css.as_bytes());
+
+    let resp = get("/assets/logo.webp", Some("br, gzip")).await;
+    assert!(!resp.headers().contains_key("content-encoding"));
+    assert_eq!(resp.status(), 200);
+
+    // The build's variants only go out in place of their original
+    let resp = get("/assets/styles.css.zst", None).await;
+    assert_eq!(resp.status(), 404);
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    // Only rendered pages are compressed on the fly
+    let pages = {
+        let css = css.clone();
+        warp::path::full()
+            .and(warp::header::optional::<String>("accept-encoding"))
+            .and(warp::path::tail().map(move |tail: warp::path::Tail| match tail.as_str() {
+                "data" => warp::reply::json(&css).into_response(),
+                _ => warp::reply::html(css.clone()).into_response(),
+            }))
+            .then(compression::html)
+    };
+    let page = |path: &'static str, accept: Option<&'static str>| {
+        let request = warp::test::request().path(path);
+        let request = match accept {
+            Some(accept) => request.header("accept-encoding", accept),
+            None => request,
+        };
+        request.reply(&pages)
+    };
+
+    let resp = page("/", Some("gzip")).await;
+    assert_eq!(resp.headers()["content-encoding"], "gzip");
+    assert_eq!(resp.headers()["vary"], "accept-encoding");
+    let mut decoded = String::new();
+    flate2::read::GzDecoder::new(&resp.body()[..]).read_to_string(&mut decoded).unwrap();
+    assert_eq!(decoded, css);
+
+    let resp = page("/", None).await;
+    assert!(!resp.headers().contains_key("content-encoding"));
+    assert_eq!(resp.headers()["vary"], "accept-encoding");
+    let resp = page("/data", Some("br")).await;
+    assert!(!resp.headers().contains_key("content-encoding"));
+
+    // Pages echoing the request next to secrets aren't
+    for path in ["/audit", "/auth/login"] {
+        let resp = page(path, Some("br, gzip")).await;
+        assert!(!resp.headers().contains_key("content-encoding"), "{}", path);
+        assert_eq!(resp.body(), css.as_bytes());
+    }
+}
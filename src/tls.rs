@@ -0,0 +1,174 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+    time::{Duration, SystemTime},
+};
+
+use serde::Deserialize;
+use tokio::time;
+use tokio_rustls::{
+    rustls::{
+        crypto::{aws_lc_rs, CryptoProvider},
+        pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer},
+        server::{ClientHello, ResolvesServerCert, WebPkiClientVerifier},
+        sign::CertifiedKey,
+        RootCertStore, ServerConfig,
+    },
+    TlsAcceptor,
+};
+use tracing::{info, warn};
+use warp::{
+    filters::{path::FullPath, BoxedFilter},
+    http::{StatusCode, Uri},
+    reply::Response,
+    Filter, Reply,
+};
+
+use crate::{consts, metrics::metrics};
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct TlsConfig {
+    /// PEM certificate chain, relative to the config dir
+    pub cert: String,
+
+    /// PEM private key, relative to the config dir
+    pub key: String,
+
+    /// PEM CAs client certificates are checked against. Clients are only
+    /// asked for a certificate when it's set, and can still go without one
+    #[serde(default)]
+    pub client_ca: Option<String>,
+
+    /// Port where plain HTTP is redirected to HTTPS
+    #[serde(default)]
+    pub redirect_port: Option<u16>,
+}
+
+fn load_key(provider: &CryptoProvider, cert: &Path, key: &Path) -> Result<CertifiedKey, String> {
+    let chain = CertificateDer::pem_file_iter(cert)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| format!("{}: {}", cert.display(), e))?;
+    if chain.is_empty() {
+        return Err(format!("{}: no certificates found", cert.display()));
+    }
+    let key = PrivateKeyDer::from_pem_file(key).map_err(|e| format!("{}: {}", key.display(), e))?;
+    CertifiedKey::from_der(chain, key, provider).map_err(|e| e.to_string())
+}
+
+fn modified(paths: &[&Path]) -> Vec<Option<SystemTime>> {
+    paths
+        .iter()
+        .map(|p| std::fs::metadata(p).and_then(|m| m.modified()).ok())
+        .collect()
+}
+
+/// Hands out the current certificate to every new handshake, so a renewed one
+/// gets used without touching the connections that are already open
+#[derive(Debug)]
+struct ReloadingCert {
+    current: RwLock<Arc<CertifiedKey>>,
+}
+
+impl ResolvesServerCert for ReloadingCert {
+    fn resolve(&self, _: ClientHello) -> Option<Arc<CertifiedKey>> {
+        Some(self.current.read().unwrap().clone())
+    }
+}
+
+impl ReloadingCert {
+    /// Checks the files every now and then, swapping in the certificate when
+    /// they change. A broken renewal leaves the old certificate in place
+    fn watch(self: Arc<Self>, provider: Arc<CryptoProvider>, cert: PathBuf, key: PathBuf) {
+        tokio::spawn(async move {
+            let mut last = modified(&[&cert, &key]);
+            let mut interval = time::interval(Duration::from_secs(consts::defaults::TLS_RELOAD_CHECK));
+            loop {
+                interval.tick().await;
+                let now = modified(&[&cert, &key]);
+                if now == last {
+                    continue;
+                }
+                match load_key(&provider, &cert, &key) {
+                    Ok(loaded) => {
+                        metrics().reloaded("tls", true);
+                        info!("Reloaded TLS certificate from {}", cert.display());
+                        *self.current.write().unwrap() = Arc::new(loaded);
+                        last = now;
+                    }
+                    Err(e) => {
+                        metrics().reloaded("tls", false);
+                        warn!("Keeping the old TLS certificate, the new one can't be used: {}", e);
+                    }
+                }
+            }
+        });
+    }
+}
+
+/// Builds the acceptor for the configured certificate, and starts watching it
+pub fn acceptor(config: &TlsConfig) -> TlsAcceptor {
+    let conf_dir = Path::new(consts::paths::get_conf_dir());
+    let (cert, key) = (conf_dir.join(&config.cert), conf_dir.join(&config.key));
+    let provider = Arc::new(aws_lc_rs::default_provider());
+
+    let loaded = load_key(&provider, &cert, &key).unwrap_or_else(|e| panic!("Couldn't load the TLS certificate: {}", e));
+    let resolver = Arc::new(ReloadingCert {
+        current: RwLock::new(Arc::new(loaded)),
+    });
+    resolver.clone().watch(provider.clone(), cert, key);
+
+    let builder = ServerConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()
+        .expect("The default protocol versions are always supported");
+    let builder = match &config.client_ca {
+        None => builder.with_no_client_auth(),
+        Some(ca) => {
+            let path = conf_dir.join(ca);
+            let mut roots = RootCertStore::empty();
+            CertificateDer::pem_file_iter(&path)
+                .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+                .unwrap_or_else(|e| panic!("Couldn't read client CAs at {}: {}", path.display(), e))
+                .into_iter()
+                .for_each(|ca| roots.add(ca).expect("Client CA is not a valid certificate"));
+            let verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider)
+                .allow_unauthenticated()
+                .build()
+                .expect("Couldn't set up client certificate verification");
+            builder.with_client_cert_verifier(verifier)
+        }
+    };
+
+    let mut server_config = builder.with_cert_resolver(resolver);
+    server_config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    TlsAcceptor::from(Arc::new(server_config))
+}
+
+/// Sends plain HTTP requests to the same place over HTTPS
+pub fn redirect(https_port: u16) -> BoxedFilter<(Response,)> {
+    warp::path::full()
+        .and(warp::query::raw().or(warp::any().map(String::new)).unify())
+        .and(warp::header::optional::<String>("host"))
+        .map(move |path: FullPath, query: String, host: Option<String>| {
+            // The port in the host is the plain HTTP one
+            let host = host.map(|host| match host.rsplit_once(':') {
+                Some((name, port)) if port.chars().all(|c| c.is_ascii_digit()) => name.to_string(),
+                _ => host,
+            });
+            let location = host.and_then(|host| {
+                let mut location = match https_port {
+                    443 => format!("https://{}{}", host, path.as_str()),
+                    port => format!("https://{}:{}{}", host, port, path.as_str()),
+                };
+                if !query.is_empty() {
+                    location.push('?');
+                    location.push_str(&query);
+                }
+                Uri::try_from(location).ok()
+            });
+            match location {
+                Some(location) => warp::redirect::permanent(location).into_response(),
+                None => StatusCode::BAD_REQUEST.into_response(),
+            }
+        })
+        .boxed()
+}
@@ -1,24 +1,31 @@
-use std::thread;
+use std::time::Duration;
 
 use serde::de::DeserializeOwned;
+use tracing::warn;
 
-pub fn get_body(url: &str) -> reqwest::blocking::Response {
-    let mut resp = reqwest::blocking::get(url);
+use crate::consts::defaults::discovery;
 
-    while resp.is_err() {
-        thread::sleep(std::time::Duration::from_secs(10));
-        resp = reqwest::blocking::get(url);
-    }
-    resp.unwrap() // This unwrap is fine
+async fn try_get_json<T>(url: &str) -> reqwest::Result<T>
+where
+    T: DeserializeOwned,
+{
+    reqwest::get(url).await?.error_for_status()?.json().await
 }
 
-pub fn get_json<T>(url: &str) -> T where
-T: DeserializeOwned {
-    let mut json = get_body(url).json();
-    while json.is_err() {
-        thread::sleep(std::time::Duration::from_secs(10));
-        json = get_body(url).json();
+/// Fetches a JSON document, retrying with an exponential backoff until it succeeds
+pub async fn get_json<T>(url: &str) -> T
+where
+    T: DeserializeOwned,
+{
+    let mut backoff = Duration::from_secs(discovery::MIN_BACKOFF);
+    loop {
+        match try_get_json(url).await {
+            Ok(json) => return json,
+            Err(e) => {
+                warn!("Couldn't get '{}', retrying in {}s: {}", url, backoff.as_secs(), e);
+                tokio::time::sleep(backoff).await;
+                backoff = (backoff * 2).min(Duration::from_secs(discovery::MAX_BACKOFF));
+            }
+        }
     }
-
-    json.unwrap() // This unwrap is fine
 }